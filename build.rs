@@ -7,10 +7,19 @@ use std::process::Command;
 
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
-    let result = Command::new("make")
-        .args(&["-f", "makefile.cargo"])
-        .status()
-        .unwrap();
+    let mut make = Command::new("make");
+    make.args(&["-f", "makefile.cargo"]);
+
+    // Without a Gonk tree, build the glue against our stand-in headers.
+    if env::var("CARGO_FEATURE_MOCK_HAL").is_ok() {
+        let cppflags = env::var("CPPFLAGS").unwrap_or_default();
+        make.env(
+            "CPPFLAGS",
+            format!("{} -Isupport/mock-hal/include", cppflags),
+        );
+    }
+
+    let result = make.status().unwrap();
     assert!(result.success());
     println!("cargo:rustc-flags=-L native={}", out_dir);
 }
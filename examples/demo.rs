@@ -16,7 +16,13 @@ fn main() {
 
     info!("About to create window");
 
-    let window = window::Window::new();
+    let window = match window::Window::new() {
+        Ok(window) => window,
+        Err(err) => {
+            error!("Failed to create window: {}", err);
+            return;
+        }
+    };
     window.fill_color(0.0, 1.0, 1.0, 1.0);
 
     loop {
@@ -0,0 +1,222 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Draws the layers the HWC leaves to us into the framebuffer target.
+
+use egl::EGLDisplay;
+use egl_image::{BufferRef, EGLImageKHR, EglImage};
+use error::GfxError;
+use gleam::gl::{self, GLenum, GLuint, Gl};
+use gonk_gfx::ANativeWindowBuffer;
+use hwc::*;
+use layers::Layer;
+
+const VERTEX_SHADER: &str = "
+attribute vec2 a_position;
+attribute vec2 a_texcoord;
+varying vec2 v_texcoord;
+void main() {
+    v_texcoord = a_texcoord;
+    gl_Position = vec4(a_position, 0.0, 1.0);
+}
+";
+
+// Outputs premultiplied alpha, whatever the layer's blending.
+const FRAGMENT_SHADER: &str = "
+#extension GL_OES_EGL_image_external : require
+precision mediump float;
+uniform samplerExternalOES u_texture;
+uniform float u_alpha;
+uniform int u_blending;
+varying vec2 v_texcoord;
+void main() {
+    vec4 color = texture2D(u_texture, v_texcoord);
+    if (u_blending == 0) {
+        color.a = 1.0;
+    } else if (u_blending == 2) {
+        color.rgb *= color.a;
+    }
+    gl_FragColor = color * u_alpha;
+}
+";
+
+const POSITION: GLuint = 0;
+const TEXCOORD: GLuint = 1;
+
+pub struct Compositor {
+    program: GLuint,
+    vertices: GLuint,
+    texture: GLuint,
+    alpha: i32,
+    blending: i32,
+    // The images of the buffers drawn last time, see retain_images().
+    images: Vec<CachedImage>,
+}
+
+// Holds a reference on its buffer, so that the address it is looked up by
+// can't be reused by another buffer meanwhile.
+struct CachedImage {
+    image: EglImage,
+    buffer: BufferRef,
+}
+
+fn compile(gl: &dyn Gl, kind: GLenum, source: &str) -> Result<GLuint, GfxError> {
+    let shader = gl.create_shader(kind);
+    gl.shader_source(shader, &[source.as_bytes()]);
+    gl.compile_shader(shader);
+    let mut status = [0];
+    unsafe { gl.get_shader_iv(shader, gl::COMPILE_STATUS, &mut status) };
+    if status[0] == 0 {
+        let log = gl.get_shader_info_log(shader);
+        gl.delete_shader(shader);
+        return Err(GfxError::Shader(log));
+    }
+    Ok(shader)
+}
+
+impl Compositor {
+    pub fn new(gl: &dyn Gl) -> Result<Compositor, GfxError> {
+        let vertex = compile(gl, gl::VERTEX_SHADER, VERTEX_SHADER)?;
+        let fragment = match compile(gl, gl::FRAGMENT_SHADER, FRAGMENT_SHADER) {
+            Ok(fragment) => fragment,
+            Err(err) => {
+                gl.delete_shader(vertex);
+                return Err(err);
+            }
+        };
+        let program = gl.create_program();
+        gl.attach_shader(program, vertex);
+        gl.attach_shader(program, fragment);
+        gl.bind_attrib_location(program, POSITION, "a_position");
+        gl.bind_attrib_location(program, TEXCOORD, "a_texcoord");
+        gl.link_program(program);
+        gl.delete_shader(vertex);
+        gl.delete_shader(fragment);
+        let mut status = [0];
+        unsafe { gl.get_program_iv(program, gl::LINK_STATUS, &mut status) };
+        if status[0] == 0 {
+            let log = gl.get_program_info_log(program);
+            gl.delete_program(program);
+            return Err(GfxError::Shader(log));
+        }
+
+        let texture = gl.gen_textures(1)[0];
+        gl.bind_texture(gl::TEXTURE_EXTERNAL_OES, texture);
+        for &(param, value) in &[
+            (gl::TEXTURE_MIN_FILTER, gl::LINEAR),
+            (gl::TEXTURE_MAG_FILTER, gl::LINEAR),
+            (gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE),
+            (gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE),
+        ] {
+            gl.tex_parameter_i(gl::TEXTURE_EXTERNAL_OES, param, value as i32);
+        }
+
+        Ok(Compositor {
+            program,
+            vertices: gl.gen_buffers(1)[0],
+            texture,
+            alpha: gl.get_uniform_location(program, "u_alpha"),
+            blending: gl.get_uniform_location(program, "u_blending"),
+            images: vec![],
+        })
+    }
+
+    /// Draws a layer onto the current surface, which covers a `width` x
+    /// `height` display once `transform` is applied to it.
+    pub fn draw(
+        &mut self,
+        gl: &dyn Gl,
+        dpy: EGLDisplay,
+        layer: &Layer,
+        width: i32,
+        height: i32,
+        transform: u32,
+    ) -> Result<(), GfxError> {
+        let buffer = layer.buffer();
+        let image = self.image(dpy, buffer)?;
+        let (buffer_width, buffer_height) =
+            unsafe { ((*buffer).width as f32, (*buffer).height as f32) };
+
+        let frame = &layer.display_frame;
+        let crop = &layer.source_crop;
+        let mut vertices = Vec::with_capacity(16);
+        // A triangle strip, from the top left corner down.
+        for &(x, y) in &[(0.0, 0.0), (0.0, 1.0), (1.0, 0.0), (1.0, 1.0)] {
+            let left = frame.left as f32 + x * (frame.right - frame.left) as f32;
+            let top = frame.top as f32 + y * (frame.bottom - frame.top) as f32;
+            let (left, top) =
+                inverse_transform_point(transform, left / width as f32, top / height as f32);
+            vertices.push(2.0 * left - 1.0);
+            vertices.push(1.0 - 2.0 * top);
+            let (s, t) = inverse_transform_point(layer.transform, x, y);
+            vertices.push((crop.left + s * (crop.right - crop.left)) / buffer_width);
+            vertices.push((crop.top + t * (crop.bottom - crop.top)) / buffer_height);
+        }
+
+        gl.use_program(self.program);
+        gl.bind_buffer(gl::ARRAY_BUFFER, self.vertices);
+        gl::buffer_data(gl, gl::ARRAY_BUFFER, &vertices, gl::STREAM_DRAW);
+        gl.vertex_attrib_pointer(POSITION, 2, gl::FLOAT, false, 16, 0);
+        gl.vertex_attrib_pointer(TEXCOORD, 2, gl::FLOAT, false, 16, 8);
+        gl.enable_vertex_attrib_array(POSITION);
+        gl.enable_vertex_attrib_array(TEXCOORD);
+
+        gl.active_texture(gl::TEXTURE0);
+        gl.bind_texture(gl::TEXTURE_EXTERNAL_OES, self.texture);
+        gl.egl_image_target_texture2d_oes(gl::TEXTURE_EXTERNAL_OES, image);
+
+        gl.uniform_1f(self.alpha, f32::from(layer.plane_alpha) / 255.0);
+        gl.uniform_1i(
+            self.blending,
+            match layer.blending {
+                HWC_BLENDING_PREMULT => 1,
+                HWC_BLENDING_COVERAGE => 2,
+                _ => 0,
+            },
+        );
+        gl.enable(gl::BLEND);
+        gl.blend_func(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+
+        gl.draw_arrays(gl::TRIANGLE_STRIP, 0, 4);
+
+        gl.disable(gl::BLEND);
+        gl.disable_vertex_attrib_array(POSITION);
+        gl.disable_vertex_attrib_array(TEXCOORD);
+        gl.bind_buffer(gl::ARRAY_BUFFER, 0);
+        Ok(())
+    }
+
+    /// Drops the images of the buffers of layers other than `layers`,
+    /// letting go of the buffers.
+    pub fn retain_images(&mut self, layers: &[&Layer]) {
+        self.images.retain(|cached| {
+            layers
+                .iter()
+                .any(|layer| layer.buffer() == cached.buffer.get())
+        });
+    }
+
+    fn image(
+        &mut self,
+        dpy: EGLDisplay,
+        buffer: *mut ANativeWindowBuffer,
+    ) -> Result<EGLImageKHR, GfxError> {
+        if let Some(cached) = self
+            .images
+            .iter()
+            .find(|cached| cached.buffer.get() == buffer)
+        {
+            return Ok(cached.image.as_ptr());
+        }
+        let cached = unsafe {
+            CachedImage {
+                image: EglImage::from_native_buffer(dpy, buffer)?,
+                buffer: BufferRef::new(buffer),
+            }
+        };
+        let image = cached.image.as_ptr();
+        self.images.push(cached);
+        Ok(image)
+    }
+}
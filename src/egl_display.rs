@@ -0,0 +1,83 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The EGL display, shared by windows and offscreen contexts. It is
+//! initialized for the first of them, and terminated with the last.
+
+use egl::{self, EGLContext, EGLDisplay, EGLSurface};
+use error::{EglStage, GfxError};
+use std::sync::Mutex;
+
+// The number of DisplayRefs alive in the process.
+static USERS: Mutex<usize> = Mutex::new(0);
+
+/// A reference on the initialized default EGL display.
+pub struct DisplayRef {
+    dpy: EGLDisplay,
+}
+
+impl DisplayRef {
+    pub fn new() -> Result<DisplayRef, GfxError> {
+        let mut users = USERS.lock().unwrap();
+        let dpy = egl::get_display(egl::EGL_DEFAULT_DISPLAY)
+            .ok_or_else(|| GfxError::egl(EglStage::GetDisplay))?;
+        if *users == 0 {
+            let mut major: i32 = 0;
+            let mut minor: i32 = 0;
+            if !egl::initialize(dpy, &mut major, &mut minor) {
+                error!("Failed to initialize EGL!");
+                return Err(GfxError::egl(EglStage::Initialize));
+            }
+            info!("EGL initialized {}.{}", major, minor);
+        }
+        *users += 1;
+        Ok(DisplayRef { dpy })
+    }
+
+    pub fn get(&self) -> EGLDisplay {
+        self.dpy
+    }
+}
+
+impl Drop for DisplayRef {
+    fn drop(&mut self) {
+        let mut users = USERS.lock().unwrap();
+        *users -= 1;
+        if *users == 0 {
+            info!("Terminating EGL");
+            egl::terminate(self.dpy);
+            egl::release_thread();
+        }
+    }
+}
+
+/// Destroys a context and its surface, if any, releasing them first if
+/// they are current on this thread.
+pub fn destroy_context(dpy: EGLDisplay, surf: EGLSurface, ctx: EGLContext) {
+    if egl::get_current_context() == Some(ctx) {
+        egl::make_current(
+            dpy,
+            egl::EGL_NO_SURFACE,
+            egl::EGL_NO_SURFACE,
+            egl::EGL_NO_CONTEXT,
+        );
+    }
+    if surf != egl::EGL_NO_SURFACE && !egl::destroy_surface(dpy, surf) {
+        error!("Failed to destroy EGL surface: 0x{:x}", egl::get_error());
+    }
+    if !egl::destroy_context(dpy, ctx) {
+        error!("Failed to destroy EGL context: 0x{:x}", egl::get_error());
+    }
+}
+
+pub fn has_extension(dpy: EGLDisplay, name: &str) -> bool {
+    egl::query_string(dpy, egl::EGL_EXTENSIONS)
+        .map(|extensions| {
+            extensions
+                .to_string_lossy()
+                .split(' ')
+                .any(|extension| extension == name)
+        })
+        .unwrap_or(false)
+}
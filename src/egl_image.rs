@@ -0,0 +1,208 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! EGLImages wrapping gralloc buffers, through EGL_KHR_image_base and
+//! EGL_ANDROID_image_native_buffer, and textures showing them.
+
+use egl::{self, EGLBoolean, EGLClientBuffer, EGLContext, EGLDisplay, EGLenum, EGLint};
+use error::{EglStage, GfxError};
+use gleam::gl::{self, GLenum, GLuint, Gl};
+use gonk_gfx::{ANativeWindowBuffer, GonkNativeWindowBuffer};
+use gralloc::{GrallocBuffer, PixelFormat};
+use libc::c_void;
+use std::mem::transmute;
+use std::rc::Rc;
+
+pub type EGLImageKHR = *mut c_void;
+
+pub const EGL_NO_IMAGE_KHR: EGLImageKHR = 0 as EGLImageKHR;
+pub const EGL_NATIVE_BUFFER_ANDROID: EGLenum = 0x3140;
+pub const EGL_IMAGE_PRESERVED_KHR: EGLint = 0x30D2;
+
+type CreateImageFn =
+    extern "C" fn(EGLDisplay, EGLContext, EGLenum, EGLClientBuffer, *const EGLint) -> EGLImageKHR;
+type DestroyImageFn = extern "C" fn(EGLDisplay, EGLImageKHR) -> EGLBoolean;
+
+pub struct EglImage {
+    dpy: EGLDisplay,
+    image: EGLImageKHR,
+    destroy: DestroyImageFn,
+}
+
+impl EglImage {
+    /// Wraps a native window buffer.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must point to a native window buffer outliving the image.
+    pub unsafe fn from_native_buffer(
+        dpy: EGLDisplay,
+        buffer: *mut ANativeWindowBuffer,
+    ) -> Result<EglImage, GfxError> {
+        let create = egl::get_proc_address("eglCreateImageKHR");
+        let destroy = egl::get_proc_address("eglDestroyImageKHR");
+        if create as usize == 0 || destroy as usize == 0 {
+            error!("EGL_KHR_image_base is not supported");
+            return Err(GfxError::egl(EglStage::CreateImage));
+        }
+        let create: CreateImageFn = transmute(create);
+        let destroy: DestroyImageFn = transmute(destroy);
+        let attrs = [
+            EGL_IMAGE_PRESERVED_KHR,
+            egl::EGL_TRUE as EGLint,
+            egl::EGL_NONE,
+        ];
+        let image = create(
+            dpy,
+            egl::EGL_NO_CONTEXT,
+            EGL_NATIVE_BUFFER_ANDROID,
+            buffer as EGLClientBuffer,
+            attrs.as_ptr(),
+        );
+        if image == EGL_NO_IMAGE_KHR {
+            error!("Failed to create an EGLImage for buffer {:?}", buffer);
+            return Err(GfxError::egl(EglStage::CreateImage));
+        }
+        Ok(EglImage {
+            dpy,
+            image,
+            destroy,
+        })
+    }
+
+    pub fn as_ptr(&self) -> EGLImageKHR {
+        self.image
+    }
+}
+
+impl Drop for EglImage {
+    fn drop(&mut self) {
+        (self.destroy)(self.dpy, self.image);
+    }
+}
+
+// A reference on a native window buffer, dropped along with it.
+pub(crate) struct BufferRef(*mut ANativeWindowBuffer);
+
+impl BufferRef {
+    pub(crate) unsafe fn new(buffer: *mut ANativeWindowBuffer) -> BufferRef {
+        let common = &mut (*buffer).common;
+        (common.inc_ref)(common);
+        BufferRef(buffer)
+    }
+
+    pub(crate) fn get(&self) -> *mut ANativeWindowBuffer {
+        self.0
+    }
+}
+
+impl Drop for BufferRef {
+    fn drop(&mut self) {
+        unsafe {
+            let common = &mut (*self.0).common;
+            (common.dec_ref)(common);
+        }
+    }
+}
+
+/// A GL texture showing a gralloc buffer without copying it, e.g. a
+/// camera or decoded video frame.
+///
+/// GL_TEXTURE_EXTERNAL_OES textures, sampled with samplerExternalOES,
+/// support every format the GPU can read, YUV ones included, which
+/// GL_TEXTURE_2D ones don't. The texture must be dropped with the context
+/// it was created on current.
+pub struct BufferTexture {
+    gl: Rc<dyn Gl>,
+    texture: GLuint,
+    target: GLenum,
+    // Declared before the buffer, so that it goes first.
+    image: EglImage,
+    buffer: BufferRef,
+}
+
+impl BufferTexture {
+    /// Binds `buffer` to a new `target` texture of the current context,
+    /// either GL_TEXTURE_EXTERNAL_OES or GL_TEXTURE_2D. The buffer is
+    /// freed along with the texture.
+    pub fn new(
+        gl: Rc<dyn Gl>,
+        buffer: GrallocBuffer,
+        target: GLenum,
+    ) -> Result<BufferTexture, GfxError> {
+        let buffer = GonkNativeWindowBuffer::from_gralloc_buffer(buffer);
+        unsafe {
+            // The texture holds its own reference.
+            let buffer = BufferRef(buffer as *mut ANativeWindowBuffer);
+            BufferTexture::from_native_buffer(gl, buffer.0, target)
+        }
+    }
+
+    /// Like `new()`, for a native window buffer which stays referenced as
+    /// long as the texture is alive.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must point to a live native window buffer.
+    pub unsafe fn from_native_buffer(
+        gl: Rc<dyn Gl>,
+        buffer: *mut ANativeWindowBuffer,
+        target: GLenum,
+    ) -> Result<BufferTexture, GfxError> {
+        if target != gl::TEXTURE_EXTERNAL_OES && target != gl::TEXTURE_2D {
+            return Err(GfxError::UnsupportedTextureTarget(target));
+        }
+        let format = (*buffer).format;
+        if target == gl::TEXTURE_2D
+            && matches!(PixelFormat::from_hal(format), Some(f) if f.is_yuv())
+        {
+            return Err(GfxError::UnsupportedFormat(format));
+        }
+        let dpy = egl::get_current_display().ok_or_else(|| GfxError::egl(EglStage::GetDisplay))?;
+        let image = EglImage::from_native_buffer(dpy, buffer)?;
+        let buffer = BufferRef::new(buffer);
+
+        let texture = gl.gen_textures(1)[0];
+        gl.bind_texture(target, texture);
+        for &(param, value) in &[
+            (gl::TEXTURE_MIN_FILTER, gl::LINEAR),
+            (gl::TEXTURE_MAG_FILTER, gl::LINEAR),
+            (gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE),
+            (gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE),
+        ] {
+            gl.tex_parameter_i(target, param, value as i32);
+        }
+        gl.egl_image_target_texture2d_oes(target, image.as_ptr());
+
+        Ok(BufferTexture {
+            gl,
+            texture,
+            target,
+            image,
+            buffer,
+        })
+    }
+
+    pub fn texture(&self) -> GLuint {
+        self.texture
+    }
+
+    pub fn target(&self) -> GLenum {
+        self.target
+    }
+
+    pub fn image(&self) -> &EglImage {
+        &self.image
+    }
+
+    pub fn buffer(&self) -> *mut ANativeWindowBuffer {
+        self.buffer.0
+    }
+}
+
+impl Drop for BufferTexture {
+    fn drop(&mut self) {
+        self.gl.delete_textures(&[self.texture]);
+    }
+}
@@ -0,0 +1,162 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Errors reported when setting up the graphics stack.
+
+use egl::{self, EGLint};
+use gleam::gl::GLenum;
+use gonk_gfx::{MAX_BUFFER_COUNT, MIN_BUFFER_COUNT};
+use hwc::hwc_rect;
+use libc::c_int;
+use std::error::Error;
+use std::fmt;
+
+/// The EGL call that failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EglStage {
+    GetDisplay,
+    Initialize,
+    ChooseConfig,
+    CreateWindowSurface,
+    CreateContext,
+    MakeCurrent,
+    SwapBuffers,
+    CreateImage,
+    CreatePbufferSurface,
+}
+
+#[derive(Debug)]
+pub enum GfxError {
+    /// hw_get_module() failed for this module id.
+    ModuleNotFound { module: &'static str, code: c_int },
+    /// The module's open() method failed for this device name.
+    DeviceOpen {
+        module: &'static str,
+        device: &'static str,
+        code: c_int,
+    },
+    /// The HWC device reports a version we don't know how to drive.
+    UnsupportedHwcVersion(u32),
+    /// A HWC device method returned an error.
+    Hwc { call: &'static str, code: c_int },
+    /// The display id doesn't designate a connected physical display.
+    DisplayNotConnected(c_int),
+    /// An EGL call failed, with the value of eglGetError() at that point.
+    Egl { stage: EglStage, code: EGLint },
+    /// The EGL implementation lacks this extension.
+    MissingExtension(&'static str),
+    /// A native window can't cycle through this number of buffers.
+    BufferCount(usize),
+    /// Only GLES 2 and 3 contexts can be created.
+    UnsupportedGlesVersion(i32),
+    /// A GLES shader failed to compile or link, with the info log.
+    Shader(String),
+    /// A gralloc module method returned an error.
+    Gralloc { call: &'static str, code: c_int },
+    /// The operation doesn't support buffers of this HAL_PIXEL_FORMAT_*.
+    UnsupportedFormat(c_int),
+    /// Buffers can only be bound to TEXTURE_2D or TEXTURE_EXTERNAL_OES.
+    UnsupportedTextureTarget(GLenum),
+    /// The gralloc allocator failed to allocate a buffer.
+    GrallocAlloc {
+        width: i32,
+        height: i32,
+        format: c_int,
+        usage: c_int,
+        code: c_int,
+    },
+    /// The rect doesn't fit in the width x height buffer.
+    RectOutOfBounds {
+        rect: hwc_rect,
+        width: i32,
+        height: i32,
+    },
+    /// A native window perform() operation, NATIVE_WINDOW_*, failed.
+    NativeWindow { operation: c_int, code: c_int },
+}
+
+impl GfxError {
+    /// Builds an EGL error for `stage` from the current eglGetError() value.
+    pub fn egl(stage: EglStage) -> GfxError {
+        GfxError::Egl {
+            stage,
+            code: egl::get_error(),
+        }
+    }
+}
+
+impl fmt::Display for GfxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GfxError::ModuleNotFound { module, code } => {
+                write!(f, "Failed to get the {} module (error {})", module, code)
+            }
+            GfxError::DeviceOpen {
+                module,
+                device,
+                code,
+            } => write!(
+                f,
+                "Failed to open {} on the {} module (error {})",
+                device, module, code
+            ),
+            GfxError::UnsupportedHwcVersion(version) => write!(
+                f,
+                "Unsupported HWC version {}.{} (0x{:08x})",
+                (version >> 24) & 0xff,
+                (version >> 16) & 0xff,
+                version
+            ),
+            GfxError::Hwc { call, code } => write!(f, "HWC {} failed (error {})", call, code),
+            GfxError::DisplayNotConnected(display) => {
+                write!(f, "Display {} is not connected", display)
+            }
+            GfxError::Egl { stage, code } => {
+                write!(f, "EGL {:?} failed (error 0x{:04x})", stage, code)
+            }
+            GfxError::MissingExtension(name) => write!(f, "{} is not supported", name),
+            GfxError::BufferCount(count) => write!(
+                f,
+                "Unsupported buffer count {}, must be between {} and {}",
+                count, MIN_BUFFER_COUNT, MAX_BUFFER_COUNT
+            ),
+            GfxError::UnsupportedGlesVersion(version) => {
+                write!(f, "Unsupported GLES version {}", version)
+            }
+            GfxError::Shader(ref log) => write!(f, "Failed to build a shader: {}", log),
+            GfxError::Gralloc { call, code } => {
+                write!(f, "gralloc {} failed (error {})", call, code)
+            }
+            GfxError::UnsupportedFormat(format) => {
+                write!(f, "Unsupported pixel format 0x{:x}", format)
+            }
+            GfxError::UnsupportedTextureTarget(target) => {
+                write!(f, "Unsupported texture target 0x{:x}", target)
+            }
+            GfxError::GrallocAlloc {
+                width,
+                height,
+                format,
+                usage,
+                code,
+            } => write!(
+                f,
+                "Failed to allocate a {}x{} gralloc buffer, format {} usage 0x{:x} (error {})",
+                width, height, format, usage, code
+            ),
+            GfxError::RectOutOfBounds {
+                rect,
+                width,
+                height,
+            } => write!(f, "{:?} is out of the {}x{} buffer", rect, width, height),
+            GfxError::NativeWindow { operation, code } => write!(
+                f,
+                "Native window operation {} failed (error {})",
+                operation, code
+            ),
+        }
+    }
+}
+
+impl Error for GfxError {}
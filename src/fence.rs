@@ -0,0 +1,266 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Sync fences, the fds through which the GPU and the HWC tell each other
+//! when they are done with a buffer.
+//!
+//! Both the sync_file interface of upstream kernels and the older Android
+//! sync driver are supported. Waiting only needs poll(), so any fd that
+//! becomes readable can stand in for a fence, as the tests do with pipes.
+
+use libc::{self, c_char, c_int, pollfd};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::ptr;
+use std::time::Duration;
+
+// From include/uapi/linux/sync_file.h
+
+#[repr(C)]
+struct sync_merge_data {
+    name: [c_char; 32],
+    fd2: i32,
+    fence: i32,
+    flags: u32,
+    pad: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct sync_fence_info {
+    obj_name: [c_char; 32],
+    driver_name: [c_char; 32],
+    status: i32,
+    flags: u32,
+    timestamp_ns: u64,
+}
+
+#[repr(C)]
+struct sync_file_info {
+    name: [c_char; 32],
+    status: i32,
+    flags: u32,
+    num_fences: u32,
+    pad: u32,
+    sync_fence_info: u64,
+}
+
+const SYNC_IOC_MERGE: u32 = 0xc030_3e03;
+const SYNC_IOC_FILE_INFO: u32 = 0xc038_3e04;
+
+// From the Android sync driver, drivers/staging/android/uapi/sync.h
+
+#[repr(C)]
+struct legacy_sync_merge_data {
+    fd2: i32,
+    name: [c_char; 32],
+    fence: i32,
+}
+
+// The header of the info buffer, followed by a legacy_sync_pt_info for
+// each point of the fence.
+#[repr(C)]
+struct legacy_sync_fence_info_data {
+    len: u32,
+    name: [c_char; 32],
+    status: i32,
+}
+
+#[repr(C)]
+struct legacy_sync_pt_info {
+    len: u32,
+    obj_name: [c_char; 32],
+    driver_name: [c_char; 32],
+    status: i32,
+    timestamp_ns: u64,
+}
+
+const LEGACY_SYNC_IOC_MERGE: u32 = 0xc028_3e01;
+const LEGACY_SYNC_IOC_FENCE_INFO: u32 = 0xc028_3e02;
+
+// Plenty for the handful of points a fence merges in practice.
+const LEGACY_INFO_SIZE: usize = 4096;
+
+fn fence_name(name: &str) -> [c_char; 32] {
+    let mut buf = [0; 32];
+    for (dst, src) in buf.iter_mut().zip(name.bytes().take(31)) {
+        *dst = src as c_char;
+    }
+    buf
+}
+
+fn is_enotty(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOTTY)
+}
+
+/// An owned fence fd, closed on drop.
+#[derive(Debug)]
+pub struct Fence {
+    fd: RawFd,
+}
+
+impl Fence {
+    /// Takes ownership of a fence fd as handed out by the HALs, where a
+    /// negative value means that there is nothing to wait for.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open fd that nothing else will close.
+    pub unsafe fn from_raw(fd: RawFd) -> Option<Fence> {
+        if fd < 0 {
+            None
+        } else {
+            Some(Fence { fd })
+        }
+    }
+
+    /// Waits for the fence to signal, for at most `timeout` if there is
+    /// one. Returns whether it signalled.
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        // Rounded up, so that short waits don't turn into polls.
+        let timeout = match timeout {
+            Some(timeout) => {
+                let ms = timeout.as_secs() as u128 * 1000
+                    + u128::from(timeout.subsec_nanos()).div_ceil(1_000_000);
+                ms.min(c_int::MAX as u128) as c_int
+            }
+            None => -1,
+        };
+        let mut fds = [pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        loop {
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), 1, timeout) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if ret == 0 {
+                return Ok(false);
+            }
+            let revents = fds[0].revents;
+            if revents & libc::POLLNVAL != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EBADF));
+            }
+            if revents & libc::POLLERR != 0 {
+                return Err(io::Error::other("fence signalled with an error"));
+            }
+            return Ok(true);
+        }
+    }
+
+    /// Creates a fence that signals once both `self` and `other` have.
+    pub fn merge(&self, other: &Fence, name: &str) -> io::Result<Fence> {
+        let mut data = sync_merge_data {
+            name: fence_name(name),
+            fd2: other.fd,
+            fence: -1,
+            flags: 0,
+            pad: 0,
+        };
+        match self.ioctl(SYNC_IOC_MERGE, &mut data) {
+            Ok(()) => return Ok(Fence { fd: data.fence }),
+            Err(ref err) if is_enotty(err) => {}
+            Err(err) => return Err(err),
+        }
+        let mut data = legacy_sync_merge_data {
+            fd2: other.fd,
+            name: fence_name(name),
+            fence: -1,
+        };
+        self.ioctl(LEGACY_SYNC_IOC_MERGE, &mut data)?;
+        Ok(Fence { fd: data.fence })
+    }
+
+    /// The CLOCK_MONOTONIC time in nanoseconds at which the fence
+    /// signalled, that of its last point for merged fences, or None if it
+    /// hasn't signalled yet.
+    pub fn signal_time(&self) -> io::Result<Option<i64>> {
+        let mut info: sync_file_info = unsafe { mem::zeroed() };
+        match self.ioctl(SYNC_IOC_FILE_INFO, &mut info) {
+            Ok(()) => {}
+            Err(ref err) if is_enotty(err) => return self.legacy_signal_time(),
+            Err(err) => return Err(err),
+        }
+        if Self::status(info.status)? == 0 {
+            return Ok(None);
+        }
+        let mut fences: Vec<sync_fence_info> =
+            vec![unsafe { mem::zeroed() }; info.num_fences as usize];
+        info.sync_fence_info = fences.as_mut_ptr() as u64;
+        self.ioctl(SYNC_IOC_FILE_INFO, &mut info)?;
+        Ok(fences.iter().map(|fence| fence.timestamp_ns as i64).max())
+    }
+
+    fn legacy_signal_time(&self) -> io::Result<Option<i64>> {
+        // Backed by u64s to keep the points aligned.
+        let mut buf = vec![0u64; LEGACY_INFO_SIZE / 8];
+        let base = buf.as_mut_ptr() as *mut u8;
+        let header = base as *mut legacy_sync_fence_info_data;
+        unsafe {
+            (*header).len = LEGACY_INFO_SIZE as u32;
+            self.ioctl(LEGACY_SYNC_IOC_FENCE_INFO, &mut *header)?;
+            if Self::status((*header).status)? == 0 {
+                return Ok(None);
+            }
+            let len = ((*header).len as usize).min(LEGACY_INFO_SIZE);
+            let mut offset = mem::size_of::<legacy_sync_fence_info_data>();
+            let mut time = None;
+            while offset + mem::size_of::<legacy_sync_pt_info>() <= len {
+                let pt = ptr::read_unaligned(base.add(offset) as *const legacy_sync_pt_info);
+                time = time.max(Some(pt.timestamp_ns as i64));
+                if pt.len == 0 {
+                    break;
+                }
+                offset += pt.len as usize;
+            }
+            Ok(time)
+        }
+    }
+
+    // Fence status as reported by the kernel: 1 when signalled, 0 while
+    // pending, negative on error.
+    fn status(status: i32) -> io::Result<i32> {
+        if status < 0 {
+            Err(io::Error::from_raw_os_error(-status))
+        } else {
+            Ok(status)
+        }
+    }
+
+    fn ioctl<T>(&self, request: u32, arg: &mut T) -> io::Result<()> {
+        let ret = unsafe { libc::ioctl(self.fd, request as _, arg as *mut T) };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl AsRawFd for Fence {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl IntoRawFd for Fence {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.fd;
+        mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for Fence {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
@@ -4,12 +4,23 @@
 
 // Low level Gonk graphics using the hardware composer.
 
+use error::GfxError;
+use fence::Fence;
 use gralloc::*;
 use hwc::*;
-use libc::{c_int, c_void, close, size_t};
-use std::mem::{size_of, transmute, zeroed};
+use libc::{self, c_int, c_void, size_t};
+use std::collections::VecDeque;
+use std::mem::{self, size_of, transmute, zeroed};
+use std::os::unix::io::IntoRawFd;
 use std::ptr;
-
+use std::rc::Rc;
+use std::slice;
+use std::time::Duration;
+
+pub const GRALLOC_USAGE_SW_READ_RARELY: c_int = 0x00000002;
+pub const GRALLOC_USAGE_SW_READ_OFTEN: c_int = 0x00000003;
+pub const GRALLOC_USAGE_SW_WRITE_RARELY: c_int = 0x00000020;
+pub const GRALLOC_USAGE_SW_WRITE_OFTEN: c_int = 0x00000030;
 pub const GRALLOC_USAGE_HW_TEXTURE: c_int = 0x00000100;
 pub const GRALLOC_USAGE_HW_RENDER: c_int = 0x00000200;
 pub const GRALLOC_USAGE_HW_2D: c_int = 0x00000400;
@@ -20,10 +31,10 @@ pub const GRALLOC_USAGE_HW_FB: c_int = 0x00001000;
 
 #[repr(C)]
 pub struct native_handle {
-    version: c_int,
-    num_fds: c_int,
-    num_ints: c_int,
-    data: [c_int; 0],
+    pub version: c_int,
+    pub num_fds: c_int,
+    pub num_ints: c_int,
+    pub data: [c_int; 0],
 }
 
 // system/core/include/system/window.h
@@ -33,23 +44,32 @@ pub struct ANativeBase {
     magic: u32,
     version: u32,
     reserved: [isize; 4],
-    inc_ref: extern "C" fn(*mut ANativeBase),
+    pub inc_ref: extern "C" fn(*mut ANativeBase),
     pub dec_ref: extern "C" fn(*mut ANativeBase),
 }
 
 #[repr(C)]
 pub struct ANativeWindowBuffer {
-    common: ANativeBase,
-    width: c_int,
-    height: c_int,
-    stride: c_int,
-    format: c_int,
-    usage: c_int,
+    pub common: ANativeBase,
+    pub width: c_int,
+    pub height: c_int,
+    pub stride: c_int,
+    pub format: c_int,
+    pub usage: c_int,
     reserved: [*mut c_void; 2],
-    handle: *const native_handle,
+    pub handle: *const native_handle,
     reserved_proc: [*mut c_void; 8],
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct android_native_rect {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
 #[repr(C)]
 pub struct ANativeWindow {
     pub common: ANativeBase,
@@ -63,13 +83,13 @@ pub struct ANativeWindow {
     dequeue_buffer_deprecated: *const c_void,
     lock_buffer_deprecated: *const c_void,
     queue_buffer_deprecated: *const c_void,
-    query: extern "C" fn(*const ANativeWindow, c_int, *mut c_int) -> c_int,
-    perform: unsafe extern "C" fn(*mut ANativeWindow, c_int, ...) -> c_int,
+    pub query: extern "C" fn(*const ANativeWindow, c_int, *mut c_int) -> c_int,
+    pub perform: unsafe extern "C" fn(*mut ANativeWindow, c_int, ...) -> c_int,
     cancel_buffer_deprecated: *const c_void,
-    dequeue_buffer:
+    pub dequeue_buffer:
         extern "C" fn(*mut ANativeWindow, *mut *mut ANativeWindowBuffer, *mut c_int) -> c_int,
-    queue_buffer: extern "C" fn(*mut ANativeWindow, *mut ANativeWindowBuffer, c_int) -> c_int,
-    cancel_buffer: extern "C" fn(*mut ANativeWindow, *mut ANativeWindowBuffer, c_int) -> c_int,
+    pub queue_buffer: extern "C" fn(*mut ANativeWindow, *mut ANativeWindowBuffer, c_int) -> c_int,
+    pub cancel_buffer: extern "C" fn(*mut ANativeWindow, *mut ANativeWindowBuffer, c_int) -> c_int,
 }
 
 #[repr(C)]
@@ -81,17 +101,47 @@ pub struct GonkNativeWindow {
     set_dimensions: extern "C" fn(*mut GonkNativeWindow, c_int, c_int) -> c_int,
     api_connect: extern "C" fn(*mut GonkNativeWindow, c_int) -> c_int,
     api_disconnect: extern "C" fn(*mut GonkNativeWindow, c_int) -> c_int,
+    set_crop: extern "C" fn(*mut GonkNativeWindow, *const android_native_rect) -> c_int,
+    set_surface_damage:
+        extern "C" fn(*mut GonkNativeWindow, *const android_native_rect, size_t) -> c_int,
     count: i32,
-    alloc_dev: *mut alloc_device,
-    hwc_dev: *mut hwc_composer_device,
+    gralloc: Gralloc,
+    hwc: Rc<HwcDevice>,
+    display: c_int,
+    // The size of the buffers, which the HWC scales to the default size,
+    // that of the display.
     width: i32,
     height: i32,
-    format: c_int,
+    default_width: i32,
+    default_height: i32,
+    // The HWC_TRANSFORM_* the HWC applies to the buffers to show them.
+    transform: u32,
+    // The transform we'd like the buffers to be rendered with, see
+    // set_transform_hint().
+    transform_hint: u32,
+    format: PixelFormat,
+    // The format set_format(0) goes back to.
+    default_format: PixelFormat,
     usage: c_int,
-    last_fence: c_int,
-    last_idx: i32,
-    bufs: [Option<*mut GonkNativeWindowBuffer>; 2],
-    fences: [c_int; 2],
+    buffer_count: usize,
+    slots: Vec<BufferSlot>,
+    // The slot last queued, which is on screen.
+    front: Option<usize>,
+    // The slot last dequeued, whose age NATIVE_WINDOW_BUFFER_AGE reports.
+    last_dequeued: Option<usize>,
+    // The number of buffers queued so far.
+    frame: u64,
+    prepared: Option<DisplayContents>,
+    // The release fences of the layers below the framebuffer target, the
+    // last time prepared contents were set.
+    layer_releases: Vec<Option<Fence>>,
+    // The part of the buffers to show, all of it if None.
+    crop: Option<hwc_rect>,
+    // What changed in the next buffer to be queued, in window coordinates.
+    // Empty if unknown, meaning everything.
+    damage: Vec<hwc_rect>,
+    // The frames not yet collected by frame_timings(), oldest first.
+    timings: VecDeque<PendingTiming>,
 }
 
 impl ANativeBase {
@@ -100,10 +150,93 @@ impl ANativeBase {
     }
 }
 
+/// The number of buffers a GonkNativeWindow can cycle through.
+pub const MIN_BUFFER_COUNT: usize = 2;
+pub const MAX_BUFFER_COUNT: usize = 4;
+
+// The display keeps the front buffer until the next one is queued.
+const MIN_UNDEQUEUED_BUFFERS: c_int = 1;
+
+// How many frames frame_timings() remembers, older ones are forgotten.
+const MAX_FRAME_TIMINGS: usize = 64;
+
+/// When a frame was queued and retired, see
+/// `GonkNativeWindow::frame_timings()`. Times are CLOCK_MONOTONIC, in
+/// nanoseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameTiming {
+    /// The number of buffers queued up to and including this frame.
+    pub frame: u64,
+    pub queue_time: i64,
+    /// When the frame was replaced on the display by the next one, which
+    /// is when the next one started being shown. None if the HWC didn't
+    /// provide a retire fence.
+    pub retire_time: Option<i64>,
+}
+
+struct PendingTiming {
+    timing: FrameTiming,
+    retire_fence: Option<Fence>,
+}
+
+impl PendingTiming {
+    // Whether the retire time is known, or will never be.
+    fn poll(&mut self) -> bool {
+        let fence = match self.retire_fence {
+            Some(ref fence) => fence,
+            None => return true,
+        };
+        match fence.wait(Some(Duration::from_millis(0))) {
+            Ok(false) => return false,
+            Ok(true) => {
+                // Fences that aren't sync files don't know when they
+                // signalled, now is the best we can do.
+                self.timing.retire_time = match fence.signal_time() {
+                    Ok(time) => time,
+                    Err(_) => Some(monotonic_time()),
+                };
+            }
+            Err(err) => error!("Waiting for the retire fence: {}", err),
+        }
+        self.retire_fence = None;
+        true
+    }
+}
+
+fn monotonic_time() -> i64 {
+    let mut ts: libc::timespec = unsafe { zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as i64 * 1_000_000_000 + ts.tv_nsec as i64
+}
+
+struct BufferSlot {
+    buffer: *mut GonkNativeWindowBuffer,
+    // The release fence handed out with the buffer on its next dequeue.
+    fence: Option<Fence>,
+    dequeued: bool,
+    // The frame at which the buffer was last queued, 0 if never.
+    queued_frame: u64,
+    // Whether the buffer has to be reallocated on its next dequeue.
+    stale: bool,
+}
+
+impl BufferSlot {
+    fn new(buffer: *mut GonkNativeWindowBuffer) -> BufferSlot {
+        BufferSlot {
+            buffer,
+            fence: None,
+            dequeued: false,
+            queued_frame: 0,
+            stale: false,
+        }
+    }
+}
+
 #[repr(C)]
 pub struct GonkNativeWindowBuffer {
     buffer: ANativeWindowBuffer,
     count: i32,
+    gralloc_buffer: GrallocBuffer,
 }
 
 #[link(name = "native_window_glue", kind = "static")]
@@ -111,27 +244,39 @@ extern "C" {
     fn gnw_perform(win: *mut ANativeWindow, op: c_int, ...) -> c_int;
 }
 
+#[cfg(not(feature = "mock-hal"))]
 #[link(name = "suspend")]
 extern "C" {
     pub fn autosuspend_disable();
     pub fn autosuspend_enable();
 }
 
+#[cfg(feature = "mock-hal")]
+pub use mock_hal::{autosuspend_disable, autosuspend_enable};
+
 extern "C" fn set_swap_interval(_base: *mut ANativeWindow, _interval: c_int) -> c_int {
     debug!("set_swap_interval");
     0
 }
 
-const NATIVE_WINDOW_WIDTH: c_int = 0;
-const NATIVE_WINDOW_HEIGHT: c_int = 1;
-const NATIVE_WINDOW_FORMAT: c_int = 2;
-const NATIVE_WINDOW_SET_CROP: c_int = 3;
-const NATIVE_WINDOW_DEFAULT_WIDTH: c_int = 6;
-const NATIVE_WINDOW_DEFAULT_HEIGHT: c_int = 7;
-const NATIVE_WINDOW_TRANSFORM_HINT: c_int = 8;
-const NATIVE_WINDOW_CONSUMER_USAGE_BITS: c_int = 10;
-const NATIVE_WINDOW_DEFAULT_DATASPACE: c_int = 12;
-const NATIVE_WINDOW_BUFFER_AGE: c_int = 13;
+pub const NATIVE_WINDOW_WIDTH: c_int = 0;
+pub const NATIVE_WINDOW_HEIGHT: c_int = 1;
+pub const NATIVE_WINDOW_FORMAT: c_int = 2;
+pub const NATIVE_WINDOW_MIN_UNDEQUEUED_BUFFERS: c_int = 3;
+pub const NATIVE_WINDOW_DEFAULT_WIDTH: c_int = 6;
+pub const NATIVE_WINDOW_DEFAULT_HEIGHT: c_int = 7;
+pub const NATIVE_WINDOW_TRANSFORM_HINT: c_int = 8;
+pub const NATIVE_WINDOW_CONSUMER_USAGE_BITS: c_int = 10;
+pub const NATIVE_WINDOW_DEFAULT_DATASPACE: c_int = 12;
+pub const NATIVE_WINDOW_BUFFER_AGE: c_int = 13;
+
+// Some of the perform() operations, see native_window_glue.cpp.
+pub const NATIVE_WINDOW_SET_USAGE: c_int = 0;
+pub const NATIVE_WINDOW_SET_CROP: c_int = 3;
+pub const NATIVE_WINDOW_SET_BUFFERS_TRANSFORM: c_int = 6;
+pub const NATIVE_WINDOW_SET_BUFFERS_DIMENSIONS: c_int = 8;
+pub const NATIVE_WINDOW_SET_BUFFERS_FORMAT: c_int = 9;
+pub const NATIVE_WINDOW_SET_SURFACE_DAMAGE: c_int = 20;
 
 extern "C" fn query(base: *const ANativeWindow, what: c_int, value: *mut c_int) -> c_int {
     info!("query {}", what);
@@ -148,24 +293,24 @@ extern "C" fn query(base: *const ANativeWindow, what: c_int, value: *mut c_int)
                 0
             }
             NATIVE_WINDOW_FORMAT => {
-                *value = window.format;
+                *value = window.format.to_hal();
                 0
             }
-            NATIVE_WINDOW_SET_CROP => {
-                // TODO: save for later use?
+            NATIVE_WINDOW_MIN_UNDEQUEUED_BUFFERS => {
+                *value = MIN_UNDEQUEUED_BUFFERS;
                 0
             }
 
             NATIVE_WINDOW_DEFAULT_WIDTH => {
-                *value = window.width;
+                *value = window.default_width;
                 0
             }
             NATIVE_WINDOW_DEFAULT_HEIGHT => {
-                *value = window.height;
+                *value = window.default_height;
                 0
             }
             NATIVE_WINDOW_TRANSFORM_HINT => {
-                *value = 0;
+                *value = window.transform_hint as c_int;
                 0
             }
             NATIVE_WINDOW_CONSUMER_USAGE_BITS => {
@@ -177,7 +322,7 @@ extern "C" fn query(base: *const ANativeWindow, what: c_int, value: *mut c_int)
                 0
             }
             NATIVE_WINDOW_BUFFER_AGE => {
-                *value = 0;
+                *value = window.buffer_age();
                 0
             }
             _ => {
@@ -194,32 +339,59 @@ extern "C" fn dequeue_buffer(
     fence: *mut c_int,
 ) -> c_int {
     info!("dequeue_buffer");
+    let window: &mut GonkNativeWindow = unsafe { transmute(base) };
+    debug!(
+        "We have {} buffers, front={:?}",
+        window.slots.len(),
+        window.front
+    );
+    // The oldest buffer is the most likely to have been released already.
+    let front = window.front;
+    let oldest = window
+        .slots
+        .iter()
+        .enumerate()
+        .filter(|(idx, slot)| !slot.dequeued && Some(*idx) != front)
+        .min_by_key(|(_, slot)| slot.queued_frame)
+        .map(|(idx, _)| idx);
+    let idx = match oldest {
+        Some(idx) => idx,
+        None => {
+            error!("No buffer left to dequeue");
+            return -1;
+        }
+    };
+    if let Err(err) = window.refresh_slot(idx) {
+        error!("{}", err);
+        return -1;
+    }
+    debug!("Dequeuing buffer {}", idx);
+    window.last_dequeued = Some(idx);
+    let slot = &mut window.slots[idx];
+    slot.dequeued = true;
     unsafe {
-        let window: &mut GonkNativeWindow = transmute(base);
-        debug!(
-            "We have {} buffers, last_idx={}",
-            window.bufs.len(),
-            window.last_idx
-        );
-        for idx in 0..window.bufs.len() {
-            if idx == window.last_idx as usize {
-                continue;
-            }
-            match window.bufs[idx] {
-                Some(entry) => {
-                    debug!("Buffer {} exists", idx);
-                    (*buf) = transmute(entry);
-                    window.bufs[idx] = None;
-                    *fence = window.fences[idx];
-                    window.fences[idx] = -1;
-                    return 0;
-                }
-                None => debug!("Buffer {} is None", idx),
-            }
+        *buf = slot.buffer as *mut ANativeWindowBuffer;
+        *fence = slot.fence.take().map_or(-1, Fence::into_raw_fd);
+    }
+    0
+}
+
+impl GonkNativeWindow {
+    // The number of frames since the contents of the last dequeued buffer
+    // were queued, as defined by EGL_EXT_buffer_age: 1 if it holds the
+    // previous frame, and 0 if its contents are undefined.
+    fn buffer_age(&self) -> c_int {
+        match self.last_dequeued.map(|idx| &self.slots[idx]) {
+            Some(slot) if slot.queued_frame > 0 => (self.frame + 1 - slot.queued_frame) as c_int,
+            _ => 0,
         }
     }
-    error!("returning -1!!");
-    -1
+
+    fn dequeued_slot(&self, buf: *mut ANativeWindowBuffer) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| slot.dequeued && slot.buffer as *mut ANativeWindowBuffer == buf)
+    }
 }
 
 extern "C" fn queue_buffer(
@@ -228,21 +400,32 @@ extern "C" fn queue_buffer(
     fence: c_int,
 ) -> c_int {
     info!("queue_buffer");
-    unsafe {
-        let window: &mut GonkNativeWindow = transmute(base);
-        for idx in 0..window.bufs.len() {
-            match window.bufs[idx] {
-                Some(_) => (),
-                None => {
-                    window.last_idx = idx as i32;
-                    window.bufs[idx] = Some(transmute(buf));
-                    window.fences[idx] = window.draw(buf, fence);
-                    return 0;
+    let window: &mut GonkNativeWindow = unsafe { transmute(base) };
+    // The fence is ours even if the buffer turns out not to be.
+    let fence = unsafe { Fence::from_raw(fence) };
+    match window.dequeued_slot(buf) {
+        Some(idx) => {
+            // Setting another display's contents may have given the front
+            // buffer a later release fence.
+            if let Some(front) = window.front {
+                if let Some(fence) = window.hwc.take_target_release_fence(window.display) {
+                    window.slots[front].fence = Some(fence);
                 }
             }
+            window.frame += 1;
+            window.front = Some(idx);
+            let release_fence = window.draw(buf, fence);
+            let slot = &mut window.slots[idx];
+            slot.dequeued = false;
+            slot.queued_frame = window.frame;
+            slot.fence = release_fence;
+            0
+        }
+        None => {
+            error!("Queuing a buffer that wasn't dequeued");
+            -1
         }
     }
-    -1
 }
 
 extern "C" fn cancel_buffer(
@@ -251,47 +434,80 @@ extern "C" fn cancel_buffer(
     fence: c_int,
 ) -> c_int {
     info!("cancel_buffer");
-    unsafe {
-        let window: &mut GonkNativeWindow = transmute(base);
-        for idx in 0..window.bufs.len() {
-            match window.bufs[idx] {
-                Some(_) => (),
-                None => {
-                    window.bufs[idx] = Some(transmute(buf));
-                    window.fences[idx] = -1;
-                    close(fence);
-                    return 0;
-                }
-            }
+    let window: &mut GonkNativeWindow = unsafe { transmute(base) };
+    let fence = unsafe { Fence::from_raw(fence) };
+    match window.dequeued_slot(buf) {
+        Some(idx) => {
+            let slot = &mut window.slots[idx];
+            slot.dequeued = false;
+            slot.fence = fence;
+            0
+        }
+        None => {
+            error!("Cancelling a buffer that wasn't dequeued");
+            -1
         }
     }
-    -1
 }
 
 extern "C" fn set_usage(window: *mut GonkNativeWindow, usage: c_int) -> c_int {
     info!("Setting usage flags to {}", usage);
-    unsafe {
-        (*window).usage = usage;
-        (*window).alloc_buffers();
-    }
+    let window = unsafe { &mut *window };
+    let format = window.format;
+    window.set_buffer_params(format, usage);
     0
 }
 
 extern "C" fn set_format(window: *mut GonkNativeWindow, format: c_int) -> c_int {
     info!("Setting format to {}", format);
-    unsafe {
-        (*window).format = format;
-    }
+    let window = unsafe { &mut *window };
+    let format = if format == 0 {
+        window.default_format
+    } else {
+        match PixelFormat::from_hal(format) {
+            Some(format) => format,
+            None => {
+                error!("{}", GfxError::UnsupportedFormat(format));
+                return -1;
+            }
+        }
+    };
+    let usage = window.usage;
+    window.set_buffer_params(format, usage);
     0
 }
 
-extern "C" fn set_transform(_: *mut GonkNativeWindow, _: c_int) -> c_int {
-    info!("set_transform");
+extern "C" fn set_transform(window: *mut GonkNativeWindow, transform: c_int) -> c_int {
+    info!("set_transform to {}", transform);
+    if transform as u32 & !HWC_TRANSFORM_ROT_270 != 0 {
+        error!("Invalid transform {}", transform);
+        return -1;
+    }
+    unsafe {
+        (*window).transform = transform as u32;
+    }
     0
 }
 
-extern "C" fn set_dimensions(_: *mut GonkNativeWindow, width: c_int, height: c_int) -> c_int {
+extern "C" fn set_dimensions(window: *mut GonkNativeWindow, width: c_int, height: c_int) -> c_int {
     info!("set_dimensions to {}x{}", width, height);
+    let window = unsafe { &mut *window };
+    match (width, height) {
+        // Back to the size of the display.
+        (0, 0) => {
+            window.width = window.default_width;
+            window.height = window.default_height;
+        }
+        (width, height) if width > 0 && height > 0 => {
+            window.width = width;
+            window.height = height;
+        }
+        _ => {
+            error!("Invalid buffer dimensions {}x{}", width, height);
+            return -1;
+        }
+    }
+    // The buffers are reallocated as they get dequeued.
     0
 }
 
@@ -305,6 +521,48 @@ extern "C" fn api_disconnect(_window: *mut GonkNativeWindow, _api: c_int) -> c_i
     0
 }
 
+extern "C" fn set_crop(window: *mut GonkNativeWindow, rect: *const android_native_rect) -> c_int {
+    let crop = unsafe { rect.as_ref() }
+        .filter(|rect| rect.right > rect.left && rect.bottom > rect.top)
+        .map(|rect| hwc_rect {
+            left: rect.left,
+            top: rect.top,
+            right: rect.right,
+            bottom: rect.bottom,
+        });
+    info!("set_crop to {:?}", crop);
+    unsafe {
+        (*window).crop = crop;
+    }
+    0
+}
+
+extern "C" fn set_surface_damage(
+    window: *mut GonkNativeWindow,
+    rects: *const android_native_rect,
+    num_rects: size_t,
+) -> c_int {
+    info!("set_surface_damage with {} rects", num_rects);
+    let window = unsafe { &mut *window };
+    window.damage.clear();
+    if rects.is_null() {
+        return 0;
+    }
+    // Like EGL, the rects have their origin at the bottom left.
+    let height = window.height;
+    window.damage.extend(
+        unsafe { slice::from_raw_parts(rects, num_rects) }
+            .iter()
+            .map(|rect| hwc_rect {
+                left: rect.left,
+                top: height - rect.top,
+                right: rect.right,
+                bottom: height - rect.bottom,
+            }),
+    );
+    0
+}
+
 extern "C" fn gnw_inc_ref(base: *mut ANativeBase) {
     info!("gnw_inc_ref");
     let win: &mut GonkNativeWindow = unsafe { transmute(base) };
@@ -322,12 +580,21 @@ extern "C" fn gnw_dec_ref(base: *mut ANativeBase) {
 
 impl GonkNativeWindow {
     pub fn new(
-        hwc_dev: *mut hwc_composer_device,
+        hwc: Rc<HwcDevice>,
+        display: c_int,
         width: i32,
         height: i32,
+        format: PixelFormat,
         usage: c_int,
-    ) -> *mut GonkNativeWindow {
-        let alloc_dev = get_gralloc_module();
+        buffer_count: usize,
+    ) -> Result<*mut GonkNativeWindow, GfxError> {
+        if !(MIN_BUFFER_COUNT..=MAX_BUFFER_COUNT).contains(&buffer_count) {
+            return Err(GfxError::BufferCount(buffer_count));
+        }
+        if display < 0 || display as usize >= HWC_NUM_DISPLAY_TYPES {
+            return Err(GfxError::DisplayNotConnected(display));
+        }
+        let gralloc = get_gralloc_module()?;
         let window = Box::new(GonkNativeWindow {
             window: ANativeWindow {
                 common: ANativeBase {
@@ -360,23 +627,83 @@ impl GonkNativeWindow {
             set_dimensions: set_dimensions,
             api_connect: api_connect,
             api_disconnect: api_disconnect,
+            set_crop: set_crop,
+            set_surface_damage: set_surface_damage,
             count: 1,
-            alloc_dev: alloc_dev,
-            hwc_dev: hwc_dev,
+            gralloc: gralloc,
+            hwc,
+            display: display,
             width: width,
             height: height,
-            format: 0,
+            default_width: width,
+            default_height: height,
+            transform: 0,
+            transform_hint: 0,
+            format,
+            default_format: format,
             usage: usage,
-            last_fence: -1,
-            last_idx: -1,
-            bufs: unsafe { zeroed() },
-            fences: [-1, -1],
+            buffer_count,
+            slots: vec![],
+            front: None,
+            last_dequeued: None,
+            frame: 0,
+            prepared: None,
+            layer_releases: vec![],
+            crop: None,
+            damage: vec![],
+            timings: VecDeque::new(),
         });
 
-        unsafe { transmute(window) }
+        Ok(unsafe { transmute(window) })
+    }
+
+    /// Runs prepare() on contents for the window's display, ending with
+    /// a HWC_FRAMEBUFFER_TARGET layer. They are kept for the next queued
+    /// buffer, which becomes the framebuffer target, and the HWC's choice
+    /// of composition for each layer can be read back from them.
+    pub fn prepare(&mut self, mut contents: DisplayContents) -> Result<&DisplayContents, GfxError> {
+        self.prepared = None;
+        contents.update_visible_regions();
+        self.hwc.prepare(self.display, &mut contents)?;
+        Ok(self.prepared.get_or_insert(contents))
+    }
+
+    /// Drops the contents saved by prepare(), if they won't be used.
+    pub fn discard_prepared(&mut self) {
+        self.prepared = None;
+    }
+
+    /// Takes the release fences of the layers given to prepare(), but the
+    /// framebuffer target, once a buffer was queued with them. Each one
+    /// signals when the display is done reading the layer's buffer, None
+    /// if it isn't reading it, e.g. for layers composited with GLES.
+    pub fn take_layer_release_fences(&mut self) -> Vec<Option<Fence>> {
+        mem::take(&mut self.layer_releases)
+    }
+
+    /// The id of the last frame queued, 0 if none was.
+    pub fn last_frame(&self) -> u64 {
+        self.frame
     }
 
-    fn draw(&mut self, buf: *mut ANativeWindowBuffer, fence: c_int) -> c_int {
+    /// Returns the timings of the frames queued since the last call, in
+    /// order, up to the first one still on the display. Only the last 64
+    /// frames are kept.
+    pub fn frame_timings(&mut self) -> Vec<FrameTiming> {
+        let mut timings = vec![];
+        while let Some(pending) = self.timings.front_mut() {
+            if !pending.poll() {
+                break;
+            }
+            timings.push(pending.timing);
+            self.timings.pop_front();
+        }
+        timings
+    }
+
+    fn draw(&mut self, buf: *mut ANativeWindowBuffer, fence: Option<Fence>) -> Option<Fence> {
+        let queue_time = monotonic_time();
+        self.layer_releases.clear();
         let gonkbuf: &mut GonkNativeWindowBuffer = unsafe { transmute(buf) };
         info!("draw {}x{}", gonkbuf.buffer.width, gonkbuf.buffer.height);
         let rect = hwc_rect {
@@ -385,109 +712,188 @@ impl GonkNativeWindow {
             right: gonkbuf.buffer.width,
             bottom: gonkbuf.buffer.height,
         };
-        let mut list = hwc_display_contents {
-            retire_fence_fd: -1,
-            outbuf: ptr::null(),
-            outbuf_acquire_fence_fd: -1,
-            flags: HWC_GEOMETRY_CHANGED,
-            num_hw_layers: 2,
-            hw_layers: [
-                hwc_layer {
-                    composition_type: HWC_FRAMEBUFFER,
-                    hints: 0,
-                    flags: HWC_SKIP_LAYER,
-                    handle: ptr::null(),
-                    transform: 0,
-                    blending: 0,
-                    source_crop: hwc_frect {
-                        left: 0.0,
-                        top: 0.0,
-                        right: 0.0,
-                        bottom: 0.0,
-                    },
-                    display_frame: rect,
-                    visible_region_screen: hwc_region {
-                        num_rects: 0,
-                        rects: ptr::null(),
-                    },
-                    acquire_fence_fd: -1,
-                    release_fence_fd: -1,
-                    plane_alpha: 0xff,
-                    pad: [0; 3],
-                    surface_damage: hwc_region {
-                        num_rects: 0,
-                        rects: ptr::null(),
-                    },
-                    reserved: [0; 12],
-                },
-                hwc_layer {
-                    composition_type: HWC_FRAMEBUFFER_TARGET,
-                    hints: 0,
-                    flags: 0,
-                    handle: gonkbuf.buffer.handle,
-                    transform: 0,
-                    blending: HWC_BLENDING_NONE,
-                    source_crop: hwc_frect {
-                        left: 0.0,
-                        top: 0.0,
-                        right: gonkbuf.buffer.width as f32,
-                        bottom: gonkbuf.buffer.height as f32,
-                    },
-                    display_frame: rect,
-                    visible_region_screen: hwc_region {
-                        num_rects: 1,
-                        rects: &rect,
-                    },
-                    acquire_fence_fd: fence,
-                    release_fence_fd: -1,
-                    plane_alpha: 0xff,
-                    pad: [0; 3],
-                    surface_damage: hwc_region {
-                        num_rects: 0,
-                        rects: ptr::null(),
-                    },
-                    reserved: [0; 12],
-                },
-            ],
+        // Buffers smaller than the display get scaled up by the HWC.
+        let display_frame = hwc_rect {
+            left: 0,
+            top: 0,
+            right: self.default_width,
+            bottom: self.default_height,
         };
-        unsafe {
-            let mut displays: [*mut hwc_display_contents; HWC_NUM_DISPLAY_TYPES] =
-                [&mut list, ptr::null_mut(), ptr::null_mut()];
-            let prep_res = ((*self.hwc_dev).prepare)(
-                self.hwc_dev,
-                displays.len() as size_t,
-                transmute(displays.as_mut_ptr()),
-            );
-            info!("hwc.prepare returned {}", prep_res);
-            let set_res = ((*self.hwc_dev).set)(
-                self.hwc_dev,
-                displays.len() as size_t,
-                transmute(displays.as_mut_ptr()),
-            );
-            info!("hwc.set returned {}", set_res);
-            if list.retire_fence_fd >= 0 {
-                close(list.retire_fence_fd);
+
+        // Only good for this buffer, but must outlive the set() call.
+        let damage = mem::take(&mut self.damage);
+
+        // Without prepared contents, the buffer is all there is to show.
+        let (mut contents, prepared) = match self.prepared.take() {
+            Some(contents) => (contents, true),
+            None => {
+                let mut contents = DisplayContents::new(2);
+                {
+                    let layers = contents.layers_mut();
+                    layers[0].flags = HWC_SKIP_LAYER;
+                    layers[0].display_frame = display_frame;
+                    layers[1].composition_type = HWC_FRAMEBUFFER_TARGET;
+                }
+                (contents, false)
             }
+        };
+        {
+            let target = contents.layers_mut().last_mut().unwrap();
+            target.handle = gonkbuf.buffer.handle;
+            // A crop reaching outside the buffer only keeps the part inside.
+            let crop = self
+                .crop
+                .map(|crop| hwc_rect {
+                    left: crop.left.max(rect.left),
+                    top: crop.top.max(rect.top),
+                    right: crop.right.min(rect.right),
+                    bottom: crop.bottom.min(rect.bottom),
+                })
+                .filter(|crop| crop.left < crop.right && crop.top < crop.bottom)
+                .unwrap_or(rect);
+            target.source_crop = hwc_frect {
+                left: crop.left as f32,
+                top: crop.top as f32,
+                right: crop.right as f32,
+                bottom: crop.bottom as f32,
+            };
+            target.display_frame = display_frame;
+            target.transform = self.transform;
+            target.acquire_fence_fd = fence.map_or(-1, Fence::into_raw_fd);
+            target.surface_damage = hwc_region {
+                num_rects: damage.len() as i32,
+                rects: damage.as_ptr(),
+            };
         }
-        list.hw_layers[1].release_fence_fd
+        contents.update_visible_regions();
+
+        let fences = match self.hwc.set(self.display, contents, prepared) {
+            Ok(fences) => fences,
+            Err(err) => {
+                error!("{}", err);
+                return None;
+            }
+        };
+        if self.timings.len() == MAX_FRAME_TIMINGS {
+            self.timings.pop_front();
+        }
+        self.timings.push_back(PendingTiming {
+            timing: FrameTiming {
+                frame: self.frame,
+                queue_time,
+                retire_time: None,
+            },
+            retire_fence: fences.retire,
+        });
+
+        // The other layers' are for whoever prepared them.
+        let mut release = fences.release;
+        let target = release.pop().flatten();
+        if prepared {
+            self.layer_releases = release;
+        }
+        target
     }
 
-    pub fn alloc_buffers(&mut self) {
-        info!("alloc_buffers");
-        self.bufs[0] = Some(GonkNativeWindowBuffer::new(
-            self.alloc_dev,
-            self.width,
-            self.height,
-            self.format,
-            self.usage,
-        ));
-        self.bufs[1] = Some(GonkNativeWindowBuffer::new(
-            self.alloc_dev,
+    /// Changes the format and usage of the buffers. Those already
+    /// allocated with different ones are reallocated as they get dequeued.
+    pub fn set_buffer_params(&mut self, format: PixelFormat, usage: c_int) {
+        self.format = format;
+        self.usage = usage;
+    }
+
+    /// Sets the transform reported by NATIVE_WINDOW_TRANSFORM_HINT, that
+    /// of the display. Rendering with it and setting it as the buffers'
+    /// transform makes the buffers cover the display.
+    pub fn set_transform_hint(&mut self, transform: u32) {
+        self.transform_hint = transform;
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// The size of the buffers, see NATIVE_WINDOW_SET_BUFFERS_DIMENSIONS.
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    // Replaces the buffer of a slot if it is stale, or was allocated
+    // before the last change of dimensions, format or usage.
+    fn refresh_slot(&mut self, idx: usize) -> Result<(), GfxError> {
+        let slot = &self.slots[idx];
+        let old = unsafe { &(*slot.buffer).gralloc_buffer };
+        let params = (old.width(), old.height(), old.format(), old.usage());
+        if !slot.stale && params == (self.width, self.height, self.format, self.usage) {
+            return Ok(());
+        }
+        // The display may still be reading the old buffer.
+        if let Some(ref fence) = slot.fence {
+            if let Err(err) = fence.wait(None) {
+                error!("Waiting for the release fence: {}", err);
+            }
+        }
+        let buffer = GonkNativeWindowBuffer::new(
+            self.gralloc.clone(),
             self.width,
             self.height,
             self.format,
             self.usage,
-        ));
+        )?;
+        let old = mem::replace(&mut self.slots[idx], BufferSlot::new(buffer));
+        unsafe {
+            let common = &mut (*old.buffer).buffer.common;
+            (common.dec_ref)(common);
+        }
+        Ok(())
+    }
+
+    /// Allocates the buffers. Those already allocated are reallocated as
+    /// they get dequeued, so that the one on the display and those being
+    /// drawn to stay valid.
+    pub fn alloc_buffers(&mut self) -> Result<(), GfxError> {
+        info!("alloc_buffers");
+        if !self.slots.is_empty() {
+            for slot in &mut self.slots {
+                slot.stale = true;
+            }
+            return Ok(());
+        }
+        for _ in 0..self.buffer_count {
+            let buffer = GonkNativeWindowBuffer::new(
+                self.gralloc.clone(),
+                self.width,
+                self.height,
+                self.format,
+                self.usage,
+            )?;
+            self.slots.push(BufferSlot::new(buffer));
+        }
+        Ok(())
+    }
+
+    fn release_buffers(&mut self) {
+        for slot in self.slots.drain(..) {
+            // Buffers still dequeued are kept alive by the references
+            // EGL holds on them.
+            unsafe {
+                let common = &mut (*slot.buffer).buffer.common;
+                (common.dec_ref)(common);
+            }
+        }
+        self.front = None;
+        self.last_dequeued = None;
+    }
+}
+
+impl Drop for GonkNativeWindow {
+    fn drop(&mut self) {
+        self.hwc.forget_display(self.display);
+        self.release_buffers();
     }
 }
 
@@ -500,23 +906,31 @@ extern "C" fn gnwb_dec_ref(base: *mut ANativeBase) {
     let buf: &mut GonkNativeWindowBuffer = unsafe { transmute(base) };
     buf.count -= 1;
     if buf.count == 0 {
-        unsafe { transmute::<_, Box<GonkNativeWindowBuffer>>(base) };
+        // Frees the gralloc buffer along with it.
+        drop(unsafe { Box::from_raw(base as *mut GonkNativeWindowBuffer) });
     }
 }
 
 impl GonkNativeWindowBuffer {
     pub fn new(
-        dev: *mut alloc_device,
+        gralloc: Gralloc,
         width: i32,
         height: i32,
-        format: c_int,
+        format: PixelFormat,
         usage: c_int,
-    ) -> *mut GonkNativeWindowBuffer {
+    ) -> Result<*mut GonkNativeWindowBuffer, GfxError> {
         info!(
-            "GonkNativeWindowBuffer::new {}x{} {} {}",
+            "GonkNativeWindowBuffer::new {}x{} {:?} {}",
             width, height, format, usage
         );
-        let mut buf = Box::new(GonkNativeWindowBuffer {
+        let gralloc_buffer = GrallocBuffer::new(gralloc, width, height, format, usage)?;
+        Ok(GonkNativeWindowBuffer::from_gralloc_buffer(gralloc_buffer))
+    }
+
+    /// Wraps a gralloc buffer, which is freed once the last reference to
+    /// the returned window buffer is dropped.
+    pub fn from_gralloc_buffer(gralloc_buffer: GrallocBuffer) -> *mut GonkNativeWindowBuffer {
+        let buf = Box::new(GonkNativeWindowBuffer {
             buffer: ANativeWindowBuffer {
                 common: ANativeBase {
                     magic: ANativeBase::magic('_', 'b', 'f', 'r'),
@@ -525,33 +939,22 @@ impl GonkNativeWindowBuffer {
                     inc_ref: gnwb_inc_ref,
                     dec_ref: gnwb_dec_ref,
                 },
-                width: width,
-                height: height,
-                stride: 0,
-                format: format,
-                usage: usage,
+                width: gralloc_buffer.width(),
+                height: gralloc_buffer.height(),
+                stride: gralloc_buffer.stride(),
+                format: gralloc_buffer.format().to_hal(),
+                usage: gralloc_buffer.usage(),
                 reserved: unsafe { zeroed() },
-                handle: ptr::null(),
+                handle: gralloc_buffer.handle(),
                 reserved_proc: unsafe { zeroed() },
             },
             count: 1,
+            gralloc_buffer,
         });
+        Box::into_raw(buf)
+    }
 
-        let ret = unsafe {
-            info!("About to call alloc {:?}", (*dev).alloc);
-
-            ((*dev).alloc)(
-                dev,
-                width,
-                height,
-                format,
-                usage,
-                &mut buf.buffer.handle,
-                &mut buf.buffer.stride,
-            )
-        };
-        assert!(ret == 0, "Failed to allocate gralloc buffer!");
-
-        unsafe { transmute(buf) }
+    pub fn gralloc_buffer(&self) -> &GrallocBuffer {
+        &self.gralloc_buffer
     }
 }
@@ -2,45 +2,51 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use error::GfxError;
 use gonk_gfx::native_handle;
 use hardware::*;
+use hwc::hwc_rect;
 use libc::{c_char, c_int, c_void, size_t};
 use std::ffi::CString;
-use std::mem::transmute;
+use std::marker::PhantomData;
+use std::mem::zeroed;
 use std::ptr;
+use std::rc::Rc;
+use std::slice;
 
 // From system/core/include/system/graphics.h
 
+pub const HAL_PIXEL_FORMAT_RGBA_8888: c_int = 1;
+pub const HAL_PIXEL_FORMAT_RGBX_8888: c_int = 2;
+pub const HAL_PIXEL_FORMAT_RGB_888: c_int = 3;
+pub const HAL_PIXEL_FORMAT_RGB_565: c_int = 4;
+pub const HAL_PIXEL_FORMAT_BGRA_8888: c_int = 5;
+pub const HAL_PIXEL_FORMAT_YCRCB_420_SP: c_int = 0x11; // NV21
+pub const HAL_PIXEL_FORMAT_RGBA_FP16: c_int = 0x16;
+pub const HAL_PIXEL_FORMAT_IMPLEMENTATION_DEFINED: c_int = 0x22;
+pub const HAL_PIXEL_FORMAT_YCBCR_420_888: c_int = 0x23;
+pub const HAL_PIXEL_FORMAT_RGBA_1010102: c_int = 0x2b;
+pub const HAL_PIXEL_FORMAT_YV12: c_int = 0x3231_5659;
+
 #[repr(C)]
 pub struct android_ycbcr {
-    y: *mut c_void,
-    cb: *mut c_void,
-    cr: *mut c_void,
-    ystride: size_t,
-    cstride: size_t,
-    chroma_step: size_t,
-    reserved: [u32; 8],
+    pub y: *mut c_void,
+    pub cb: *mut c_void,
+    pub cr: *mut c_void,
+    pub ystride: size_t,
+    pub cstride: size_t,
+    pub chroma_step: size_t,
+    pub reserved: [u32; 8],
 }
 
 // From hardware/libhardware/include/hardware/gralloc.h
 
 #[repr(C)]
 pub struct gralloc_module {
-    common: hw_module,
-    register_buffer: extern "C" fn(*const gralloc_module, *const native_handle) -> c_int,
-    unregister_buffer: extern "C" fn(*const gralloc_module, *const native_handle) -> c_int,
-    lock: extern "C" fn(
-        *const gralloc_module,
-        *const native_handle,
-        c_int,
-        c_int,
-        c_int,
-        c_int,
-        *mut *mut c_void,
-    ) -> c_int,
-    unlock: extern "C" fn(*const gralloc_module, *const native_handle) -> c_int,
-    perform: extern "C" fn(*const gralloc_module, c_int, ...) -> c_int,
-    lock_ycbcr: extern "C" fn(
+    pub common: hw_module,
+    pub register_buffer: extern "C" fn(*const gralloc_module, *const native_handle) -> c_int,
+    pub unregister_buffer: extern "C" fn(*const gralloc_module, *const native_handle) -> c_int,
+    pub lock: extern "C" fn(
         *const gralloc_module,
         *const native_handle,
         c_int,
@@ -48,14 +54,28 @@ pub struct gralloc_module {
         c_int,
         c_int,
         c_int,
-        *mut android_ycbcr,
+        *mut *mut c_void,
     ) -> c_int,
-    reserved: [*mut c_void; 6],
+    pub unlock: extern "C" fn(*const gralloc_module, *const native_handle) -> c_int,
+    pub perform: Option<extern "C" fn(*const gralloc_module, c_int, ...) -> c_int>,
+    pub lock_ycbcr: Option<
+        extern "C" fn(
+            *const gralloc_module,
+            *const native_handle,
+            c_int,
+            c_int,
+            c_int,
+            c_int,
+            c_int,
+            *mut android_ycbcr,
+        ) -> c_int,
+    >,
+    pub reserved: [*mut c_void; 6],
 }
 
 #[repr(C)]
 pub struct alloc_device {
-    common: hw_device,
+    pub common: hw_device,
     pub alloc: extern "C" fn(
         *mut alloc_device,
         c_int,
@@ -67,21 +87,522 @@ pub struct alloc_device {
     ) -> c_int,
     pub free: extern "C" fn(*mut alloc_device, *const native_handle) -> c_int,
     pub dump: Option<extern "C" fn(*mut alloc_device, *mut c_char, c_int)>,
-    reserved: [*mut c_void; 7],
+    pub reserved: [*mut c_void; 7],
+}
+
+unsafe impl HwDevice for alloc_device {}
+
+/// The gralloc module, and the allocator device opened from it. Clones
+/// share the device, which is closed with the last of them and of the
+/// buffers allocated from it.
+#[derive(Clone)]
+pub struct Gralloc {
+    pub module: *const gralloc_module,
+    alloc_dev: Rc<HalDevice<alloc_device>>,
+}
+
+impl Gralloc {
+    pub fn alloc_dev(&self) -> *mut alloc_device {
+        self.alloc_dev.as_ptr()
+    }
 }
 
-pub fn get_gralloc_module() -> *mut alloc_device {
+pub fn get_gralloc_module() -> Result<Gralloc, GfxError> {
     let mut gralloc_mod = ptr::null();
-    let alloc_dev: *mut alloc_device;
     unsafe {
-        let mut device = ptr::null();
         let cstr = CString::new("gralloc").unwrap();
-        let ret1 = hw_get_module(cstr.as_ptr(), &mut gralloc_mod);
-        assert_eq!(ret1, 0, "Failed to get gralloc module!");
-        let cstr2 = CString::new("gpu0").unwrap();
-        let ret2 = ((*(*gralloc_mod).methods).open)(gralloc_mod, cstr2.as_ptr(), &mut device);
-        assert_eq!(ret2, 0, "Failed to open gpu0 on gralloc module!");
-        alloc_dev = transmute(device);
-    }
-    alloc_dev
+        let ret = hw_get_module(cstr.as_ptr(), &mut gralloc_mod);
+        if ret != 0 {
+            error!("Failed to get gralloc module!");
+            return Err(GfxError::ModuleNotFound {
+                module: "gralloc",
+                code: ret,
+            });
+        }
+    }
+    let alloc_dev = unsafe { HalDevice::open(gralloc_mod, "gralloc", "gpu0")? };
+    Ok(Gralloc {
+        module: gralloc_mod as *const gralloc_module,
+        alloc_dev: Rc::new(alloc_dev),
+    })
+}
+
+/// The buffer formats we know how to allocate and draw to.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    RGBA_8888,
+    RGBX_8888,
+    RGB_888,
+    RGB_565,
+    BGRA_8888,
+    /// Planar YUV 4:2:0, a Y plane followed by the Cr and Cb planes.
+    YV12,
+    /// Semi-planar YUV 4:2:0, a Y plane followed by interleaved Cr and Cb.
+    NV21,
+    /// Left to gralloc to pick, from the usage flags.
+    IMPLEMENTATION_DEFINED,
+    /// Half float components.
+    RGBA_FP16,
+    RGBA_1010102,
+}
+
+impl PixelFormat {
+    pub fn from_hal(format: c_int) -> Option<PixelFormat> {
+        Some(match format {
+            HAL_PIXEL_FORMAT_RGBA_8888 => PixelFormat::RGBA_8888,
+            HAL_PIXEL_FORMAT_RGBX_8888 => PixelFormat::RGBX_8888,
+            HAL_PIXEL_FORMAT_RGB_888 => PixelFormat::RGB_888,
+            HAL_PIXEL_FORMAT_RGB_565 => PixelFormat::RGB_565,
+            HAL_PIXEL_FORMAT_BGRA_8888 => PixelFormat::BGRA_8888,
+            HAL_PIXEL_FORMAT_YV12 => PixelFormat::YV12,
+            HAL_PIXEL_FORMAT_YCRCB_420_SP => PixelFormat::NV21,
+            HAL_PIXEL_FORMAT_IMPLEMENTATION_DEFINED => PixelFormat::IMPLEMENTATION_DEFINED,
+            HAL_PIXEL_FORMAT_RGBA_FP16 => PixelFormat::RGBA_FP16,
+            HAL_PIXEL_FORMAT_RGBA_1010102 => PixelFormat::RGBA_1010102,
+            _ => return None,
+        })
+    }
+
+    /// The HAL_PIXEL_FORMAT_* value.
+    pub fn to_hal(self) -> c_int {
+        match self {
+            PixelFormat::RGBA_8888 => HAL_PIXEL_FORMAT_RGBA_8888,
+            PixelFormat::RGBX_8888 => HAL_PIXEL_FORMAT_RGBX_8888,
+            PixelFormat::RGB_888 => HAL_PIXEL_FORMAT_RGB_888,
+            PixelFormat::RGB_565 => HAL_PIXEL_FORMAT_RGB_565,
+            PixelFormat::BGRA_8888 => HAL_PIXEL_FORMAT_BGRA_8888,
+            PixelFormat::YV12 => HAL_PIXEL_FORMAT_YV12,
+            PixelFormat::NV21 => HAL_PIXEL_FORMAT_YCRCB_420_SP,
+            PixelFormat::IMPLEMENTATION_DEFINED => HAL_PIXEL_FORMAT_IMPLEMENTATION_DEFINED,
+            PixelFormat::RGBA_FP16 => HAL_PIXEL_FORMAT_RGBA_FP16,
+            PixelFormat::RGBA_1010102 => HAL_PIXEL_FORMAT_RGBA_1010102,
+        }
+    }
+
+    /// Bytes per pixel of single plane formats, None for the YUV ones and
+    /// for IMPLEMENTATION_DEFINED, whose layout only gralloc knows.
+    pub fn bytes_per_pixel(self) -> Option<usize> {
+        match self {
+            PixelFormat::RGBA_8888
+            | PixelFormat::RGBX_8888
+            | PixelFormat::BGRA_8888
+            | PixelFormat::RGBA_1010102 => Some(4),
+            PixelFormat::RGB_888 => Some(3),
+            PixelFormat::RGB_565 => Some(2),
+            PixelFormat::RGBA_FP16 => Some(8),
+            PixelFormat::YV12 | PixelFormat::NV21 | PixelFormat::IMPLEMENTATION_DEFINED => None,
+        }
+    }
+
+    pub fn is_yuv(self) -> bool {
+        matches!(self, PixelFormat::YV12 | PixelFormat::NV21)
+    }
+}
+
+/// A gralloc buffer, freed when dropped, or unregistered if imported.
+pub struct GrallocBuffer {
+    gralloc: Gralloc,
+    handle: *const native_handle,
+    // Whether the handle comes from elsewhere, see import().
+    imported: bool,
+    width: i32,
+    height: i32,
+    stride: i32,
+    format: PixelFormat,
+    usage: c_int,
+}
+
+impl GrallocBuffer {
+    pub fn new(
+        gralloc: Gralloc,
+        width: i32,
+        height: i32,
+        format: PixelFormat,
+        usage: c_int,
+    ) -> Result<GrallocBuffer, GfxError> {
+        let mut handle = ptr::null();
+        let mut stride = 0;
+        let dev = gralloc.alloc_dev();
+        let ret = unsafe {
+            ((*dev).alloc)(
+                dev,
+                width,
+                height,
+                format.to_hal(),
+                usage,
+                &mut handle,
+                &mut stride,
+            )
+        };
+        if ret != 0 {
+            error!("Failed to allocate gralloc buffer!");
+            return Err(GfxError::GrallocAlloc {
+                width,
+                height,
+                format: format.to_hal(),
+                usage,
+                code: ret,
+            });
+        }
+        Ok(GrallocBuffer {
+            gralloc,
+            handle,
+            imported: false,
+            width,
+            height,
+            stride,
+            format,
+            usage,
+        })
+    }
+
+    /// Wraps a buffer allocated by another process, e.g. a camera or
+    /// video decoder frame received over binder, registering the handle
+    /// with the gralloc module. It is unregistered on drop, but neither
+    /// closed nor freed, which is up to the caller once done with it.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must stay valid for the lifetime of the buffer, and match
+    /// the given dimensions and format.
+    pub unsafe fn import(
+        gralloc: Gralloc,
+        handle: *const native_handle,
+        width: i32,
+        height: i32,
+        stride: i32,
+        format: PixelFormat,
+        usage: c_int,
+    ) -> Result<GrallocBuffer, GfxError> {
+        let module = gralloc.module;
+        let ret = ((*module).register_buffer)(module, handle);
+        if ret != 0 {
+            error!("Failed to register gralloc buffer {:?}", handle);
+            return Err(GfxError::Gralloc {
+                call: "registerBuffer",
+                code: ret,
+            });
+        }
+        Ok(GrallocBuffer {
+            gralloc,
+            handle,
+            imported: true,
+            width,
+            height,
+            stride,
+            format,
+            usage,
+        })
+    }
+
+    pub fn handle(&self) -> *const native_handle {
+        self.handle
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    /// The distance between rows, in pixels.
+    pub fn stride(&self) -> i32 {
+        self.stride
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn usage(&self) -> c_int {
+        self.usage
+    }
+
+    fn check_rect(&self, rect: &hwc_rect) -> Result<(), GfxError> {
+        if rect.left < 0
+            || rect.top < 0
+            || rect.left > rect.right
+            || rect.top > rect.bottom
+            || rect.right > self.width
+            || rect.bottom > self.height
+        {
+            return Err(GfxError::RectOutOfBounds {
+                rect: *rect,
+                width: self.width,
+                height: self.height,
+            });
+        }
+        Ok(())
+    }
+
+    /// Maps the pixels within `rect` for CPU access, with the
+    /// GRALLOC_USAGE_SW_* flags in `usage`. Only single plane formats can
+    /// be locked this way, see lock_ycbcr() for YUV ones.
+    pub fn lock(&mut self, usage: c_int, rect: hwc_rect) -> Result<MappedBuffer<'_>, GfxError> {
+        self.check_rect(&rect)?;
+        let bpp = self
+            .format
+            .bytes_per_pixel()
+            .ok_or_else(|| GfxError::UnsupportedFormat(self.format.to_hal()))?;
+        let mut vaddr = ptr::null_mut();
+        let module = self.gralloc.module;
+        let ret = unsafe {
+            ((*module).lock)(
+                module,
+                self.handle,
+                usage,
+                rect.left,
+                rect.top,
+                rect.right - rect.left,
+                rect.bottom - rect.top,
+                &mut vaddr,
+            )
+        };
+        if ret != 0 {
+            return Err(GfxError::Gralloc {
+                call: "lock",
+                code: ret,
+            });
+        }
+        Ok(MappedBuffer {
+            module,
+            handle: self.handle,
+            data: vaddr as *mut u8,
+            bpp,
+            stride: self.stride as usize * bpp,
+            rect,
+            _buffer: PhantomData,
+        })
+    }
+
+    /// Maps the pixels within `rect` of a YUV 4:2:0 buffer for CPU access.
+    pub fn lock_ycbcr(
+        &mut self,
+        usage: c_int,
+        rect: hwc_rect,
+    ) -> Result<MappedYCbCr<'_>, GfxError> {
+        self.check_rect(&rect)?;
+        let module = self.gralloc.module;
+        let lock_ycbcr = unsafe { (*module).lock_ycbcr }.ok_or(GfxError::Gralloc {
+            call: "lock_ycbcr",
+            code: -::libc::ENOSYS,
+        })?;
+        let mut ycbcr: android_ycbcr = unsafe { zeroed() };
+        let ret = lock_ycbcr(
+            module,
+            self.handle,
+            usage,
+            rect.left,
+            rect.top,
+            rect.right - rect.left,
+            rect.bottom - rect.top,
+            &mut ycbcr,
+        );
+        if ret != 0 {
+            return Err(GfxError::Gralloc {
+                call: "lock_ycbcr",
+                code: ret,
+            });
+        }
+        // Not a 4:2:0 layout, e.g. Y8 doesn't have chroma planes.
+        if ycbcr.y.is_null() || ycbcr.cb.is_null() || ycbcr.cr.is_null() {
+            unlock(module, self.handle);
+            return Err(GfxError::UnsupportedFormat(self.format.to_hal()));
+        }
+        Ok(MappedYCbCr {
+            module,
+            handle: self.handle,
+            ycbcr,
+            rect,
+            _buffer: PhantomData,
+        })
+    }
+}
+
+impl Drop for GrallocBuffer {
+    fn drop(&mut self) {
+        if self.imported {
+            let module = self.gralloc.module;
+            let ret = unsafe { ((*module).unregister_buffer)(module, self.handle) };
+            if ret != 0 {
+                error!("Failed to unregister gralloc buffer: {}", ret);
+            }
+            return;
+        }
+        let dev = self.gralloc.alloc_dev();
+        unsafe {
+            ((*dev).free)(dev, self.handle);
+        }
+    }
+}
+
+fn unlock(module: *const gralloc_module, handle: *const native_handle) {
+    let ret = unsafe { ((*module).unlock)(module, handle) };
+    if ret != 0 {
+        error!("Failed to unlock gralloc buffer: {}", ret);
+    }
+}
+
+/// The pixels of a locked buffer, unlocked when dropped. Rows are
+/// relative to the locked rect.
+pub struct MappedBuffer<'a> {
+    module: *const gralloc_module,
+    handle: *const native_handle,
+    // The start of the buffer, not of the rect.
+    data: *mut u8,
+    bpp: usize,
+    stride: usize,
+    rect: hwc_rect,
+    _buffer: PhantomData<&'a mut GrallocBuffer>,
+}
+
+impl<'a> MappedBuffer<'a> {
+    pub fn rect(&self) -> hwc_rect {
+        self.rect
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.bpp
+    }
+
+    /// The distance between rows, in bytes.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn height(&self) -> usize {
+        (self.rect.bottom - self.rect.top) as usize
+    }
+
+    fn row_offset(&self, y: usize) -> usize {
+        (self.rect.top as usize + y) * self.stride + self.rect.left as usize * self.bpp
+    }
+
+    fn row_ptr(&self, y: usize) -> *mut u8 {
+        assert!(y < self.height());
+        unsafe { self.data.add(self.row_offset(y)) }
+    }
+
+    fn row_len(&self) -> usize {
+        (self.rect.right - self.rect.left) as usize * self.bpp
+    }
+
+    pub fn row(&self, y: usize) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.row_ptr(y), self.row_len()) }
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.row_ptr(y), self.row_len()) }
+    }
+
+    /// The rows of the locked rect, top to bottom, without the padding.
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        (0..self.height()).map(move |y| self.row(y))
+    }
+
+    pub fn rows_mut(&mut self) -> impl Iterator<Item = &mut [u8]> {
+        // Not dereferenced when the rect is empty.
+        let ptr = self.data.wrapping_add(self.row_offset(0));
+        let (len, stride) = (self.row_len(), self.stride);
+        (0..self.height())
+            .map(move |y| unsafe { slice::from_raw_parts_mut(ptr.add(y * stride), len) })
+    }
+}
+
+impl<'a> Drop for MappedBuffer<'a> {
+    fn drop(&mut self) {
+        unlock(self.module, self.handle);
+    }
+}
+
+/// One plane of a locked YUV buffer.
+#[derive(Clone, Copy)]
+pub struct Plane<'a> {
+    /// From the first sample to the last.
+    pub data: &'a [u8],
+    /// The distance between rows, in bytes.
+    pub stride: usize,
+    /// The distance between samples of a row, in bytes. Cb and Cr samples
+    /// are interleaved when this is 2.
+    pub step: usize,
+}
+
+impl<'a> Plane<'a> {
+    pub fn sample(&self, x: usize, y: usize) -> u8 {
+        self.data[y * self.stride + x * self.step]
+    }
+}
+
+/// The planes of a locked YUV 4:2:0 buffer, unlocked when dropped. Like
+/// the rows of a MappedBuffer, the planes start at the top left of the
+/// locked rect.
+pub struct MappedYCbCr<'a> {
+    module: *const gralloc_module,
+    handle: *const native_handle,
+    // Planes from the start of the buffer, not of the rect.
+    ycbcr: android_ycbcr,
+    rect: hwc_rect,
+    _buffer: PhantomData<&'a mut GrallocBuffer>,
+}
+
+impl<'a> MappedYCbCr<'a> {
+    pub fn rect(&self) -> hwc_rect {
+        self.rect
+    }
+
+    fn plane(&self, data: *mut c_void, stride: usize, step: usize, chroma: bool) -> Plane<'_> {
+        let rect = &self.rect;
+        let (mut left, mut top) = (rect.left as usize, rect.top as usize);
+        let (mut right, mut bottom) = (rect.right as usize, rect.bottom as usize);
+        // Each chroma sample covers 2x2 pixels, keep those the rect touches.
+        if chroma {
+            left /= 2;
+            top /= 2;
+            right = right.div_ceil(2);
+            bottom = bottom.div_ceil(2);
+        }
+        let (width, height) = (right - left, bottom - top);
+        let len = if width == 0 || height == 0 {
+            0
+        } else {
+            (height - 1) * stride + (width - 1) * step + 1
+        };
+        unsafe {
+            let data = (data as *const u8).add(top * stride + left * step);
+            Plane {
+                data: slice::from_raw_parts(data, len),
+                stride,
+                step,
+            }
+        }
+    }
+
+    pub fn y(&self) -> Plane<'_> {
+        self.plane(self.ycbcr.y, self.ycbcr.ystride, 1, false)
+    }
+
+    pub fn cb(&self) -> Plane<'_> {
+        let ycbcr = &self.ycbcr;
+        self.plane(ycbcr.cb, ycbcr.cstride, ycbcr.chroma_step, true)
+    }
+
+    pub fn cr(&self) -> Plane<'_> {
+        let ycbcr = &self.ycbcr;
+        self.plane(ycbcr.cr, ycbcr.cstride, ycbcr.chroma_step, true)
+    }
+
+    /// The description returned by gralloc, e.g. to write to the planes.
+    /// Its planes start at the top left of the buffer.
+    pub fn as_raw(&self) -> &android_ycbcr {
+        &self.ycbcr
+    }
+}
+
+impl<'a> Drop for MappedYCbCr<'a> {
+    fn drop(&mut self) {
+        unlock(self.module, self.handle);
+    }
 }
@@ -4,7 +4,10 @@
 
 // hardware/libhardware/include/hardware/hardware.h
 
+use error::GfxError;
 use libc::{c_char, c_int};
+use std::ffi::CString;
+use std::ptr;
 
 #[repr(C)]
 pub struct hw_module_methods {
@@ -13,27 +16,97 @@ pub struct hw_module_methods {
 
 #[repr(C)]
 pub struct hw_module {
-    tag: u32,
-    module_api_version: u16,
-    hal_api_version: u16,
-    id: *const c_char,
-    name: *const c_char,
-    author: *const c_char,
+    pub tag: u32,
+    pub module_api_version: u16,
+    pub hal_api_version: u16,
+    pub id: *const c_char,
+    pub name: *const c_char,
+    pub author: *const c_char,
     pub methods: *mut hw_module_methods,
-    dso: *mut u32,
-    reserved: [u32; (32 - 7)],
+    pub dso: *mut u32,
+    pub reserved: [u32; (32 - 7)],
 }
 
 #[repr(C)]
 pub struct hw_device {
-    tag: u32,
+    pub tag: u32,
     pub version: u32,
-    module: *mut hw_module,
-    reserved: [u32; 12],
-    close: extern "C" fn(*mut hw_device) -> c_int,
+    pub module: *mut hw_module,
+    pub reserved: [u32; 12],
+    pub close: extern "C" fn(*mut hw_device) -> c_int,
 }
 
+/// Device structs of HALs, which all start with their `hw_device`.
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]` with a `hw_device` as first field.
+pub unsafe trait HwDevice {}
+
+unsafe impl HwDevice for hw_device {}
+
+/// A device opened from a HAL module, closed when dropped.
+pub struct HalDevice<T: HwDevice> {
+    device: *mut T,
+}
+
+impl<T: HwDevice> HalDevice<T> {
+    /// Opens the `name` device of `module`, the HAL module named
+    /// `module_name`.
+    ///
+    /// # Safety
+    ///
+    /// `module` must be a loaded HAL module whose `name` devices are `T`s.
+    pub unsafe fn open(
+        module: *const hw_module,
+        module_name: &'static str,
+        name: &'static str,
+    ) -> Result<HalDevice<T>, GfxError> {
+        let mut device = ptr::null();
+        let cstr = CString::new(name).unwrap();
+        let ret = ((*(*module).methods).open)(module, cstr.as_ptr(), &mut device);
+        if ret != 0 || device.is_null() {
+            error!("Failed to open {} device {}!", module_name, name);
+            return Err(GfxError::DeviceOpen {
+                module: module_name,
+                device: name,
+                code: ret,
+            });
+        }
+        Ok(HalDevice {
+            device: device as *mut T,
+        })
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.device
+    }
+
+    /// The device version, whose meaning depends on the HAL.
+    pub fn version(&self) -> u32 {
+        self.common().version
+    }
+
+    fn common(&self) -> &hw_device {
+        unsafe { &*(self.device as *const hw_device) }
+    }
+}
+
+impl<T: HwDevice> Drop for HalDevice<T> {
+    fn drop(&mut self) {
+        let common = self.device as *mut hw_device;
+        let ret = unsafe { ((*common).close)(common) };
+        if ret != 0 {
+            error!("Failed to close HAL device: {}", ret);
+        }
+    }
+}
+
+#[cfg(not(feature = "mock-hal"))]
 #[link(name = "hardware")]
 extern "C" {
     pub fn hw_get_module(id: *const c_char, module: *mut *const hw_module) -> c_int;
 }
+
+#[cfg(feature = "mock-hal")]
+pub use mock_hal::hw_get_module;
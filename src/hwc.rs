@@ -4,12 +4,20 @@
 
 //! A wrapper around the hwc device
 
+use error::GfxError;
+use fence::Fence;
 use gonk_gfx::*;
 use hardware::*;
-use libc::{c_char, c_int, c_void, size_t};
+use libc::{self, c_char, c_int, c_void, size_t};
+use std::alloc::{self, Layout};
+use std::cell::{Cell, RefCell};
 use std::ffi::CString;
-use std::mem::transmute;
+use std::mem::{self, align_of, size_of};
 use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
 
 // From hardware/libhardware/include/hardware/hwcomposer.h
 
@@ -27,6 +35,8 @@ pub const HWC_POWER_MODE_DOZE_SUSPEND: c_int = 3;
 
 pub const HWC_GEOMETRY_CHANGED: u32 = 1;
 
+pub const HWC_EVENT_VSYNC: c_int = 0;
+
 pub const HWC_DISPLAY_PRIMARY: c_int = 0;
 pub const HWC_DISPLAY_EXTERNAL: c_int = 1; // HDMI, DP, etc.
 pub const HWC_DISPLAY_VIRTUAL: c_int = 2;
@@ -38,6 +48,45 @@ pub const HWC_BLENDING_NONE: c_int = 0x0100;
 pub const HWC_BLENDING_PREMULT: c_int = 0x0105;
 pub const HWC_BLENDING_COVERAGE: c_int = 0x0405;
 
+pub const HWC_TRANSFORM_FLIP_H: u32 = 0x01;
+pub const HWC_TRANSFORM_FLIP_V: u32 = 0x02;
+pub const HWC_TRANSFORM_ROT_90: u32 = 0x04;
+pub const HWC_TRANSFORM_ROT_180: u32 = 0x03;
+pub const HWC_TRANSFORM_ROT_270: u32 = 0x07;
+
+/// Maps a point of a buffer shown with `transform` to where it lands on
+/// screen, both normalized to [0, 1]. The flips apply before the rotation.
+pub fn transform_point(transform: u32, x: f32, y: f32) -> (f32, f32) {
+    let (mut x, mut y) = (x, y);
+    if transform & HWC_TRANSFORM_FLIP_H != 0 {
+        x = 1.0 - x;
+    }
+    if transform & HWC_TRANSFORM_FLIP_V != 0 {
+        y = 1.0 - y;
+    }
+    if transform & HWC_TRANSFORM_ROT_90 != 0 {
+        (1.0 - y, x)
+    } else {
+        (x, y)
+    }
+}
+
+/// The inverse of transform_point(), from the screen back to the buffer.
+pub fn inverse_transform_point(transform: u32, x: f32, y: f32) -> (f32, f32) {
+    let (mut x, mut y) = if transform & HWC_TRANSFORM_ROT_90 != 0 {
+        (y, 1.0 - x)
+    } else {
+        (x, y)
+    };
+    if transform & HWC_TRANSFORM_FLIP_H != 0 {
+        x = 1.0 - x;
+    }
+    if transform & HWC_TRANSFORM_FLIP_V != 0 {
+        y = 1.0 - y;
+    }
+    (x, y)
+}
+
 #[repr(C)]
 pub struct hwc_composer_device {
     pub common: hw_device,
@@ -54,9 +103,16 @@ pub struct hwc_composer_device {
         extern "C" fn(*mut hwc_composer_device, c_int, *mut u32, *mut size_t) -> c_int,
     pub get_display_attributes:
         extern "C" fn(*mut hwc_composer_device, c_int, u32, *const u32, *mut i32) -> c_int,
-    reserved: [*mut c_void; 4],
+    // Since HWC 1.4, null before.
+    pub get_active_config: Option<extern "C" fn(*mut hwc_composer_device, c_int) -> c_int>,
+    pub set_active_config: Option<extern "C" fn(*mut hwc_composer_device, c_int, c_int) -> c_int>,
+    pub set_cursor_position_async:
+        Option<extern "C" fn(*mut hwc_composer_device, c_int, c_int, c_int) -> c_int>,
+    pub reserved: [*mut c_void; 1],
 }
 
+unsafe impl HwDevice for hwc_composer_device {}
+
 #[repr(C)]
 pub struct hwc_color {
     r: u8,
@@ -66,7 +122,7 @@ pub struct hwc_color {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct hwc_rect {
     pub left: c_int,
     pub top: c_int,
@@ -75,7 +131,7 @@ pub struct hwc_rect {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct hwc_frect {
     pub left: f32,
     pub top: f32,
@@ -116,6 +172,52 @@ pub struct hwc_layer {
     pub reserved: [u8; (96 - 84)],
 }
 
+impl Default for hwc_layer {
+    /// An opaque layer without a buffer, left to GLES composition.
+    fn default() -> Self {
+        hwc_layer {
+            composition_type: HWC_FRAMEBUFFER,
+            hints: 0,
+            flags: 0,
+            handle: ptr::null(),
+            transform: 0,
+            blending: HWC_BLENDING_NONE,
+            source_crop: hwc_frect {
+                left: 0.0,
+                top: 0.0,
+                right: 0.0,
+                bottom: 0.0,
+            },
+            display_frame: hwc_rect {
+                left: 0,
+                top: 0,
+                right: 0,
+                bottom: 0,
+            },
+            visible_region_screen: hwc_region {
+                num_rects: 0,
+                rects: ptr::null(),
+            },
+            acquire_fence_fd: -1,
+            release_fence_fd: -1,
+            plane_alpha: 0xff,
+            pad: [0; 3],
+            surface_damage: hwc_region {
+                num_rects: 0,
+                rects: ptr::null(),
+            },
+            reserved: [0; 12],
+        }
+    }
+}
+
+impl hwc_layer {
+    /// Takes ownership of the release fence set() returned, if any.
+    pub fn take_release_fence(&mut self) -> Option<Fence> {
+        unsafe { Fence::from_raw(mem::replace(&mut self.release_fence_fd, -1)) }
+    }
+}
+
 #[repr(C)]
 pub struct hwc_display_contents {
     pub retire_fence_fd: c_int,
@@ -123,14 +225,198 @@ pub struct hwc_display_contents {
     pub outbuf_acquire_fence_fd: c_int,
     pub flags: u32,
     pub num_hw_layers: size_t,
-    pub hw_layers: [hwc_layer; 2],
+    // Variable length, see DisplayContents.
+    pub hw_layers: [hwc_layer; 0],
+}
+
+/// A heap allocated hwc_display_contents, followed by its layers.
+pub struct DisplayContents {
+    contents: *mut hwc_display_contents,
+    layout: Layout,
+    // Backs the visible region of each layer, see update_visible_regions().
+    visible: Vec<hwc_rect>,
+}
+
+impl DisplayContents {
+    /// Allocates contents with `num_layers` default layers.
+    pub fn new(num_layers: usize) -> DisplayContents {
+        let size = size_of::<hwc_display_contents>() + num_layers * size_of::<hwc_layer>();
+        let layout = Layout::from_size_align(size, align_of::<hwc_display_contents>()).unwrap();
+        unsafe {
+            let contents = alloc::alloc(layout) as *mut hwc_display_contents;
+            if contents.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+            ptr::write(
+                contents,
+                hwc_display_contents {
+                    retire_fence_fd: -1,
+                    outbuf: ptr::null(),
+                    outbuf_acquire_fence_fd: -1,
+                    flags: HWC_GEOMETRY_CHANGED,
+                    num_hw_layers: num_layers,
+                    hw_layers: [],
+                },
+            );
+            let layers = (*contents).hw_layers.as_mut_ptr();
+            for i in 0..num_layers {
+                ptr::write(layers.add(i), hwc_layer::default());
+            }
+            DisplayContents {
+                contents,
+                layout,
+                visible: vec![],
+            }
+        }
+    }
+
+    pub fn layers(&self) -> &[hwc_layer] {
+        unsafe {
+            slice::from_raw_parts(
+                (*self.contents).hw_layers.as_ptr(),
+                (*self.contents).num_hw_layers,
+            )
+        }
+    }
+
+    pub fn layers_mut(&mut self) -> &mut [hwc_layer] {
+        unsafe {
+            slice::from_raw_parts_mut(
+                (*self.contents).hw_layers.as_mut_ptr(),
+                (*self.contents).num_hw_layers,
+            )
+        }
+    }
+
+    /// Sets the visible region of every layer to its display frame.
+    pub fn update_visible_regions(&mut self) {
+        self.visible = self
+            .layers()
+            .iter()
+            .map(|layer| layer.display_frame)
+            .collect();
+        let rects = self.visible.as_ptr();
+        for (i, layer) in self.layers_mut().iter_mut().enumerate() {
+            layer.visible_region_screen = hwc_region {
+                num_rects: 1,
+                rects: unsafe { rects.add(i) },
+            };
+        }
+    }
+
+    pub fn retire_fence_fd(&self) -> c_int {
+        unsafe { (*self.contents).retire_fence_fd }
+    }
+
+    /// Takes ownership of the retire fence set() returned, if any.
+    pub fn take_retire_fence(&mut self) -> Option<Fence> {
+        unsafe { Fence::from_raw(mem::replace(&mut (*self.contents).retire_fence_fd, -1)) }
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut hwc_display_contents {
+        self.contents
+    }
+
+    // Readies contents that were set for being set again unchanged: their
+    // fences were consumed, and the damage they pointed to is gone.
+    fn reuse(&mut self) {
+        unsafe {
+            (*self.contents).flags &= !HWC_GEOMETRY_CHANGED;
+            (*self.contents).retire_fence_fd = -1;
+        }
+        for layer in self.layers_mut() {
+            layer.acquire_fence_fd = -1;
+            layer.release_fence_fd = -1;
+            layer.surface_damage = hwc_region {
+                num_rects: 1,
+                rects: &NO_DAMAGE,
+            };
+        }
+    }
+}
+
+// A single empty rect is how layers say that nothing changed.
+static NO_DAMAGE: hwc_rect = hwc_rect {
+    left: 0,
+    top: 0,
+    right: 0,
+    bottom: 0,
+};
+
+/// The fences set() handed back for the contents of a display.
+pub struct SetFences {
+    pub retire: Option<Fence>,
+    /// The release fence of each layer, in order.
+    pub release: Vec<Option<Fence>>,
+}
+
+impl Drop for DisplayContents {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.contents as *mut u8, self.layout) };
+    }
 }
 
 #[repr(C)]
 pub struct hwc_procs {
-    invalidate: extern "C" fn(*const hwc_procs),
-    vsync: extern "C" fn(*const hwc_procs, c_int, i64),
-    hotplug: extern "C" fn(*const hwc_procs, c_int, c_int),
+    pub invalidate: extern "C" fn(*const hwc_procs),
+    pub vsync: extern "C" fn(*const hwc_procs, c_int, i64),
+    pub hotplug: extern "C" fn(*const hwc_procs, c_int, c_int),
+}
+
+/// Notifications sent by the HWC, usually from one of its own threads.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HwcEvent {
+    /// A vsync happened on `display`, at `timestamp` nanoseconds on
+    /// CLOCK_MONOTONIC.
+    Vsync { display: c_int, timestamp: i64 },
+    /// The HWC asks for the screen to be redrawn.
+    Invalidate,
+    /// An external display was plugged in or out.
+    Hotplug { display: c_int, connected: bool },
+}
+
+// Shared, so that a handler can run without holding the lock it is set
+// through, and replace itself.
+type HwcEventHandler = Arc<Mutex<dyn FnMut(HwcEvent) + Send>>;
+
+// The procs table we register, followed by what we need to route its
+// callbacks back to Rust.
+#[repr(C)]
+struct HwcProcs {
+    procs: hwc_procs,
+    handler: Mutex<Option<HwcEventHandler>>,
+    connected: [AtomicBool; HWC_NUM_PHYSICAL_DISPLAY_TYPES],
+}
+
+impl HwcProcs {
+    fn dispatch(procs: *const hwc_procs, event: HwcEvent) {
+        let procs = unsafe { &*(procs as *const HwcProcs) };
+        let handler = procs.handler.lock().unwrap().clone();
+        if let Some(handler) = handler {
+            (*handler.lock().unwrap())(event);
+        }
+    }
+}
+
+extern "C" fn hwc_invalidate(procs: *const hwc_procs) {
+    HwcProcs::dispatch(procs, HwcEvent::Invalidate);
+}
+
+extern "C" fn hwc_vsync(procs: *const hwc_procs, display: c_int, timestamp: i64) {
+    HwcProcs::dispatch(procs, HwcEvent::Vsync { display, timestamp });
+}
+
+extern "C" fn hwc_hotplug(procs: *const hwc_procs, display: c_int, connected: c_int) {
+    info!("Hotplug on display {}: {}", display, connected);
+    if display < 0 || display as usize >= HWC_NUM_PHYSICAL_DISPLAY_TYPES {
+        return;
+    }
+    let connected = connected != 0;
+    {
+        let procs = unsafe { &*(procs as *const HwcProcs) };
+        procs.connected[display as usize].store(connected, Ordering::SeqCst);
+    }
+    HwcProcs::dispatch(procs, HwcEvent::Hotplug { display, connected });
 }
 
 #[derive(PartialEq)]
@@ -159,68 +445,429 @@ impl HwcApiVersion {
     }
 }
 
+/// The attributes of a display config, see `HwcDevice::display_info()`
+/// and `HwcDevice::configs()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DisplayInfo {
+    /// In pixels.
+    pub width: i32,
+    pub height: i32,
+    /// Horizontal and vertical dots per inch.
+    pub dpi_x: f32,
+    pub dpi_y: f32,
+    /// In nanoseconds.
+    pub vsync_period: i64,
+}
+
+impl DisplayInfo {
+    /// The physical width of the display in millimeters, as far as its
+    /// dpi can be trusted.
+    pub fn width_mm(&self) -> f32 {
+        self.width as f32 / self.dpi_x * 25.4
+    }
+
+    pub fn height_mm(&self) -> f32 {
+        self.height as f32 / self.dpi_y * 25.4
+    }
+}
+
+// What we assume when the HAL doesn't tell: 60Hz, and Android's baseline
+// density.
+const DEFAULT_VSYNC_PERIOD: i64 = 16_666_667;
+const DEFAULT_DPI: f32 = 160.0;
+
+// HWC_DISPLAY_DPI_* are in dots per thousand inches, but some HALs
+// report plain dots per inch.
+fn dpi_from_hal(value: i32) -> Option<f32> {
+    match value {
+        value if value <= 0 => None,
+        value if value < 1000 => Some(value as f32),
+        value => Some(value as f32 / 1000.0),
+    }
+}
+
+/// The power state of a display, see `HwcDevice::set_power_mode()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerMode {
+    Off,
+    /// Low power, e.g. for an ambient display. The screen can still be
+    /// updated.
+    Doze,
+    Normal,
+    /// Low power, showing the last frame while the system may suspend.
+    DozeSuspend,
+}
+
+impl PowerMode {
+    pub fn to_hwc(self) -> c_int {
+        match self {
+            PowerMode::Off => HWC_POWER_MODE_OFF,
+            PowerMode::Doze => HWC_POWER_MODE_DOZE,
+            PowerMode::Normal => HWC_POWER_MODE_NORMAL,
+            PowerMode::DozeSuspend => HWC_POWER_MODE_DOZE_SUSPEND,
+        }
+    }
+
+    // Whether the system has to stay up for the display to work, as
+    // opposed to modes in which it can suspend.
+    fn needs_cpu(self) -> bool {
+        match self {
+            PowerMode::Doze | PowerMode::Normal => true,
+            PowerMode::Off | PowerMode::DozeSuspend => false,
+        }
+    }
+}
+
 pub struct HwcDevice {
-    native: *mut hwc_composer_device,
+    // Declared before the procs, so that the device is closed before they
+    // are freed.
+    native: HalDevice<hwc_composer_device>,
     version: HwcApiVersion,
+    // Registered with the HAL, see HwcDevice::new().
+    procs: Box<HwcProcs>,
+    power_modes: Cell<[PowerMode; HWC_NUM_PHYSICAL_DISPLAY_TYPES]>,
+    // The contents last set on each display. HWC1 blanks the displays left
+    // out of a prepare() and set(), so they go along with those of the
+    // display being set.
+    frames: RefCell<[Option<DisplayContents>; HWC_NUM_DISPLAY_TYPES]>,
+    // The release fence of the framebuffer target of each display, from
+    // the last time its contents were set again with another display's.
+    target_releases: RefCell<[Option<Fence>; HWC_NUM_DISPLAY_TYPES]>,
+    // The display whose contents the last prepare() was for, if they have
+    // not been set yet.
+    prepared: Cell<Option<c_int>>,
 }
 
 impl HwcDevice {
-    pub fn new() -> Option<HwcDevice> {
+    pub fn new() -> Result<HwcDevice, GfxError> {
         let mut hwc_mod = ptr::null();
         unsafe {
             let cstr = CString::new("hwcomposer").unwrap();
             let ret = hw_get_module(cstr.as_ptr(), &mut hwc_mod);
             if ret != 0 {
                 error!("Failed to get HWC module!");
-                return None;
+                return Err(GfxError::ModuleNotFound {
+                    module: "hwcomposer",
+                    code: ret,
+                });
             }
         }
 
-        let hwc_device: *mut hwc_composer_device;
-        unsafe {
-            let mut device = ptr::null();
-            let cstr = CString::new("composer").unwrap();
-            let ret = ((*(*hwc_mod).methods).open)(hwc_mod, cstr.as_ptr(), &mut device);
+        let native: HalDevice<hwc_composer_device> =
+            unsafe { HalDevice::open(hwc_mod, "hwcomposer", "composer")? };
+        let raw_version = native.version();
+        let version = match HwcApiVersion::from(raw_version) {
+            Some(version) => version,
+            None => {
+                error!("Unsupported HWC version 0x{:08x}", raw_version);
+                return Err(GfxError::UnsupportedHwcVersion(raw_version));
+            }
+        };
+        // There is no way to unregister the procs, and HALs may still call
+        // them while closing, so they are only freed once the device is.
+        let procs = Box::new(HwcProcs {
+            procs: hwc_procs {
+                invalidate: hwc_invalidate,
+                vsync: hwc_vsync,
+                hotplug: hwc_hotplug,
+            },
+            handler: Mutex::new(None),
+            // The primary display is always there, and the HAL sends
+            // hotplug events for the external one.
+            connected: [AtomicBool::new(true), AtomicBool::new(false)],
+        });
+        unsafe { ((*native.as_ptr()).register_procs)(native.as_ptr(), &procs.procs) };
+        Ok(HwcDevice {
+            native,
+            version,
+            procs,
+            power_modes: Cell::new([PowerMode::Off; HWC_NUM_PHYSICAL_DISPLAY_TYPES]),
+            frames: RefCell::new(Default::default()),
+            target_releases: RefCell::new(Default::default()),
+            prepared: Cell::new(None),
+        })
+    }
+
+    /// Sets the function called for every event sent by the HWC. It runs
+    /// on a HWC thread, so it should return quickly. It may set another
+    /// handler, which gets the events after the current one.
+    pub fn set_event_handler<F>(&self, handler: F)
+    where
+        F: FnMut(HwcEvent) + Send + 'static,
+    {
+        let procs = &self.procs;
+        *procs.handler.lock().unwrap() = Some(Arc::new(Mutex::new(handler)));
+    }
+
+    /// Replaces the event handler with one forwarding events to a channel.
+    pub fn event_channel(&self) -> Receiver<HwcEvent> {
+        let (sender, receiver) = channel();
+        self.set_event_handler(move |event| {
+            let _ = sender.send(event);
+        });
+        receiver
+    }
+
+    /// Turns delivery of vsync events for `display` on or off. They are off
+    /// by default, and should only be enabled while needed.
+    pub fn set_vsync_enabled(&self, display: c_int, enabled: bool) -> Result<(), GfxError> {
+        let ret = unsafe {
+            ((*self.native.as_ptr()).event_control)(
+                self.native.as_ptr(),
+                display,
+                HWC_EVENT_VSYNC,
+                enabled as c_int,
+            )
+        };
+        if ret != 0 {
+            error!("Failed to set vsync to {} on display {}", enabled, display);
+            return Err(GfxError::Hwc {
+                call: "event_control",
+                code: ret,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether a physical display is currently connected.
+    pub fn is_connected(&self, display: c_int) -> bool {
+        if display < 0 || display as usize >= HWC_NUM_PHYSICAL_DISPLAY_TYPES {
+            return false;
+        }
+        let procs = &self.procs;
+        procs.connected[display as usize].load(Ordering::SeqCst)
+    }
+
+    /// Like get_display_dimensions_and_dpi(), for the primary display.
+    pub fn get_dimensions_and_dpi(&self) -> Result<(i32, i32, i32), GfxError> {
+        self.get_display_dimensions_and_dpi(HWC_DISPLAY_PRIMARY)
+    }
+
+    /// Returns the width, height and horizontal dpi of a connected display,
+    /// in its active config.
+    pub fn get_display_dimensions_and_dpi(
+        &self,
+        display: c_int,
+    ) -> Result<(i32, i32, i32), GfxError> {
+        let info = self.display_info(display)?;
+        Ok((info.width, info.height, info.dpi_x as i32))
+    }
+
+    /// The attributes of a connected display, in its active config.
+    pub fn display_info(&self, display: c_int) -> Result<DisplayInfo, GfxError> {
+        let configs = self.config_ids(display)?;
+        let active = self.active_config(display)?;
+        self.config(display, configs[active])
+    }
+
+    /// The configs a connected display supports. Their index in the list
+    /// is what active_config() and set_active_config() deal with.
+    pub fn configs(&self, display: c_int) -> Result<Vec<DisplayInfo>, GfxError> {
+        self.config_ids(display)?
+            .into_iter()
+            .map(|id| self.config(display, id))
+            .collect()
+    }
+
+    /// The index of the config a display is currently driven with. Before
+    /// HWC 1.4, it is always the first one.
+    pub fn active_config(&self, display: c_int) -> Result<usize, GfxError> {
+        if !self.is_connected(display) {
+            return Err(GfxError::DisplayNotConnected(display));
+        }
+        let get_active_config = match unsafe { (*self.native.as_ptr()).get_active_config } {
+            Some(get_active_config) if self.version != HwcApiVersion::Hwc1_3 => get_active_config,
+            _ => return Ok(0),
+        };
+        let ret = get_active_config(self.native.as_ptr(), display);
+        if ret < 0 {
+            return Err(GfxError::Hwc {
+                call: "get_active_config",
+                code: ret,
+            });
+        }
+        Ok(ret as usize)
+    }
+
+    /// Switches a display to another of its configs, e.g. one with a lower
+    /// refresh rate to save power. This needs HWC 1.4.
+    pub fn set_active_config(&self, display: c_int, index: usize) -> Result<(), GfxError> {
+        let count = self.config_ids(display)?.len();
+        if index >= count {
+            error!("Display {} has no config {}", display, index);
+            return Err(GfxError::Hwc {
+                call: "set_active_config",
+                code: -libc::EINVAL,
+            });
+        }
+        let set_active_config = match unsafe { (*self.native.as_ptr()).set_active_config } {
+            Some(set_active_config) if self.version != HwcApiVersion::Hwc1_3 => set_active_config,
+            _ if index == 0 => return Ok(()),
+            _ => {
+                return Err(GfxError::Hwc {
+                    call: "set_active_config",
+                    code: -libc::ENOSYS,
+                })
+            }
+        };
+        let ret = set_active_config(self.native.as_ptr(), display, index as c_int);
+        if ret != 0 {
+            return Err(GfxError::Hwc {
+                call: "set_active_config",
+                code: ret,
+            });
+        }
+        Ok(())
+    }
+
+    // The HAL's handles to the configs of a display.
+    fn config_ids(&self, display: c_int) -> Result<Vec<u32>, GfxError> {
+        if !self.is_connected(display) {
+            return Err(GfxError::DisplayNotConnected(display));
+        }
+        let mut ids = vec![0; 8];
+        loop {
+            let mut count = ids.len();
+            let ret = unsafe {
+                ((*self.native.as_ptr()).get_display_configs)(
+                    self.native.as_ptr(),
+                    display,
+                    ids.as_mut_ptr(),
+                    &mut count,
+                )
+            };
             if ret != 0 {
-                error!("Failed to get HWC device!");
-                return None;
+                return Err(GfxError::Hwc {
+                    call: "get_display_configs",
+                    code: ret,
+                });
+            }
+            if count == 0 {
+                // Only happens with disconnected displays.
+                return Err(GfxError::DisplayNotConnected(display));
             }
-            hwc_device = transmute(device);
-
-            match HwcApiVersion::from((*hwc_device).common.version) {
-                None => None,
-                Some(version) => Some(HwcDevice {
-                    native: hwc_device,
-                    version,
-                }),
+            // The list was truncated, try again with enough room.
+            if count > ids.len() {
+                ids.resize(count, 0);
+                continue;
             }
+            ids.truncate(count);
+            return Ok(ids);
         }
     }
 
-    pub fn get_dimensions_and_dpi(&self) -> (i32, i32, i32) {
-        let attrs: [u32; 4] = [
+    fn config(&self, display: c_int, id: u32) -> Result<DisplayInfo, GfxError> {
+        let attrs: [u32; 6] = [
             HWC_DISPLAY_WIDTH,
             HWC_DISPLAY_HEIGHT,
             HWC_DISPLAY_DPI_X,
+            HWC_DISPLAY_DPI_Y,
+            HWC_DISPLAY_VSYNC_PERIOD,
             HWC_DISPLAY_NO_ATTRIBUTE,
         ];
-        let mut values: [i32; 4] = [0; 4];
+        let mut values: [i32; 6] = [0; 6];
+        let ret = self.get_display_attributes(display, id, &attrs, &mut values);
+        if ret != 0 {
+            // Some HALs fail as soon as they don't know one of the
+            // attributes, and others fail for the primary display while
+            // filling in the values anyway. Ask again one at a time, and
+            // keep what we got.
+            warn!(
+                "get_display_attributes failed on display {} ({}), retrying",
+                display, ret
+            );
+            for (&attr, value) in attrs.iter().zip(values.iter_mut()) {
+                let mut single = [0; 2];
+                let attrs = [attr, HWC_DISPLAY_NO_ATTRIBUTE];
+                if attr != HWC_DISPLAY_NO_ATTRIBUTE
+                    && self.get_display_attributes(display, id, &attrs, &mut single) == 0
+                {
+                    *value = single[0];
+                }
+            }
+        }
+        if values[0] <= 0 || values[1] <= 0 {
+            error!("No dimensions for display {}", display);
+            return Err(GfxError::Hwc {
+                call: "get_display_attributes",
+                code: if ret != 0 { ret } else { -libc::EINVAL },
+            });
+        }
+        let dpi_x = dpi_from_hal(values[2]).or_else(|| dpi_from_hal(values[3]));
+        let dpi_x = dpi_x.unwrap_or(DEFAULT_DPI);
+        Ok(DisplayInfo {
+            width: values[0],
+            height: values[1],
+            dpi_x,
+            dpi_y: dpi_from_hal(values[3]).unwrap_or(dpi_x),
+            vsync_period: if values[4] > 0 {
+                i64::from(values[4])
+            } else {
+                DEFAULT_VSYNC_PERIOD
+            },
+        })
+    }
+
+    fn get_display_attributes(
+        &self,
+        display: c_int,
+        id: u32,
+        attrs: &[u32],
+        values: &mut [i32],
+    ) -> c_int {
+        assert_eq!(attrs.last(), Some(&HWC_DISPLAY_NO_ATTRIBUTE));
+        assert!(values.len() >= attrs.len());
         unsafe {
-            // In theory, we should check the return code.
-            // However, there are HALs which implement this wrong.
-            let _ = ((*self.native).get_display_attributes)(
-                self.native,
-                0,
-                0,
+            ((*self.native.as_ptr()).get_display_attributes)(
+                self.native.as_ptr(),
+                display,
+                id,
                 attrs.as_ptr(),
                 values.as_mut_ptr(),
-            );
+            )
         }
-        (values[0], values[1], values[2] / 1000)
     }
 
     pub fn set_display(&self, enable: bool) {
-        if enable {
+        self.set_display_on(HWC_DISPLAY_PRIMARY, enable);
+    }
+
+    /// Turns a physical display on or off. Autosuspend is disabled while
+    /// any display is on.
+    pub fn set_display_on(&self, display: c_int, enable: bool) {
+        let mode = if enable {
+            PowerMode::Normal
+        } else {
+            PowerMode::Off
+        };
+        if let Err(err) = self.set_power_mode(display, mode) {
+            error!("{}", err);
+        }
+    }
+
+    /// Changes the power mode of a physical display. Autosuspend is
+    /// disabled while any display is in a mode that needs the system up,
+    /// Normal or Doze, and enabled again once all of them are Off or
+    /// DozeSuspend.
+    ///
+    /// HWC 1.3 has no power modes, only blank(): there, the doze modes
+    /// unblank the display like Normal, and keep the system up as well.
+    pub fn set_power_mode(&self, display: c_int, mode: PowerMode) -> Result<(), GfxError> {
+        if display < 0 || display as usize >= HWC_NUM_PHYSICAL_DISPLAY_TYPES {
+            return Err(GfxError::DisplayNotConnected(display));
+        }
+        let mode = if self.version == HwcApiVersion::Hwc1_3 && mode != PowerMode::Off {
+            PowerMode::Normal
+        } else {
+            mode
+        };
+
+        let mut modes = self.power_modes.get();
+        let was_up = modes.iter().any(|mode| mode.needs_cpu());
+        modes[display as usize] = mode;
+        let is_up = modes.iter().any(|mode| mode.needs_cpu());
+
+        if is_up && !was_up {
             unsafe {
                 autosuspend_disable();
             }
@@ -228,31 +875,179 @@ impl HwcDevice {
 
         // If the version is 1.3, we actually are using the blank()
         // method behing the scene.
-        let mode = if self.version == HwcApiVersion::Hwc1_3 {
-            if enable {
-                0
-            } else {
-                1
-            }
+        let hwc_mode = if self.version == HwcApiVersion::Hwc1_3 {
+            (mode == PowerMode::Off) as c_int
         } else {
-            if enable {
-                HWC_POWER_MODE_NORMAL
-            } else {
-                HWC_POWER_MODE_OFF
-            }
+            mode.to_hwc()
         };
-        unsafe {
-            ((*self.native).set_power_mode)(self.native, 0, mode);
+        let ret = unsafe {
+            ((*self.native.as_ptr()).set_power_mode)(self.native.as_ptr(), display, hwc_mode)
+        };
+        if ret == 0 {
+            self.power_modes.set(modes);
         }
 
-        if !enable {
+        if !self.power_modes.get().iter().any(|mode| mode.needs_cpu()) {
             unsafe {
                 autosuspend_enable();
             }
         }
+
+        if ret != 0 {
+            return Err(GfxError::Hwc {
+                call: "set_power_mode",
+                code: ret,
+            });
+        }
+        Ok(())
+    }
+
+    /// The last power mode set for a physical display.
+    pub fn power_mode(&self, display: c_int) -> PowerMode {
+        self.power_modes
+            .get()
+            .get(display as usize)
+            .cloned()
+            .unwrap_or(PowerMode::Off)
     }
 
     pub fn native(&self) -> *mut hwc_composer_device {
-        self.native
+        self.native.as_ptr()
+    }
+
+    /// Runs prepare() on `contents` for `display`, along with the contents
+    /// last set on the other displays. The HWC's choice of composition for
+    /// each layer can then be read back from `contents`.
+    pub fn prepare(&self, display: c_int, contents: &mut DisplayContents) -> Result<(), GfxError> {
+        let prepare = unsafe { (*self.native()).prepare };
+        self.call("prepare", prepare, display, contents)?;
+        self.prepared.set(Some(display));
+        Ok(())
+    }
+
+    /// Shows `contents` on `display`, running prepare() first unless they
+    /// were `prepared` and no other display was prepared or set since.
+    ///
+    /// The contents last set on the other displays are set again along
+    /// with them, so they must only point to buffers that are still alive
+    /// until those displays are set again or forgotten. The same goes for
+    /// `contents`, which are kept for that once this returns.
+    pub fn set(
+        &self,
+        display: c_int,
+        mut contents: DisplayContents,
+        prepared: bool,
+    ) -> Result<SetFences, GfxError> {
+        if !prepared || self.prepared.get() != Some(display) {
+            self.prepare(display, &mut contents)?;
+        }
+        self.prepared.set(None);
+        let set = unsafe { (*self.native()).set };
+        let ret = self.call("set", set, display, &mut contents);
+
+        // The other displays got new fences for the same buffers, which
+        // signal after the ones they had.
+        {
+            let mut frames = self.frames.borrow_mut();
+            let mut target_releases = self.target_releases.borrow_mut();
+            for (i, frame) in frames.iter_mut().enumerate() {
+                if i == display as usize {
+                    continue;
+                }
+                if let Some(ref mut frame) = *frame {
+                    drop(frame.take_retire_fence());
+                    let (target, layers) = frame.layers_mut().split_last_mut().unwrap();
+                    for layer in layers {
+                        drop(layer.take_release_fence());
+                    }
+                    if let Some(fence) = target.take_release_fence() {
+                        target_releases[i] = Some(fence);
+                    }
+                    frame.reuse();
+                }
+            }
+        }
+        ret?;
+
+        let fences = SetFences {
+            retire: contents.take_retire_fence(),
+            release: contents
+                .layers_mut()
+                .iter_mut()
+                .map(|layer| layer.take_release_fence())
+                .collect(),
+        };
+        contents.reuse();
+        self.target_releases.borrow_mut()[display as usize] = None;
+        self.frames.borrow_mut()[display as usize] = Some(contents);
+        Ok(fences)
+    }
+
+    /// Takes the release fence the framebuffer target of `display` got
+    /// when its contents were last set again along with another display's.
+    /// It replaces the one its own set() returned.
+    pub fn take_target_release_fence(&self, display: c_int) -> Option<Fence> {
+        self.target_releases
+            .borrow_mut()
+            .get_mut(display as usize)
+            .and_then(Option::take)
+    }
+
+    /// Stops setting the last contents of `display` along with those of
+    /// the other displays, before the buffers they point to go away. The
+    /// display shows nothing from the next set() of another display on.
+    pub fn forget_display(&self, display: c_int) {
+        if let Some(frame) = self.frames.borrow_mut().get_mut(display as usize) {
+            *frame = None;
+        }
+        if let Some(fence) = self.target_releases.borrow_mut().get_mut(display as usize) {
+            *fence = None;
+        }
+    }
+
+    fn call(
+        &self,
+        name: &'static str,
+        call: extern "C" fn(
+            *mut hwc_composer_device,
+            size_t,
+            *mut *mut hwc_display_contents,
+        ) -> c_int,
+        display: c_int,
+        contents: &mut DisplayContents,
+    ) -> Result<(), GfxError> {
+        if display < 0 || display as usize >= HWC_NUM_DISPLAY_TYPES {
+            return Err(GfxError::DisplayNotConnected(display));
+        }
+        let mut frames = self.frames.borrow_mut();
+        let mut displays = [ptr::null_mut(); HWC_NUM_DISPLAY_TYPES];
+        for (i, frame) in frames.iter_mut().enumerate() {
+            if let Some(ref mut frame) = *frame {
+                displays[i] = frame.as_mut_ptr();
+            }
+        }
+        displays[display as usize] = contents.as_mut_ptr();
+        let ret = call(
+            self.native(),
+            displays.len() as size_t,
+            displays.as_mut_ptr(),
+        );
+        info!("hwc.{} returned {}", name, ret);
+        if ret != 0 {
+            return Err(GfxError::Hwc {
+                call: name,
+                code: ret,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for HwcDevice {
+    fn drop(&mut self) {
+        // The device is only closed after this, make sure events sent
+        // meanwhile don't call into anything we owned.
+        *self.procs.handler.lock().unwrap() = None;
+        info!("Closing HWC device");
     }
 }
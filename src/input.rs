@@ -0,0 +1,376 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Input events from Linux evdev devices.
+//!
+//! Devices are usually the /dev/input/event* nodes, but anything that can
+//! be read from works, so recorded event streams can be replayed through a
+//! pipe or a file.
+
+use libc::{self, c_int, c_ulong, pollfd, timeval};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::mem::size_of;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::time::Duration;
+
+// From bionic/libc/kernel/uapi/linux/input.h
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct linux_input_event {
+    pub time: timeval,
+    pub type_: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+const EVENT_SIZE: usize = size_of::<linux_input_event>();
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct input_absinfo {
+    pub value: i32,
+    pub minimum: i32,
+    pub maximum: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    pub resolution: i32,
+}
+
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+
+pub const SYN_REPORT: u16 = 0;
+pub const SYN_CONFIG: u16 = 1;
+pub const SYN_MT_REPORT: u16 = 2;
+pub const SYN_DROPPED: u16 = 3;
+
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const ABS_PRESSURE: u16 = 0x18;
+pub const ABS_MT_SLOT: u16 = 0x2f;
+pub const ABS_MT_TOUCH_MAJOR: u16 = 0x30;
+pub const ABS_MT_POSITION_X: u16 = 0x35;
+pub const ABS_MT_POSITION_Y: u16 = 0x36;
+pub const ABS_MT_TRACKING_ID: u16 = 0x39;
+pub const ABS_MT_PRESSURE: u16 = 0x3a;
+
+pub const KEY_POWER: u16 = 116;
+pub const KEY_VOLUMEDOWN: u16 = 114;
+pub const KEY_VOLUMEUP: u16 = 115;
+pub const BTN_TOUCH: u16 = 0x14a;
+
+// _IOC() from asm-generic/ioctl.h
+const IOC_READ: c_ulong = 2;
+
+fn eviocg(nr: c_ulong, size: usize) -> c_ulong {
+    (IOC_READ << 30) | ((size as c_ulong) << 16) | ((b'E' as c_ulong) << 8) | nr
+}
+
+fn eviocgname(len: usize) -> c_ulong {
+    eviocg(0x06, len)
+}
+
+fn eviocgabs(axis: u16) -> c_ulong {
+    eviocg(0x40 + axis as c_ulong, size_of::<input_absinfo>())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyState {
+    Released,
+    Pressed,
+    Repeated,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncKind {
+    /// The end of a packet of events describing the device state.
+    Report,
+    Config,
+    /// The end of a contact in the legacy (protocol A) multitouch protocol.
+    MtReport,
+    /// The kernel buffer overran; events were lost since the last report.
+    Dropped,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    Key { code: u16, state: KeyState },
+    Relative { axis: u16, value: i32 },
+    Absolute { axis: u16, value: i32 },
+    Sync(SyncKind),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputEvent {
+    /// The kernel timestamp of the event.
+    pub time: Duration,
+    pub kind: EventKind,
+}
+
+impl InputEvent {
+    /// Converts a raw event, returning None for the event types we don't
+    /// deliver (EV_MSC, EV_LED, EV_SW...).
+    pub fn from_raw(raw: &linux_input_event) -> Option<InputEvent> {
+        let kind = match raw.type_ {
+            EV_SYN => EventKind::Sync(match raw.code {
+                SYN_REPORT => SyncKind::Report,
+                SYN_CONFIG => SyncKind::Config,
+                SYN_MT_REPORT => SyncKind::MtReport,
+                SYN_DROPPED => SyncKind::Dropped,
+                _ => return None,
+            }),
+            EV_KEY => EventKind::Key {
+                code: raw.code,
+                state: match raw.value {
+                    0 => KeyState::Released,
+                    1 => KeyState::Pressed,
+                    _ => KeyState::Repeated,
+                },
+            },
+            EV_REL => EventKind::Relative {
+                axis: raw.code,
+                value: raw.value,
+            },
+            EV_ABS => EventKind::Absolute {
+                axis: raw.code,
+                value: raw.value,
+            },
+            _ => return None,
+        };
+        let time = Duration::new(raw.time.tv_sec as u64, raw.time.tv_usec as u32 * 1000);
+        Some(InputEvent { time, kind })
+    }
+}
+
+/// A source of evdev events.
+pub struct InputDevice<R> {
+    name: String,
+    reader: R,
+    pending: Vec<u8>,
+}
+
+impl InputDevice<File> {
+    /// Opens an evdev device node.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<InputDevice<File>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(path.as_ref())?;
+
+        let mut name = [0u8; 256];
+        let ret = unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                eviocgname(name.len()) as _,
+                name.as_mut_ptr(),
+            )
+        };
+        let name = if ret > 0 {
+            let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+            String::from_utf8_lossy(&name[..len]).into_owned()
+        } else {
+            path.as_ref().to_string_lossy().into_owned()
+        };
+        info!("Opened input device {:?}: {}", path.as_ref(), name);
+
+        Ok(InputDevice::from_reader(&name, file))
+    }
+}
+
+impl<R: Read> InputDevice<R> {
+    /// Reads events from any reader producing raw `linux_input_event`
+    /// records, e.g. a pipe replaying a recording.
+    pub fn from_reader(name: &str, reader: R) -> InputDevice<R> {
+        InputDevice {
+            name: name.to_owned(),
+            reader,
+            pending: Vec::with_capacity(EVENT_SIZE * 64),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Does a single read and returns the complete events received.
+    /// Returns an empty vector when nothing is available on a non-blocking
+    /// device, and an UnexpectedEof error once the reader is exhausted.
+    pub fn read_events(&mut self) -> io::Result<Vec<InputEvent>> {
+        let mut buf = [0u8; EVENT_SIZE * 64];
+        let len = match self.reader.read(&mut buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => len,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => 0,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => 0,
+            Err(err) => return Err(err),
+        };
+        self.pending.extend_from_slice(&buf[..len]);
+
+        // Pipes don't preserve record boundaries, so keep any partial event
+        // around for the next read.
+        let complete = self.pending.len() / EVENT_SIZE;
+        let events = self.pending[..complete * EVENT_SIZE]
+            .chunks(EVENT_SIZE)
+            .filter_map(|chunk| {
+                let raw = unsafe { (chunk.as_ptr() as *const linux_input_event).read_unaligned() };
+                InputEvent::from_raw(&raw)
+            })
+            .collect();
+        self.pending.drain(..complete * EVENT_SIZE);
+        Ok(events)
+    }
+}
+
+impl<R: AsRawFd> InputDevice<R> {
+    /// Queries the range and resolution of an absolute axis. This fails
+    /// for readers that aren't evdev nodes.
+    pub fn abs_info(&self, axis: u16) -> io::Result<input_absinfo> {
+        let mut info = input_absinfo::default();
+        let ret = unsafe {
+            libc::ioctl(
+                self.reader.as_raw_fd(),
+                eviocgabs(axis) as _,
+                &mut info as *mut input_absinfo,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(info)
+    }
+}
+
+impl<R: AsRawFd> AsRawFd for InputDevice<R> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+}
+
+/// Returns the paths of the /dev/input/event* nodes, sorted.
+pub fn event_device_paths() -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir("/dev/input")?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("event"))
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Multiplexes a set of input devices.
+pub struct InputManager<R> {
+    devices: Vec<InputDevice<R>>,
+}
+
+impl<R> Default for InputManager<R> {
+    fn default() -> Self {
+        InputManager { devices: vec![] }
+    }
+}
+
+impl InputManager<File> {
+    /// Opens every evdev device that we have access to.
+    pub fn open_all() -> io::Result<InputManager<File>> {
+        let mut manager = InputManager::new();
+        for path in event_device_paths()? {
+            match InputDevice::open(&path) {
+                Ok(device) => manager.add_device(device),
+                Err(err) => error!("Failed to open {:?}: {}", path, err),
+            }
+        }
+        Ok(manager)
+    }
+}
+
+impl<R: Read + AsRawFd> InputManager<R> {
+    pub fn new() -> InputManager<R> {
+        InputManager::default()
+    }
+
+    pub fn add_device(&mut self, device: InputDevice<R>) {
+        self.devices.push(device);
+    }
+
+    pub fn devices(&self) -> &[InputDevice<R>] {
+        &self.devices
+    }
+
+    /// Waits up to `timeout` milliseconds (-1 to block) for input, and
+    /// calls `handler` with the index of the device and each event read.
+    /// Devices that reach end of file or fail are dropped, so indices of
+    /// later devices shift down. Returns the number of events delivered.
+    pub fn poll_events<F>(&mut self, timeout: c_int, mut handler: F) -> io::Result<usize>
+    where
+        F: FnMut(usize, &InputEvent),
+    {
+        let mut fds: Vec<pollfd> = self
+            .devices
+            .iter()
+            .map(|device| pollfd {
+                fd: device.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, timeout) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(err);
+        }
+
+        let mut count = 0;
+        let mut closed = vec![];
+        for (idx, fd) in fds.iter().enumerate() {
+            if fd.revents == 0 {
+                continue;
+            }
+            match self.devices[idx].read_events() {
+                Ok(events) => {
+                    for event in &events {
+                        handler(idx, event);
+                    }
+                    count += events.len();
+                }
+                Err(err) => {
+                    if err.kind() != io::ErrorKind::UnexpectedEof {
+                        error!("Error reading from {}: {}", self.devices[idx].name, err);
+                    }
+                    closed.push(idx);
+                }
+            }
+        }
+        for idx in closed.into_iter().rev() {
+            info!("Closing input device {}", self.devices[idx].name);
+            self.devices.remove(idx);
+        }
+        Ok(count)
+    }
+}
+
+impl linux_input_event {
+    /// The raw bytes of a sequence of events, as the kernel would produce
+    /// them. Handy to record or synthesize event streams.
+    pub fn as_bytes(events: &[linux_input_event]) -> &[u8] {
+        unsafe { slice::from_raw_parts(events.as_ptr() as *const u8, events.len() * EVENT_SIZE) }
+    }
+}
@@ -0,0 +1,164 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Layers composited by the HWC on top of each other, see
+//! `Window::compose()`.
+
+use fence::Fence;
+use gonk_gfx::ANativeWindowBuffer;
+use hwc::*;
+use libc::c_int;
+
+/// A buffer to show on screen, and how to show it.
+#[derive(Clone, Copy, Debug)]
+pub struct Layer {
+    buffer: *mut ANativeWindowBuffer,
+    /// The part of the buffer to show, in buffer pixels.
+    pub source_crop: hwc_frect,
+    /// Where to show it, in display pixels.
+    pub display_frame: hwc_rect,
+    /// A combination of the HWC_TRANSFORM_* flags.
+    pub transform: u32,
+    /// One of the HWC_BLENDING_* modes.
+    pub blending: c_int,
+    pub plane_alpha: u8,
+    /// Layers with a higher z-order are shown above the others, and those
+    /// with the same one in the order they were pushed.
+    pub z_order: i32,
+}
+
+impl Layer {
+    /// Creates an opaque layer showing the whole buffer at the top left of
+    /// the display, at its own size.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must stay alive until the layer has been composed.
+    pub unsafe fn new(buffer: *mut ANativeWindowBuffer) -> Layer {
+        let (width, height) = ((*buffer).width, (*buffer).height);
+        Layer {
+            buffer,
+            source_crop: hwc_frect {
+                left: 0.0,
+                top: 0.0,
+                right: width as f32,
+                bottom: height as f32,
+            },
+            display_frame: hwc_rect {
+                left: 0,
+                top: 0,
+                right: width,
+                bottom: height,
+            },
+            transform: 0,
+            blending: HWC_BLENDING_NONE,
+            plane_alpha: 0xff,
+            z_order: 0,
+        }
+    }
+
+    pub fn buffer(&self) -> *mut ANativeWindowBuffer {
+        self.buffer
+    }
+
+    /// Fills in the HWC's view of the layer, leaving its composition to GLES.
+    pub fn to_hwc_layer(&self, layer: &mut hwc_layer) {
+        *layer = hwc_layer {
+            handle: unsafe { (*self.buffer).handle },
+            transform: self.transform,
+            blending: self.blending,
+            source_crop: self.source_crop,
+            display_frame: self.display_frame,
+            plane_alpha: self.plane_alpha,
+            ..hwc_layer::default()
+        };
+    }
+}
+
+/// The layers of a display, kept bottom-most first.
+#[derive(Default)]
+pub struct LayerStack {
+    layers: Vec<Layer>,
+}
+
+impl LayerStack {
+    pub fn new() -> LayerStack {
+        LayerStack::default()
+    }
+
+    /// Adds a layer above the others with the same or a lower z-order.
+    pub fn push(&mut self, layer: Layer) {
+        let index = self
+            .layers
+            .iter()
+            .position(|other| other.z_order > layer.z_order)
+            .unwrap_or(self.layers.len());
+        self.layers.insert(index, layer);
+    }
+
+    pub fn clear(&mut self) {
+        self.layers.clear();
+    }
+
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Builds the contents to prepare for a display: the layers, followed
+    /// by a framebuffer target covering `target_frame` whose buffer is
+    /// filled in when the GLES surface is swapped.
+    pub fn display_contents(&self, target_frame: hwc_rect) -> DisplayContents {
+        let mut contents = DisplayContents::new(self.layers.len() + 1);
+        {
+            let (target, layers) = contents.layers_mut().split_last_mut().unwrap();
+            for (layer, hwc_layer) in self.layers.iter().zip(layers) {
+                layer.to_hwc_layer(hwc_layer);
+            }
+            target.composition_type = HWC_FRAMEBUFFER_TARGET;
+            target.blending = HWC_BLENDING_PREMULT;
+            target.display_frame = target_frame;
+        }
+        contents
+    }
+}
+
+/// What `Window::compose()` showed.
+#[derive(Debug)]
+pub struct ComposedFrame {
+    /// The id of the frame, like those returned by `Window::swap_buffers()`.
+    pub frame: u64,
+    /// How each layer of the stack was composited, bottom-most first.
+    pub composition: Vec<Composition>,
+    /// For each layer, a fence signalled once the display is done reading
+    /// its buffer, which can't be drawn to before. None if the display
+    /// doesn't read it, as for GLES layers.
+    pub layer_release: Vec<Option<Fence>>,
+}
+
+/// How a layer ended up on screen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Composition {
+    /// Scanned out by the display hardware.
+    Overlay,
+    /// Drawn with GLES into the framebuffer target.
+    Gles,
+}
+
+impl Composition {
+    pub fn from_hwc(composition_type: i32) -> Composition {
+        if composition_type == HWC_OVERLAY {
+            Composition::Overlay
+        } else {
+            Composition::Gles
+        }
+    }
+}
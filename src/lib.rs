@@ -2,9 +2,6 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-// For size_of::<linux_input_event>() in input.rs
-#![feature(const_size_of)]
-
 extern crate egl;
 extern crate errno;
 extern crate gleam;
@@ -12,8 +9,19 @@ extern crate libc;
 #[macro_use]
 extern crate log;
 
+mod compositor;
+mod egl_display;
+pub mod egl_image;
+pub mod error;
+pub mod fence;
 pub mod gonk_gfx;
 pub mod gralloc;
 pub mod hardware;
 pub mod hwc;
+pub mod input;
+pub mod layers;
+#[cfg(feature = "mock-hal")]
+pub mod mock_hal;
+pub mod offscreen;
+pub mod touch;
 pub mod window;
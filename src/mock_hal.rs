@@ -0,0 +1,994 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An in-process software HAL, replacing libhardware and libsuspend when
+//! the `mock-hal` feature is enabled.
+//!
+//! It provides a malloc-backed gralloc and a hwcomposer that records every
+//! call it receives, so that the rest of the crate can be exercised on a
+//! Linux host. The knobs and counters are per-thread, which keeps tests
+//! running in parallel from seeing each other's devices.
+
+use fence::Fence;
+use gonk_gfx::native_handle;
+use gralloc::*;
+use hardware::*;
+use hwc::*;
+use libc::{self, c_char, c_int, c_void, size_t};
+use std::cell::{Cell, RefCell};
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::ptr;
+use std::slice;
+
+const HARDWARE_MODULE_TAG: u32 = 0x484d_4f44; // 'HMOD'
+const HARDWARE_DEVICE_TAG: u32 = 0x4844_4556; // 'HDEV'
+
+// Lets us keep the module descriptors, which hold raw pointers, in statics.
+struct Static<T>(T);
+unsafe impl<T> Sync for Static<T> {}
+
+/// The attributes reported by the mock HWC for a display.
+#[derive(Clone, Copy, Debug)]
+pub struct MockDisplayConfig {
+    pub width: i32,
+    pub height: i32,
+    /// Dots per thousand inches, as returned by the HAL.
+    pub dpi_x: i32,
+    pub dpi_y: i32,
+    /// In nanoseconds.
+    pub vsync_period: i32,
+}
+
+impl Default for MockDisplayConfig {
+    fn default() -> Self {
+        MockDisplayConfig {
+            width: 720,
+            height: 1280,
+            dpi_x: 320_000,
+            dpi_y: 320_000,
+            vsync_period: 16_666_666,
+        }
+    }
+}
+
+thread_local! {
+    static HWC_VERSION: Cell<u32> = Cell::new(HwcApiVersion::hwc_api_version(1, 4));
+    static PRIMARY_DISPLAY: Cell<MockDisplayConfig> = Cell::new(MockDisplayConfig::default());
+    static AUTOSUSPEND_ENABLED: Cell<bool> = const { Cell::new(true) };
+    static LIVE_BUFFERS: Cell<usize> = const { Cell::new(0) };
+    static REGISTERED_BUFFERS: Cell<usize> = const { Cell::new(0) };
+    static OPEN_HWC_DEVICES: Cell<usize> = const { Cell::new(0) };
+    static OPEN_ALLOC_DEVICES: Cell<usize> = const { Cell::new(0) };
+    static FAIL_ALLOCATIONS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Sets the version reported by HWC devices opened afterwards on this thread.
+pub fn set_hwc_version(version: u32) {
+    HWC_VERSION.with(|v| v.set(version));
+}
+
+/// Sets the primary display reported by HWC devices opened afterwards on
+/// this thread.
+pub fn set_primary_display(config: MockDisplayConfig) {
+    PRIMARY_DISPLAY.with(|c| c.set(config));
+}
+
+/// Makes the allocator fail with -ENOMEM on this thread, until called
+/// again with false.
+pub fn set_fail_allocations(fail: bool) {
+    FAIL_ALLOCATIONS.with(|f| f.set(fail));
+}
+
+/// Whether autosuspend is currently enabled, as seen from this thread.
+pub fn autosuspend_enabled() -> bool {
+    AUTOSUSPEND_ENABLED.with(|e| e.get())
+}
+
+/// The number of gralloc buffers allocated on this thread and not freed yet.
+pub fn live_buffers() -> usize {
+    LIVE_BUFFERS.with(|n| n.get())
+}
+
+/// The number of handles registered on this thread and not unregistered
+/// yet.
+pub fn registered_buffers() -> usize {
+    REGISTERED_BUFFERS.with(|n| n.get())
+}
+
+/// The number of HWC devices opened on this thread and not closed yet.
+pub fn open_hwc_devices() -> usize {
+    OPEN_HWC_DEVICES.with(|n| n.get())
+}
+
+/// The number of gralloc allocator devices opened on this thread and not
+/// closed yet.
+pub fn open_alloc_devices() -> usize {
+    OPEN_ALLOC_DEVICES.with(|n| n.get())
+}
+
+/// Whether a buffer allocated by the mock gralloc is currently locked.
+///
+/// # Safety
+///
+/// `handle` must come from the mock gralloc and not have been freed.
+pub unsafe fn is_locked(handle: *const native_handle) -> bool {
+    (*(handle as *const MockHandle)).lock_count > 0
+}
+
+/// Stands in for libsuspend's autosuspend_enable().
+///
+/// # Safety
+///
+/// Always safe, it is only unsafe like the function it replaces.
+pub unsafe fn autosuspend_enable() {
+    AUTOSUSPEND_ENABLED.with(|e| e.set(true));
+}
+
+/// Stands in for libsuspend's autosuspend_disable().
+///
+/// # Safety
+///
+/// Always safe, it is only unsafe like the function it replaces.
+pub unsafe fn autosuspend_disable() {
+    AUTOSUSPEND_ENABLED.with(|e| e.set(false));
+}
+
+/// Stands in for libhardware's hw_get_module(), with the mock gralloc and
+/// hwcomposer modules.
+///
+/// # Safety
+///
+/// `id` must be a valid C string, and `module` valid for writes.
+pub unsafe fn hw_get_module(id: *const c_char, module: *mut *const hw_module) -> c_int {
+    match CStr::from_ptr(id).to_bytes() {
+        b"gralloc" => {
+            *module = &GRALLOC_MODULE.0.common;
+            0
+        }
+        b"hwcomposer" => {
+            *module = &HWC_MODULE.0;
+            0
+        }
+        _ => -libc::ENOENT,
+    }
+}
+
+fn device_info(
+    module: *const hw_module,
+    version: u32,
+    close: extern "C" fn(*mut hw_device) -> c_int,
+) -> hw_device {
+    hw_device {
+        tag: HARDWARE_DEVICE_TAG,
+        version,
+        module: module as *mut hw_module,
+        reserved: [0; 12],
+        close,
+    }
+}
+
+// Software gralloc
+
+static GRALLOC_METHODS: Static<hw_module_methods> = Static(hw_module_methods {
+    open: gralloc_open,
+});
+
+/// The layout of the handles handed out by the mock allocator. The pixels
+/// live in a malloc'd block, and the handle carries everything needed to
+/// lock it.
+#[repr(C)]
+struct MockHandle {
+    base: native_handle,
+    width: c_int,
+    height: c_int,
+    stride: c_int,
+    format: c_int,
+    usage: c_int,
+    size: c_int,
+    lock_count: c_int,
+    data: *mut u8,
+}
+
+const MOCK_HANDLE_INTS: c_int =
+    ((size_of::<MockHandle>() - size_of::<native_handle>()) / size_of::<c_int>()) as c_int;
+
+/// Bytes per pixel in the mock allocator. Multi-plane formats, and
+/// HAL_PIXEL_FORMAT_IMPLEMENTATION_DEFINED, get 32 bits.
+fn bytes_per_pixel(format: c_int) -> c_int {
+    PixelFormat::from_hal(format)
+        .and_then(PixelFormat::bytes_per_pixel)
+        .unwrap_or(4) as c_int
+}
+
+extern "C" fn gralloc_open(
+    module: *const hw_module,
+    name: *const c_char,
+    device: *mut *const hw_device,
+) -> c_int {
+    if unsafe { CStr::from_ptr(name) }.to_bytes() != b"gpu0" {
+        return -libc::EINVAL;
+    }
+    let dev = Box::new(alloc_device {
+        common: device_info(module, 0, gralloc_close),
+        alloc: gralloc_alloc,
+        free: gralloc_free,
+        dump: None,
+        reserved: [ptr::null_mut(); 7],
+    });
+    unsafe {
+        *device = Box::into_raw(dev) as *const hw_device;
+    }
+    OPEN_ALLOC_DEVICES.with(|n| n.set(n.get() + 1));
+    0
+}
+
+extern "C" fn gralloc_close(device: *mut hw_device) -> c_int {
+    unsafe {
+        drop(Box::from_raw(device as *mut alloc_device));
+    }
+    OPEN_ALLOC_DEVICES.with(|n| n.set(n.get() - 1));
+    0
+}
+
+extern "C" fn gralloc_alloc(
+    _dev: *mut alloc_device,
+    width: c_int,
+    height: c_int,
+    format: c_int,
+    usage: c_int,
+    handle: *mut *const native_handle,
+    stride: *mut c_int,
+) -> c_int {
+    if width <= 0 || height <= 0 {
+        return -libc::EINVAL;
+    }
+    if FAIL_ALLOCATIONS.with(|f| f.get()) {
+        return -libc::ENOMEM;
+    }
+    // Pad rows like real allocators do, so that callers can't get away
+    // with assuming stride == width.
+    let aligned = (width + 31) & !31;
+    let size = aligned * height * bytes_per_pixel(format);
+    let data = unsafe { libc::calloc(size as size_t, 1) as *mut u8 };
+    if data.is_null() {
+        return -libc::ENOMEM;
+    }
+    let mock = Box::new(MockHandle {
+        base: native_handle {
+            version: size_of::<native_handle>() as c_int,
+            num_fds: 0,
+            num_ints: MOCK_HANDLE_INTS,
+            data: [],
+        },
+        width,
+        height,
+        stride: aligned,
+        format,
+        usage,
+        size,
+        lock_count: 0,
+        data,
+    });
+    unsafe {
+        *stride = aligned;
+        *handle = Box::into_raw(mock) as *const native_handle;
+    }
+    LIVE_BUFFERS.with(|n| n.set(n.get() + 1));
+    0
+}
+
+extern "C" fn gralloc_free(_dev: *mut alloc_device, handle: *const native_handle) -> c_int {
+    if handle.is_null() {
+        return -libc::EINVAL;
+    }
+    unsafe {
+        let mock = Box::from_raw(handle as *mut MockHandle);
+        libc::free(mock.data as *mut c_void);
+    }
+    LIVE_BUFFERS.with(|n| n.set(n.get() - 1));
+    0
+}
+
+extern "C" fn gralloc_register_buffer(
+    _module: *const gralloc_module,
+    _handle: *const native_handle,
+) -> c_int {
+    REGISTERED_BUFFERS.with(|n| n.set(n.get() + 1));
+    0
+}
+
+extern "C" fn gralloc_unregister_buffer(
+    _module: *const gralloc_module,
+    _handle: *const native_handle,
+) -> c_int {
+    REGISTERED_BUFFERS.with(|n| n.set(n.get() - 1));
+    0
+}
+
+extern "C" fn gralloc_lock(
+    _module: *const gralloc_module,
+    handle: *const native_handle,
+    _usage: c_int,
+    _left: c_int,
+    _top: c_int,
+    _width: c_int,
+    _height: c_int,
+    vaddr: *mut *mut c_void,
+) -> c_int {
+    unsafe {
+        let mock = handle as *mut MockHandle;
+        (*mock).lock_count += 1;
+        *vaddr = (*mock).data as *mut c_void;
+    }
+    0
+}
+
+extern "C" fn gralloc_unlock(_module: *const gralloc_module, handle: *const native_handle) -> c_int {
+    unsafe {
+        let mock = handle as *mut MockHandle;
+        if (*mock).lock_count == 0 {
+            return -libc::EINVAL;
+        }
+        (*mock).lock_count -= 1;
+    }
+    0
+}
+
+extern "C" fn gralloc_lock_ycbcr(
+    _module: *const gralloc_module,
+    handle: *const native_handle,
+    _usage: c_int,
+    _left: c_int,
+    _top: c_int,
+    _width: c_int,
+    _height: c_int,
+    ycbcr: *mut android_ycbcr,
+) -> c_int {
+    unsafe {
+        let mock = &mut *(handle as *mut MockHandle);
+        let stride = mock.stride as usize;
+        let chroma = mock.data.add(stride * mock.height as usize);
+        let ycbcr = &mut *ycbcr;
+        ycbcr.y = mock.data as *mut c_void;
+        ycbcr.ystride = stride;
+        match mock.format {
+            // Y plane, then interleaved Cr and Cb.
+            HAL_PIXEL_FORMAT_YCRCB_420_SP | HAL_PIXEL_FORMAT_YCBCR_420_888 => {
+                ycbcr.cr = chroma as *mut c_void;
+                ycbcr.cb = chroma.add(1) as *mut c_void;
+                ycbcr.cstride = stride;
+                ycbcr.chroma_step = 2;
+            }
+            // Y plane, then Cr plane, then Cb plane.
+            HAL_PIXEL_FORMAT_YV12 => {
+                let cstride = (stride / 2 + 15) & !15;
+                ycbcr.cr = chroma as *mut c_void;
+                ycbcr.cb = chroma.add(cstride * (mock.height as usize + 1) / 2) as *mut c_void;
+                ycbcr.cstride = cstride;
+                ycbcr.chroma_step = 1;
+            }
+            _ => return -libc::EINVAL,
+        }
+        mock.lock_count += 1;
+    }
+    0
+}
+
+static GRALLOC_MODULE: Static<gralloc_module> = Static(gralloc_module {
+    common: hw_module {
+        tag: HARDWARE_MODULE_TAG,
+        module_api_version: 1,
+        hal_api_version: 0,
+        id: b"gralloc\0" as *const u8 as *const c_char,
+        name: b"Mock gralloc\0" as *const u8 as *const c_char,
+        author: b"gonk-gfx\0" as *const u8 as *const c_char,
+        methods: &GRALLOC_METHODS.0 as *const hw_module_methods as *mut hw_module_methods,
+        dso: ptr::null_mut(),
+        reserved: [0; 25],
+    },
+    register_buffer: gralloc_register_buffer,
+    unregister_buffer: gralloc_unregister_buffer,
+    lock: gralloc_lock,
+    unlock: gralloc_unlock,
+    perform: None,
+    lock_ycbcr: Some(gralloc_lock_ycbcr),
+    reserved: [ptr::null_mut(); 6],
+});
+
+// Recording hwcomposer
+
+static HWC_METHODS: Static<hw_module_methods> = Static(hw_module_methods { open: hwc_open });
+
+static HWC_MODULE: Static<hw_module> = Static(hw_module {
+    tag: HARDWARE_MODULE_TAG,
+    module_api_version: 1,
+    hal_api_version: 0,
+    id: b"hwcomposer\0" as *const u8 as *const c_char,
+    name: b"Mock hwcomposer\0" as *const u8 as *const c_char,
+    author: b"gonk-gfx\0" as *const u8 as *const c_char,
+    methods: &HWC_METHODS.0 as *const hw_module_methods as *mut hw_module_methods,
+    dso: ptr::null_mut(),
+    reserved: [0; 25],
+});
+
+/// A copy of a layer, as the mock HWC received it.
+#[derive(Clone, Debug)]
+pub struct RecordedLayer {
+    pub composition_type: i32,
+    pub flags: u32,
+    pub handle: *const native_handle,
+    pub transform: u32,
+    pub blending: i32,
+    pub source_crop: hwc_frect,
+    pub display_frame: hwc_rect,
+    pub visible_region: Vec<hwc_rect>,
+    pub acquire_fence_fd: c_int,
+    pub plane_alpha: u8,
+    pub surface_damage: Vec<hwc_rect>,
+}
+
+/// A copy of the contents submitted for one display.
+#[derive(Clone, Debug)]
+pub struct RecordedDisplay {
+    pub flags: u32,
+    pub layers: Vec<RecordedLayer>,
+}
+
+#[derive(Clone, Debug)]
+pub enum HwcCall {
+    /// One entry per display, None when no contents were passed for it.
+    Prepare(Vec<Option<RecordedDisplay>>),
+    Set(Vec<Option<RecordedDisplay>>),
+    /// On HWC 1.3 this slot is blank(), and `mode` is the blank flag.
+    SetPowerMode { display: c_int, mode: c_int },
+    EventControl {
+        display: c_int,
+        event: c_int,
+        enabled: c_int,
+    },
+    SetActiveConfig {
+        display: c_int,
+        index: c_int,
+    },
+}
+
+/// A hwc_composer_device that keeps track of what it is asked to do.
+#[repr(C)]
+pub struct MockHwcDevice {
+    base: hwc_composer_device,
+    // The configs of each physical display, none when disconnected.
+    displays: RefCell<[Vec<MockDisplayConfig>; HWC_NUM_PHYSICAL_DISPLAY_TYPES]>,
+    active_configs: Cell<[usize; HWC_NUM_PHYSICAL_DISPLAY_TYPES]>,
+    calls: RefCell<Vec<HwcCall>>,
+    procs: Cell<*const hwc_procs>,
+    vsync_enabled: Cell<[bool; HWC_NUM_DISPLAY_TYPES]>,
+    overlay_planes: Cell<usize>,
+    unknown_attribute: Cell<u32>,
+    // The signals of the retire fences handed out, if set() makes any.
+    retire_fences: RefCell<Option<Vec<FenceSignal>>>,
+    // The same for the release fences of the layers the HWC reads.
+    release_fences: RefCell<Option<Vec<FenceSignal>>>,
+}
+
+impl MockHwcDevice {
+    /// Gets back the mock behind a device opened through hw_get_module(),
+    /// e.g. with `HwcDevice::native()`.
+    ///
+    /// # Safety
+    ///
+    /// `device` must have been opened from the mock hwcomposer module and
+    /// outlive the returned reference.
+    pub unsafe fn from_native<'a>(device: *mut hwc_composer_device) -> &'a MockHwcDevice {
+        &*(device as *const MockHwcDevice)
+    }
+
+    /// Every call received so far, oldest first.
+    pub fn calls(&self) -> Vec<HwcCall> {
+        self.calls.borrow().clone()
+    }
+
+    pub fn clear_calls(&self) {
+        self.calls.borrow_mut().clear();
+    }
+
+    /// Makes prepare() accept up to `planes` layers per display as
+    /// overlays, bottom-most first. There are none by default.
+    pub fn set_overlay_planes(&self, planes: usize) {
+        self.overlay_planes.set(planes);
+    }
+
+    /// Makes get_display_attributes() fail whenever it is asked for
+    /// `attribute`, like HALs predating it do.
+    pub fn set_unknown_attribute(&self, attribute: u32) {
+        self.unknown_attribute.set(attribute);
+    }
+
+    /// Makes set() return a retire fence for each display, which signals
+    /// on the next `retire_frames()`. There are none by default.
+    pub fn enable_retire_fences(&self) {
+        self.retire_fences.borrow_mut().get_or_insert_with(Vec::new);
+    }
+
+    /// Signals the retire fences handed out so far, as if the frames they
+    /// belong to had been replaced on the displays.
+    pub fn retire_frames(&self) {
+        if let Some(ref mut signals) = *self.retire_fences.borrow_mut() {
+            for signal in signals.drain(..) {
+                // The fence may be gone already.
+                let _ = signal.signal();
+            }
+        }
+    }
+
+    /// Makes set() return a release fence for each overlay and
+    /// framebuffer target layer, which signals on the next
+    /// `release_layers()`. There are none by default.
+    pub fn enable_release_fences(&self) {
+        self.release_fences.borrow_mut().get_or_insert_with(Vec::new);
+    }
+
+    /// Signals the release fences handed out so far, as if the display
+    /// was done reading their buffers.
+    pub fn release_layers(&self) {
+        if let Some(ref mut signals) = *self.release_fences.borrow_mut() {
+            for signal in signals.drain(..) {
+                let _ = signal.signal();
+            }
+        }
+    }
+
+    /// Sends a vsync event, as long as they are enabled for `display`.
+    pub fn vsync(&self, display: c_int, timestamp: i64) {
+        let procs = self.procs.get();
+        if !procs.is_null() && self.vsync_enabled.get()[display as usize] {
+            unsafe { ((*procs).vsync)(procs, display, timestamp) };
+        }
+    }
+
+    /// Plugs in an external display, and sends the hotplug event.
+    pub fn connect_external(&self, config: MockDisplayConfig) {
+        self.hotplug(Some(config));
+    }
+
+    pub fn disconnect_external(&self) {
+        self.hotplug(None);
+    }
+
+    /// Adds a config to a connected display.
+    pub fn add_config(&self, display: c_int, config: MockDisplayConfig) {
+        let mut displays = self.displays.borrow_mut();
+        assert!(!displays[display as usize].is_empty());
+        displays[display as usize].push(config);
+    }
+
+    fn hotplug(&self, config: Option<MockDisplayConfig>) {
+        self.displays.borrow_mut()[HWC_DISPLAY_EXTERNAL as usize] = config.into_iter().collect();
+        self.set_active_config(HWC_DISPLAY_EXTERNAL, 0);
+        let procs = self.procs.get();
+        if !procs.is_null() {
+            let connected = config.is_some() as c_int;
+            unsafe { ((*procs).hotplug)(procs, HWC_DISPLAY_EXTERNAL, connected) };
+        }
+    }
+
+    fn configs(&self, display: c_int) -> Vec<MockDisplayConfig> {
+        if display < 0 || display as usize >= HWC_NUM_PHYSICAL_DISPLAY_TYPES {
+            return vec![];
+        }
+        self.displays.borrow()[display as usize].clone()
+    }
+
+    fn set_active_config(&self, display: c_int, index: usize) {
+        let mut active = self.active_configs.get();
+        active[display as usize] = index;
+        self.active_configs.set(active);
+    }
+
+    pub fn invalidate(&self) {
+        let procs = self.procs.get();
+        if !procs.is_null() {
+            unsafe { ((*procs).invalidate)(procs) };
+        }
+    }
+
+    fn record(&self, call: HwcCall) {
+        self.calls.borrow_mut().push(call);
+    }
+}
+
+unsafe fn copy_region(region: &hwc_region) -> Vec<hwc_rect> {
+    if region.rects.is_null() || region.num_rects <= 0 {
+        return vec![];
+    }
+    slice::from_raw_parts(region.rects, region.num_rects as usize).to_vec()
+}
+
+unsafe fn copy_displays(
+    num_displays: size_t,
+    displays: *mut *mut hwc_display_contents,
+) -> Vec<Option<RecordedDisplay>> {
+    slice::from_raw_parts(displays, num_displays)
+        .iter()
+        .map(|&contents| {
+            if contents.is_null() {
+                return None;
+            }
+            // Don't rely on the declared array length, only on num_hw_layers.
+            let layers = (*contents).hw_layers.as_ptr();
+            let layers = (0..(*contents).num_hw_layers)
+                .map(|i| {
+                    let layer = &*layers.add(i);
+                    RecordedLayer {
+                        composition_type: layer.composition_type,
+                        flags: layer.flags,
+                        handle: layer.handle,
+                        transform: layer.transform,
+                        blending: layer.blending,
+                        source_crop: layer.source_crop,
+                        display_frame: layer.display_frame,
+                        visible_region: copy_region(&layer.visible_region_screen),
+                        acquire_fence_fd: layer.acquire_fence_fd,
+                        plane_alpha: layer.plane_alpha,
+                        surface_damage: copy_region(&layer.surface_damage),
+                    }
+                })
+                .collect();
+            Some(RecordedDisplay {
+                flags: (*contents).flags,
+                layers,
+            })
+        })
+        .collect()
+}
+
+extern "C" fn hwc_open(
+    module: *const hw_module,
+    name: *const c_char,
+    device: *mut *const hw_device,
+) -> c_int {
+    if unsafe { CStr::from_ptr(name) }.to_bytes() != b"composer" {
+        return -libc::EINVAL;
+    }
+    let version = HWC_VERSION.with(|v| v.get());
+    let dev = Box::new(MockHwcDevice {
+        base: hwc_composer_device {
+            common: device_info(module, version, hwc_close),
+            prepare: hwc_prepare,
+            set: hwc_set,
+            event_control: hwc_event_control,
+            set_power_mode: hwc_set_power_mode,
+            query: hwc_query,
+            register_procs: hwc_register_procs,
+            dump: hwc_dump,
+            get_display_configs: hwc_get_display_configs,
+            get_display_attributes: hwc_get_display_attributes,
+            get_active_config: if version < HwcApiVersion::hwc_api_version(1, 4) {
+                None
+            } else {
+                Some(hwc_get_active_config)
+            },
+            set_active_config: if version < HwcApiVersion::hwc_api_version(1, 4) {
+                None
+            } else {
+                Some(hwc_set_active_config)
+            },
+            set_cursor_position_async: None,
+            reserved: [ptr::null_mut(); 1],
+        },
+        displays: RefCell::new([vec![PRIMARY_DISPLAY.with(|c| c.get())], vec![]]),
+        active_configs: Cell::new([0; HWC_NUM_PHYSICAL_DISPLAY_TYPES]),
+        calls: RefCell::new(vec![]),
+        procs: Cell::new(ptr::null()),
+        vsync_enabled: Cell::new([false; HWC_NUM_DISPLAY_TYPES]),
+        overlay_planes: Cell::new(0),
+        unknown_attribute: Cell::new(HWC_DISPLAY_NO_ATTRIBUTE),
+        retire_fences: RefCell::new(None),
+        release_fences: RefCell::new(None),
+    });
+    unsafe {
+        *device = Box::into_raw(dev) as *const hw_device;
+    }
+    OPEN_HWC_DEVICES.with(|n| n.set(n.get() + 1));
+    0
+}
+
+extern "C" fn hwc_close(device: *mut hw_device) -> c_int {
+    unsafe {
+        drop(Box::from_raw(device as *mut MockHwcDevice));
+    }
+    OPEN_HWC_DEVICES.with(|n| n.set(n.get() - 1));
+    0
+}
+
+extern "C" fn hwc_prepare(
+    dev: *mut hwc_composer_device,
+    num_displays: size_t,
+    displays: *mut *mut hwc_display_contents,
+) -> c_int {
+    unsafe {
+        let mock = MockHwcDevice::from_native(dev);
+        mock.record(HwcCall::Prepare(copy_displays(num_displays, displays)));
+
+        // Anything that doesn't fit on an overlay plane is left to GLES.
+        for &contents in slice::from_raw_parts(displays, num_displays) {
+            if contents.is_null() {
+                continue;
+            }
+            let layers = (*contents).hw_layers.as_mut_ptr();
+            let mut planes = mock.overlay_planes.get();
+            for i in 0..(*contents).num_hw_layers {
+                let layer = &mut *layers.add(i);
+                if planes > 0
+                    && layer.composition_type == HWC_FRAMEBUFFER
+                    && layer.flags & HWC_SKIP_LAYER == 0
+                    && !layer.handle.is_null()
+                {
+                    layer.composition_type = HWC_OVERLAY;
+                    planes -= 1;
+                }
+            }
+        }
+    }
+    0
+}
+
+extern "C" fn hwc_set(
+    dev: *mut hwc_composer_device,
+    num_displays: size_t,
+    displays: *mut *mut hwc_display_contents,
+) -> c_int {
+    unsafe {
+        let mock = MockHwcDevice::from_native(dev);
+        mock.record(HwcCall::Set(copy_displays(num_displays, displays)));
+
+        // We own the acquire fences, and signal back retirement and the
+        // release of what we read.
+        for &contents in slice::from_raw_parts(displays, num_displays) {
+            if contents.is_null() {
+                continue;
+            }
+            let layers = (*contents).hw_layers.as_mut_ptr();
+            for i in 0..(*contents).num_hw_layers {
+                let layer = &mut *layers.add(i);
+                if layer.acquire_fence_fd >= 0 {
+                    libc::close(layer.acquire_fence_fd);
+                }
+                let read = layer.composition_type == HWC_OVERLAY
+                    || layer.composition_type == HWC_FRAMEBUFFER_TARGET;
+                layer.release_fence_fd = match *mock.release_fences.borrow_mut() {
+                    Some(ref mut signals) if read => {
+                        let (fence, signal) = pipe_fence().unwrap();
+                        signals.push(signal);
+                        fence.into_raw_fd()
+                    }
+                    _ => -1,
+                };
+            }
+            (*contents).retire_fence_fd = match *mock.retire_fences.borrow_mut() {
+                Some(ref mut signals) => {
+                    let (fence, signal) = pipe_fence().unwrap();
+                    signals.push(signal);
+                    fence.into_raw_fd()
+                }
+                None => -1,
+            };
+        }
+    }
+    0
+}
+
+extern "C" fn hwc_event_control(
+    dev: *mut hwc_composer_device,
+    display: c_int,
+    event: c_int,
+    enabled: c_int,
+) -> c_int {
+    if display < 0 || display as usize >= HWC_NUM_DISPLAY_TYPES || event != HWC_EVENT_VSYNC {
+        return -libc::EINVAL;
+    }
+    unsafe {
+        let mock = MockHwcDevice::from_native(dev);
+        mock.record(HwcCall::EventControl {
+            display,
+            event,
+            enabled,
+        });
+        let mut vsync_enabled = mock.vsync_enabled.get();
+        vsync_enabled[display as usize] = enabled != 0;
+        mock.vsync_enabled.set(vsync_enabled);
+    }
+    0
+}
+
+extern "C" fn hwc_set_power_mode(dev: *mut hwc_composer_device, display: c_int, mode: c_int) -> c_int {
+    unsafe {
+        let mock = MockHwcDevice::from_native(dev);
+        mock.record(HwcCall::SetPowerMode { display, mode });
+    }
+    0
+}
+
+extern "C" fn hwc_query(_dev: *mut hwc_composer_device, _what: c_int, _value: *mut c_int) -> c_int {
+    -libc::EINVAL
+}
+
+extern "C" fn hwc_register_procs(dev: *mut hwc_composer_device, procs: *const hwc_procs) {
+    unsafe {
+        MockHwcDevice::from_native(dev).procs.set(procs);
+    }
+}
+
+extern "C" fn hwc_dump(_dev: *mut hwc_composer_device, _buff: *const c_char, _buff_len: c_int) {}
+
+// The HAL's config handles are opaque, don't make them look like indices.
+const CONFIG_ID_BASE: u32 = 100;
+
+extern "C" fn hwc_get_display_configs(
+    dev: *mut hwc_composer_device,
+    display: c_int,
+    configs: *mut u32,
+    num_configs: *mut size_t,
+) -> c_int {
+    unsafe {
+        let count = MockHwcDevice::from_native(dev).configs(display).len();
+        if count == 0 {
+            return -libc::EINVAL;
+        }
+        for i in 0..count.min(*num_configs) {
+            *configs.add(i) = CONFIG_ID_BASE + i as u32;
+        }
+        *num_configs = count;
+    }
+    0
+}
+
+extern "C" fn hwc_get_display_attributes(
+    dev: *mut hwc_composer_device,
+    display: c_int,
+    config: u32,
+    attributes: *const u32,
+    values: *mut i32,
+) -> c_int {
+    unsafe {
+        let mock = MockHwcDevice::from_native(dev);
+        let configs = mock.configs(display);
+        let config = match config
+            .checked_sub(CONFIG_ID_BASE)
+            .and_then(|i| configs.get(i as usize))
+        {
+            Some(config) => *config,
+            None => return -libc::EINVAL,
+        };
+        let mut i = 0;
+        loop {
+            let value = match *attributes.add(i) {
+                HWC_DISPLAY_NO_ATTRIBUTE => return 0,
+                attribute if attribute == mock.unknown_attribute.get() => return -libc::EINVAL,
+                HWC_DISPLAY_VSYNC_PERIOD => config.vsync_period,
+                HWC_DISPLAY_WIDTH => config.width,
+                HWC_DISPLAY_HEIGHT => config.height,
+                HWC_DISPLAY_DPI_X => config.dpi_x,
+                HWC_DISPLAY_DPI_Y => config.dpi_y,
+                _ => return -libc::EINVAL,
+            };
+            *values.add(i) = value;
+            i += 1;
+        }
+    }
+}
+
+extern "C" fn hwc_get_active_config(dev: *mut hwc_composer_device, display: c_int) -> c_int {
+    let mock = unsafe { MockHwcDevice::from_native(dev) };
+    if mock.configs(display).is_empty() {
+        return -libc::EINVAL;
+    }
+    mock.active_configs.get()[display as usize] as c_int
+}
+
+extern "C" fn hwc_set_active_config(
+    dev: *mut hwc_composer_device,
+    display: c_int,
+    index: c_int,
+) -> c_int {
+    let mock = unsafe { MockHwcDevice::from_native(dev) };
+    mock.record(HwcCall::SetActiveConfig { display, index });
+    if index < 0 || index as usize >= mock.configs(display).len() {
+        return -libc::EINVAL;
+    }
+    mock.set_active_config(display, index as usize);
+    0
+}
+
+// Fences
+
+/// The other end of a fence made by `pipe_fence`.
+pub struct FenceSignal {
+    pipe: File,
+}
+
+impl FenceSignal {
+    /// Makes the fence poll readable.
+    pub fn signal(mut self) -> io::Result<()> {
+        self.pipe.write_all(&[1])
+    }
+}
+
+/// Makes a fence out of the read end of a pipe, for hosts without
+/// sw_sync. It can be waited on, but doesn't support the sync ioctls.
+pub fn pipe_fence() -> io::Result<(Fence, FenceSignal)> {
+    let mut fds = [-1; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        let fence = Fence::from_raw(fds[0]).unwrap();
+        let pipe = File::from_raw_fd(fds[1]);
+        Ok((fence, FenceSignal { pipe }))
+    }
+}
+
+// From drivers/dma-buf/sync_debug.h
+#[repr(C)]
+struct sw_sync_create_fence_data {
+    value: u32,
+    name: [c_char; 32],
+    fence: i32,
+}
+
+const SW_SYNC_IOC_CREATE_FENCE: u32 = 0xc028_5700;
+const SW_SYNC_IOC_INC: u32 = 0x4004_5701;
+
+const SW_SYNC_PATHS: [&str; 2] = ["/sys/kernel/debug/sync/sw_sync", "/dev/sw_sync"];
+
+/// A software sync timeline, whose fences are real sync files.
+pub struct SwSyncTimeline {
+    file: File,
+}
+
+impl SwSyncTimeline {
+    /// Creates a timeline at 0. Fails on kernels without CONFIG_SW_SYNC,
+    /// or when debugfs isn't accessible.
+    pub fn new() -> io::Result<SwSyncTimeline> {
+        let mut last_err = None;
+        for path in &SW_SYNC_PATHS {
+            match OpenOptions::new().read(true).write(true).open(path) {
+                Ok(file) => return Ok(SwSyncTimeline { file }),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    /// Creates a fence that signals once the timeline reaches `value`.
+    pub fn create_fence(&self, value: u32, name: &str) -> io::Result<Fence> {
+        let mut data = sw_sync_create_fence_data {
+            value,
+            name: [0; 32],
+            fence: -1,
+        };
+        for (dst, src) in data.name.iter_mut().zip(name.bytes().take(31)) {
+            *dst = src as c_char;
+        }
+        self.ioctl(SW_SYNC_IOC_CREATE_FENCE, &mut data as *mut _ as *mut c_void)?;
+        Ok(unsafe { Fence::from_raw(data.fence) }.unwrap())
+    }
+
+    /// Advances the timeline, signalling the fences it reaches.
+    pub fn inc(&self, count: u32) -> io::Result<()> {
+        let mut count = count;
+        self.ioctl(SW_SYNC_IOC_INC, &mut count as *mut u32 as *mut c_void)
+    }
+
+    fn ioctl(&self, request: u32, arg: *mut c_void) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, arg) } < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
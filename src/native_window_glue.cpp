@@ -3,9 +3,13 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 #include <stdarg.h>
+#include <stddef.h>
 #include <stdio.h>
 #include "system/window.h"
 
+// Not declared by the window.h of older Gonk trees.
+#define GNW_SET_SURFACE_DAMAGE 20
+
 struct GonkNativeWindow
 {
     ANativeWindow window;
@@ -15,6 +19,9 @@ struct GonkNativeWindow
     int (*set_dimensions)(struct ANativeWindow *window, int w, int h);
     int (*api_connect)(struct ANativeWindow *window, int api);
     int (*api_disconnect)(struct ANativeWindow *window, int api);
+    int (*set_crop)(struct ANativeWindow *window, const android_native_rect_t *rect);
+    int (*set_surface_damage)(struct ANativeWindow *window,
+                              const android_native_rect_t *rects, size_t num_rects);
 };
 
 // Rust doesn't support implementing variadic functions, so handle that here
@@ -76,6 +83,24 @@ gnw_perform(struct ANativeWindow *window, int op, ...)
         va_end(ap);
         return gnw->api_disconnect(window, api);
     }
+    case NATIVE_WINDOW_SET_CROP:
+    {
+        const android_native_rect_t *rect;
+        va_start(ap, op);
+        rect = va_arg(ap, const android_native_rect_t *);
+        va_end(ap);
+        return gnw->set_crop(window, rect);
+    }
+    case GNW_SET_SURFACE_DAMAGE:
+    {
+        const android_native_rect_t *rects;
+        size_t num_rects;
+        va_start(ap, op);
+        rects = va_arg(ap, const android_native_rect_t *);
+        num_rects = va_arg(ap, size_t);
+        va_end(ap);
+        return gnw->set_surface_damage(window, rects, num_rects);
+    }
     default:
         printf("Unsupported GonkNativeWindow operation! %d\n", op);
         return -1;
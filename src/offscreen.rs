@@ -0,0 +1,185 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! GLES contexts without a window, for uploading textures in the
+//! background or rendering thumbnails.
+//!
+//! They only need EGL, and work as well with Mesa on a Linux host when
+//! EGL_PLATFORM=surfaceless is set.
+
+use egl::{self, EGLContext, EGLDisplay, EGLSurface};
+use egl_display::{destroy_context, has_extension, DisplayRef};
+use error::{EglStage, GfxError};
+use gleam::gl::{self, Gl};
+use std::rc::Rc;
+use window::{Window, EGL_OPENGL_ES3_BIT_KHR};
+
+/// What an offscreen context renders to when no framebuffer object is
+/// bound.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OffscreenSurface {
+    /// A pbuffer of this size.
+    Pbuffer { width: i32, height: i32 },
+    /// Nothing, only framebuffer objects can be rendered to. Needs
+    /// EGL_KHR_surfaceless_context.
+    Surfaceless,
+}
+
+/// A GLES context and its pbuffer, if any.
+///
+/// It can be made current on any thread, one at a time, which each needs
+/// its own `load_gl()`.
+pub struct OffscreenContext {
+    display: DisplayRef,
+    ctx: EGLContext,
+    surf: EGLSurface,
+    surface: OffscreenSurface,
+    gles_version: i32,
+}
+
+// EGL handles aren't tied to a thread, only being current is.
+unsafe impl Send for OffscreenContext {}
+
+impl OffscreenContext {
+    /// Creates a context with a GLES `gles_version` (2 or 3) API on the
+    /// default display, for use without any window.
+    pub fn new(surface: OffscreenSurface, gles_version: i32) -> Result<OffscreenContext, GfxError> {
+        OffscreenContext::create(egl::EGL_NO_CONTEXT, surface, gles_version)
+    }
+
+    /// Creates a context on the display of `window`, with the same GLES
+    /// version. With `share_objects`, textures, buffers and the other GL
+    /// objects of the window's context can be used from the new one too.
+    pub fn for_window(
+        window: &Window,
+        surface: OffscreenSurface,
+        share_objects: bool,
+    ) -> Result<OffscreenContext, GfxError> {
+        let share = if share_objects {
+            window.ctx
+        } else {
+            egl::EGL_NO_CONTEXT
+        };
+        OffscreenContext::create(share, surface, window.config().gles_version)
+    }
+
+    /// Creates another context on the same display, sharing GL objects
+    /// with this one.
+    pub fn share(&self, surface: OffscreenSurface) -> Result<OffscreenContext, GfxError> {
+        OffscreenContext::create(self.ctx, surface, self.gles_version)
+    }
+
+    fn create(
+        share: EGLContext,
+        surface: OffscreenSurface,
+        gles_version: i32,
+    ) -> Result<OffscreenContext, GfxError> {
+        if gles_version != 2 && gles_version != 3 {
+            return Err(GfxError::UnsupportedGlesVersion(gles_version));
+        }
+        // The same display as that of the windows.
+        let display = DisplayRef::new()?;
+        let dpy = display.get();
+        let surface_type = match surface {
+            OffscreenSurface::Pbuffer { .. } => egl::EGL_PBUFFER_BIT,
+            OffscreenSurface::Surfaceless => {
+                if !has_extension(dpy, "EGL_KHR_surfaceless_context") {
+                    return Err(GfxError::MissingExtension("EGL_KHR_surfaceless_context"));
+                }
+                0
+            }
+        };
+        let renderable_type = if gles_version == 3 {
+            EGL_OPENGL_ES3_BIT_KHR
+        } else {
+            egl::EGL_OPENGL_ES2_BIT
+        };
+        let conf_attr = [
+            egl::EGL_SURFACE_TYPE,
+            surface_type,
+            egl::EGL_RENDERABLE_TYPE,
+            renderable_type,
+            egl::EGL_RED_SIZE,
+            8,
+            egl::EGL_GREEN_SIZE,
+            8,
+            egl::EGL_BLUE_SIZE,
+            8,
+            egl::EGL_ALPHA_SIZE,
+            8,
+            egl::EGL_NONE,
+        ];
+        let config = egl::choose_config(dpy, &conf_attr, 1)
+            .ok_or_else(|| GfxError::egl(EglStage::ChooseConfig))?;
+
+        let ctx_attr = [egl::EGL_CONTEXT_CLIENT_VERSION, gles_version, egl::EGL_NONE];
+        let ctx = egl::create_context(dpy, config, share, &ctx_attr)
+            .ok_or_else(|| GfxError::egl(EglStage::CreateContext))?;
+
+        let surf = match surface {
+            OffscreenSurface::Pbuffer { width, height } => {
+                let surf_attr = [
+                    egl::EGL_WIDTH,
+                    width,
+                    egl::EGL_HEIGHT,
+                    height,
+                    egl::EGL_NONE,
+                ];
+                match egl::create_pbuffer_surface(dpy, config, &surf_attr) {
+                    Some(surf) => surf,
+                    None => {
+                        let err = GfxError::egl(EglStage::CreatePbufferSurface);
+                        egl::destroy_context(dpy, ctx);
+                        return Err(err);
+                    }
+                }
+            }
+            OffscreenSurface::Surfaceless => egl::EGL_NO_SURFACE,
+        };
+        info!(
+            "Created {:?} offscreen GLES {} context",
+            surface, gles_version
+        );
+
+        Ok(OffscreenContext {
+            display,
+            ctx,
+            surf,
+            surface,
+            gles_version,
+        })
+    }
+
+    /// Makes the context current on the calling thread. Windows need to
+    /// be made current again afterwards, see `Window::make_current()`.
+    pub fn make_current(&self) -> Result<(), GfxError> {
+        if !egl::make_current(self.display.get(), self.surf, self.surf, self.ctx) {
+            return Err(GfxError::egl(EglStage::MakeCurrent));
+        }
+        Ok(())
+    }
+
+    /// Loads the GLES functions, for the thread the context is current on.
+    pub fn load_gl(&self) -> Rc<dyn Gl> {
+        unsafe { gl::GlesFns::load_with(|s| egl::get_proc_address(s) as *const _) }
+    }
+
+    pub fn surface(&self) -> OffscreenSurface {
+        self.surface
+    }
+
+    pub fn display(&self) -> EGLDisplay {
+        self.display.get()
+    }
+
+    pub fn context(&self) -> EGLContext {
+        self.ctx
+    }
+}
+
+impl Drop for OffscreenContext {
+    fn drop(&mut self) {
+        destroy_context(self.display.get(), self.surf, self.ctx);
+    }
+}
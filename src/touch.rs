@@ -0,0 +1,313 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Touch point tracking for devices using the kernel's slot based
+//! multitouch protocol (type B, see Documentation/input/multi-touch-protocol.txt).
+
+use input::*;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use window::Window;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TouchPhase {
+    Down,
+    Move,
+    Up,
+    /// The touch point is gone, but not because the finger was lifted,
+    /// e.g. after the kernel dropped events.
+    Cancel,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TouchEvent {
+    /// Identifies a touch point from Down to Up or Cancel. Never reused.
+    pub id: u32,
+    pub phase: TouchPhase,
+    /// Position on the surface given to the tracker, see
+    /// `TouchTracker::for_window()` for windows.
+    pub x: f32,
+    pub y: f32,
+    /// Between 0.0 and 1.0, or 1.0 if the device doesn't report pressure.
+    pub pressure: f32,
+    /// Length of the major axis of the contact in pixels of that surface,
+    /// or 0.0 if the device doesn't report it.
+    pub touch_major: f32,
+    pub time: Duration,
+}
+
+/// The ranges of the axes reported by a touch device.
+#[derive(Clone, Copy, Debug)]
+pub struct TouchAxes {
+    pub x: input_absinfo,
+    pub y: input_absinfo,
+    pub pressure: Option<input_absinfo>,
+    pub touch_major: Option<input_absinfo>,
+    /// The number of slots, i.e. of simultaneous touch points.
+    pub slots: usize,
+}
+
+impl TouchAxes {
+    /// Queries the multitouch axes of an evdev device.
+    pub fn from_device<R: AsRawFd>(device: &InputDevice<R>) -> io::Result<TouchAxes> {
+        // EVIOCGABS succeeds with an empty range for unsupported axes.
+        let optional = |axis| {
+            device
+                .abs_info(axis)
+                .ok()
+                .filter(|info| info.maximum > info.minimum)
+        };
+        Ok(TouchAxes {
+            x: device.abs_info(ABS_MT_POSITION_X)?,
+            y: device.abs_info(ABS_MT_POSITION_Y)?,
+            pressure: optional(ABS_MT_PRESSURE),
+            touch_major: optional(ABS_MT_TOUCH_MAJOR),
+            slots: optional(ABS_MT_SLOT).map_or(1, |info| info.maximum as usize + 1),
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    // The state as last reported by the kernel.
+    tracking_id: i32,
+    x: i32,
+    y: i32,
+    pressure: i32,
+    touch_major: i32,
+    // Set when a new tracking id shows up before the next SYN_REPORT.
+    new_contact: bool,
+    changed: bool,
+    // Our id for the touch point, once we have sent Down for it.
+    id: Option<u32>,
+    last_x: f32,
+    last_y: f32,
+}
+
+impl Default for Slot {
+    fn default() -> Self {
+        Slot {
+            tracking_id: -1,
+            x: 0,
+            y: 0,
+            pressure: 0,
+            touch_major: 0,
+            new_contact: false,
+            changed: false,
+            id: None,
+            last_x: 0.0,
+            last_y: 0.0,
+        }
+    }
+}
+
+/// Turns the raw events of a multitouch device into per-finger touch
+/// events, scaled to the size of a window.
+pub struct TouchTracker {
+    axes: TouchAxes,
+    width: i32,
+    height: i32,
+    slots: Vec<Slot>,
+    current: usize,
+    next_id: u32,
+    // After SYN_DROPPED, events are ignored until the next SYN_REPORT.
+    dropping: bool,
+}
+
+fn scale(value: i32, info: &input_absinfo, size: i32) -> f32 {
+    let range = info.maximum - info.minimum;
+    if range <= 0 {
+        return value as f32;
+    }
+    (value - info.minimum) as f32 * size as f32 / (range + 1) as f32
+}
+
+impl TouchTracker {
+    /// Creates a tracker mapping the device axes onto a `width` x `height`
+    /// surface.
+    pub fn new(axes: TouchAxes, width: i32, height: i32) -> TouchTracker {
+        TouchTracker {
+            axes,
+            width,
+            height,
+            slots: vec![Slot::default(); axes.slots.max(1)],
+            current: 0,
+            next_id: 0,
+            dropping: false,
+        }
+    }
+
+    /// Creates a tracker for an evdev device on the window's display.
+    /// Touch screens cover the display whatever the window's size and
+    /// transform, so positions are in display pixels and have to be mapped
+    /// through `Window::from_display()`.
+    pub fn for_window<R: AsRawFd>(
+        device: &InputDevice<R>,
+        window: &Window,
+    ) -> io::Result<TouchTracker> {
+        let axes = TouchAxes::from_device(device)?;
+        let info = window.display_info();
+        Ok(TouchTracker::new(axes, info.width, info.height))
+    }
+
+    /// Feeds an event from the device, and returns the touch events that
+    /// it completes. Touch events are only produced on SYN_REPORT.
+    pub fn process(&mut self, event: &InputEvent) -> Vec<TouchEvent> {
+        match event.kind {
+            EventKind::Sync(SyncKind::Report) => {
+                if self.dropping {
+                    self.dropping = false;
+                    return vec![];
+                }
+                self.report(event.time)
+            }
+            EventKind::Sync(SyncKind::Dropped) => {
+                self.dropping = true;
+                self.cancel_all(event.time)
+            }
+            EventKind::Absolute { axis, value } if !self.dropping => {
+                self.update(axis, value);
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn update(&mut self, axis: u16, value: i32) {
+        if axis == ABS_MT_SLOT {
+            if value < 0 {
+                return;
+            }
+            self.current = value as usize;
+            return;
+        }
+
+        // Slots past the maximum the device reported are bogus, drop their
+        // events.
+        let slot = match self.slots.get_mut(self.current) {
+            Some(slot) => slot,
+            None => return,
+        };
+        match axis {
+            ABS_MT_TRACKING_ID => {
+                slot.tracking_id = value;
+                if value >= 0 {
+                    slot.new_contact = true;
+                }
+            }
+            ABS_MT_POSITION_X => slot.x = value,
+            ABS_MT_POSITION_Y => slot.y = value,
+            ABS_MT_PRESSURE => slot.pressure = value,
+            ABS_MT_TOUCH_MAJOR => slot.touch_major = value,
+            _ => return,
+        }
+        slot.changed = true;
+    }
+
+    fn report(&mut self, time: Duration) -> Vec<TouchEvent> {
+        let mut events = vec![];
+        for idx in 0..self.slots.len() {
+            let slot = self.slots[idx];
+            if !slot.changed {
+                continue;
+            }
+
+            let mut id = slot.id;
+            // The previous contact in this slot went away, possibly replaced
+            // by a new one within the same report.
+            if let Some(old) = id {
+                if slot.tracking_id < 0 || slot.new_contact {
+                    events.push(self.event(
+                        &slot,
+                        old,
+                        TouchPhase::Up,
+                        slot.last_x,
+                        slot.last_y,
+                        time,
+                    ));
+                    id = None;
+                }
+            }
+
+            let (x, y) = (
+                scale(slot.x, &self.axes.x, self.width),
+                scale(slot.y, &self.axes.y, self.height),
+            );
+            if slot.tracking_id >= 0 {
+                match id {
+                    None => {
+                        let new_id = self.next_id;
+                        self.next_id = self.next_id.wrapping_add(1);
+                        events.push(self.event(&slot, new_id, TouchPhase::Down, x, y, time));
+                        id = Some(new_id);
+                    }
+                    Some(id) => events.push(self.event(&slot, id, TouchPhase::Move, x, y, time)),
+                }
+            }
+
+            let slot = &mut self.slots[idx];
+            slot.id = id;
+            slot.last_x = x;
+            slot.last_y = y;
+            slot.changed = false;
+            slot.new_contact = false;
+        }
+        events
+    }
+
+    fn cancel_all(&mut self, time: Duration) -> Vec<TouchEvent> {
+        let events = self
+            .slots
+            .iter()
+            .filter_map(|slot| {
+                slot.id.map(|id| {
+                    self.event(slot, id, TouchPhase::Cancel, slot.last_x, slot.last_y, time)
+                })
+            })
+            .collect();
+        // We can't tell which contacts survive the overrun, so start over.
+        // Fingers still down will be picked up once they are lifted and
+        // touch again.
+        for slot in &mut self.slots {
+            *slot = Slot::default();
+        }
+        events
+    }
+
+    fn event(
+        &self,
+        slot: &Slot,
+        id: u32,
+        phase: TouchPhase,
+        x: f32,
+        y: f32,
+        time: Duration,
+    ) -> TouchEvent {
+        let pressure = match self.axes.pressure {
+            Some(ref info) => {
+                (slot.pressure - info.minimum) as f32 / (info.maximum - info.minimum) as f32
+            }
+            None => 1.0,
+        };
+        let touch_major = match self.axes.touch_major {
+            // Touch major uses the same units as the X axis.
+            Some(_) => scale(
+                slot.touch_major + self.axes.x.minimum,
+                &self.axes.x,
+                self.width,
+            ),
+            None => 0.0,
+        };
+        TouchEvent {
+            id,
+            phase,
+            x,
+            y,
+            pressure,
+            touch_major,
+            time,
+        }
+    }
+}
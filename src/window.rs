@@ -4,114 +4,652 @@
 
 //! A windowing implementation using Gonk interfaces.
 
-use egl::{self, EGLContext, EGLDisplay, EGLSurface};
-use hwc::HwcDevice;
+use compositor::Compositor;
+use egl::{self, EGLBoolean, EGLConfig, EGLContext, EGLDisplay, EGLSurface, EGLint};
+use egl_display::{destroy_context, has_extension, DisplayRef};
+use error::{EglStage, GfxError};
 use gleam::gl::{self, Gl};
 use gonk_gfx::*;
+use gralloc::PixelFormat;
+use hwc::*;
+use layers::{ComposedFrame, Composition, Layer, LayerStack};
+use libc::{self, c_int};
+use std::cell::{Cell, RefCell};
+#[cfg(not(feature = "mock-hal"))]
 use std::mem::transmute;
+use std::ptr;
 use std::rc::Rc;
 
 /// The type of a window.
 pub struct Window {
-    pub width: i32,
-    pub height: i32,
-    pub dpi: i32,
-    hwc: HwcDevice,
+    // The size of the surface, see resize().
+    width: Cell<i32>,
+    height: Cell<i32>,
+    // The display in the config the window was created with.
+    info: DisplayInfo,
+    // The HWC_TRANSFORM_* applied to the surface to show it.
+    transform: Cell<u32>,
+    config: WindowConfig,
+    pub display: c_int,
+    hwc: Rc<HwcDevice>,
     pub native_window: *mut GonkNativeWindow,
     pub dpy: EGLDisplay,
+    // Keeps EGL initialized for as long as the window is alive.
+    egl_display: DisplayRef,
     pub ctx: EGLContext,
     pub surf: EGLSurface,
     pub gl: Rc<Gl>,
+    // Created on first use by compose().
+    compositor: RefCell<Option<Compositor>>,
 }
 
-impl Window {
-    /// Creates a new window.
-    pub fn new() -> Rc<Window> {
-        let hwc = HwcDevice::new();
-        assert!(hwc.is_some(), "Failed to get the HWC device");
-        let hwc = hwc.unwrap();
+/// The EGL config, context and buffers a window ended up with, which may
+/// exceed what its `WindowBuilder` asked for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WindowConfig {
+    /// The format of the window's buffers.
+    pub format: PixelFormat,
+    pub red_size: i32,
+    pub green_size: i32,
+    pub blue_size: i32,
+    pub alpha_size: i32,
+    pub depth_size: i32,
+    pub stencil_size: i32,
+    /// The number of samples per pixel, 0 without multisampling.
+    pub samples: i32,
+    pub gles_version: i32,
+    pub debug: bool,
+    pub swap_interval: i32,
+    /// The GRALLOC_USAGE_* flags of the window's buffers.
+    pub usage: c_int,
+    pub buffer_count: usize,
+}
+
+/// Sets up a window, with a choice of display, EGL config and buffers.
+/// The defaults are those of `Window::new()`: RGBA_8888 buffers with a
+/// 24-bit depth buffer, a GLES 2 context and a swap interval of 1.
+pub struct WindowBuilder {
+    hwc: Option<Rc<HwcDevice>>,
+    display: c_int,
+    display_config: Option<usize>,
+    buffer_count: usize,
+    format: PixelFormat,
+    depth_size: i32,
+    stencil_size: i32,
+    samples: i32,
+    gles_version: i32,
+    debug: bool,
+    swap_interval: i32,
+    usage: c_int,
+}
+
+// From EGL_KHR_create_context.
+pub const EGL_OPENGL_ES3_BIT_KHR: EGLint = 0x40;
+const EGL_CONTEXT_FLAGS_KHR: EGLint = 0x30fc;
+const EGL_CONTEXT_OPENGL_DEBUG_BIT_KHR: EGLint = 0x1;
+
+// The egl crate only hands out the first matching config, when we need to
+// look for the one rendering in our buffer format.
+extern "C" {
+    fn eglChooseConfig(
+        dpy: EGLDisplay,
+        attrib_list: *const EGLint,
+        configs: *mut EGLConfig,
+        config_size: EGLint,
+        num_config: *mut EGLint,
+    ) -> EGLBoolean;
+}
+
+fn choose_configs(dpy: EGLDisplay, attrs: &[EGLint]) -> Vec<EGLConfig> {
+    let mut count = 0;
+    let mut configs = vec![];
+    unsafe {
+        if eglChooseConfig(dpy, attrs.as_ptr(), ptr::null_mut(), 0, &mut count) == egl::EGL_TRUE {
+            configs.resize(count as usize, ptr::null_mut());
+            eglChooseConfig(dpy, attrs.as_ptr(), configs.as_mut_ptr(), count, &mut count);
+            configs.truncate(count as usize);
+        }
+    }
+    configs
+}
+
+fn config_attrib(dpy: EGLDisplay, config: EGLConfig, attribute: EGLint) -> EGLint {
+    let mut value = 0;
+    egl::get_config_attrib(dpy, config, attribute, &mut value);
+    value
+}
+
+#[cfg(not(feature = "mock-hal"))]
+const SURFACE_TYPE: EGLint = egl::EGL_WINDOW_BIT;
+
+#[cfg(not(feature = "mock-hal"))]
+fn create_surface(
+    dpy: EGLDisplay,
+    config: EGLConfig,
+    native_window: *mut GonkNativeWindow,
+) -> Option<EGLSurface> {
+    unsafe { egl::create_window_surface(dpy, config, transmute(native_window), &[]) }
+}
+
+// The host's EGL can't render to a GonkNativeWindow, so with the mock HAL
+// windows render to a pbuffer, and only buffers queued by hand reach the
+// HWC.
+#[cfg(feature = "mock-hal")]
+const SURFACE_TYPE: EGLint = egl::EGL_PBUFFER_BIT;
+
+#[cfg(feature = "mock-hal")]
+fn create_surface(
+    dpy: EGLDisplay,
+    config: EGLConfig,
+    native_window: *mut GonkNativeWindow,
+) -> Option<EGLSurface> {
+    let (width, height) = unsafe { ((*native_window).width(), (*native_window).height()) };
+    let attrs = [
+        egl::EGL_WIDTH,
+        width,
+        egl::EGL_HEIGHT,
+        height,
+        egl::EGL_NONE,
+    ];
+    egl::create_pbuffer_surface(dpy, config, &attrs)
+}
+
+// The red, green, blue and alpha sizes of the formats EGL can render to.
+fn color_sizes(format: PixelFormat) -> Option<[EGLint; 4]> {
+    match format {
+        PixelFormat::RGBA_8888 | PixelFormat::BGRA_8888 => Some([8, 8, 8, 8]),
+        PixelFormat::RGBX_8888 => Some([8, 8, 8, 0]),
+        PixelFormat::RGB_565 => Some([5, 6, 5, 0]),
+        _ => None,
+    }
+}
+
+impl Default for WindowBuilder {
+    fn default() -> Self {
+        WindowBuilder {
+            hwc: None,
+            display: HWC_DISPLAY_PRIMARY,
+            display_config: None,
+            buffer_count: MIN_BUFFER_COUNT,
+            format: PixelFormat::RGBA_8888,
+            depth_size: 24,
+            stencil_size: 0,
+            samples: 0,
+            gles_version: 2,
+            debug: false,
+            swap_interval: 1,
+            usage: GRALLOC_USAGE_HW_FB | GRALLOC_USAGE_HW_RENDER | GRALLOC_USAGE_HW_COMPOSER,
+        }
+    }
+}
+
+impl WindowBuilder {
+    pub fn new() -> WindowBuilder {
+        WindowBuilder::default()
+    }
+
+    /// The HWC device to show the window with, a new one if unset.
+    /// Windows on different displays must share the same HwcDevice, see
+    /// `Window::hwc()`.
+    pub fn hwc(mut self, hwc: Rc<HwcDevice>) -> Self {
+        self.hwc = Some(hwc);
+        self
+    }
+
+    /// The physical display the window covers, the primary one by default.
+    pub fn display(mut self, display: c_int) -> Self {
+        self.display = display;
+        self
+    }
+
+    /// Switches the display to one of the configs listed by
+    /// `HwcDevice::configs()` first.
+    pub fn display_config(mut self, config: usize) -> Self {
+        self.display_config = Some(config);
+        self
+    }
+
+    /// The number of buffers the window cycles through. More buffers let
+    /// rendering run ahead of the display, at the cost of memory and
+    /// latency.
+    pub fn buffer_count(mut self, count: usize) -> Self {
+        self.buffer_count = count;
+        self
+    }
 
-        let (width, height, dpi) = hwc.get_dimensions_and_dpi();
+    /// The format of the buffers, one of RGBA_8888, RGBX_8888, BGRA_8888
+    /// or RGB_565.
+    pub fn format(mut self, format: PixelFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// The minimum size of the depth buffer in bits, 0 for none.
+    pub fn depth_size(mut self, bits: i32) -> Self {
+        self.depth_size = bits;
+        self
+    }
+
+    /// The minimum size of the stencil buffer in bits, 0 for none.
+    pub fn stencil_size(mut self, bits: i32) -> Self {
+        self.stencil_size = bits;
+        self
+    }
+
+    /// The minimum number of samples per pixel, 0 to disable multisampling.
+    pub fn samples(mut self, samples: i32) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    /// The major version of the GLES context, 2 or 3.
+    pub fn gles_version(mut self, version: i32) -> Self {
+        self.gles_version = version;
+        self
+    }
 
-        let dpy = egl::get_display(egl::EGL_DEFAULT_DISPLAY).unwrap();
+    /// Asks for a debug context, if EGL_KHR_create_context is supported.
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
 
-        let mut major: i32 = 0;
-        let mut minor: i32 = 0;
-        let ret = { egl::initialize(dpy, &mut major, &mut minor) };
+    /// The number of vsyncs each swap waits for, 0 not to wait. Clamped to
+    /// what the EGL config supports.
+    pub fn swap_interval(mut self, interval: i32) -> Self {
+        self.swap_interval = interval;
+        self
+    }
 
-        assert!(ret, "Failed to initialize EGL!");
+    /// The GRALLOC_USAGE_* flags of the buffers. GRALLOC_USAGE_HW_RENDER
+    /// is always added, for EGL to render into them.
+    pub fn usage(mut self, usage: c_int) -> Self {
+        self.usage = usage;
+        self
+    }
 
-        info!("EGL initialized {}.{}", major, minor);
+    pub fn build(self) -> Result<Rc<Window>, GfxError> {
+        let color_sizes =
+            color_sizes(self.format).ok_or(GfxError::UnsupportedFormat(self.format.to_hal()))?;
+        if self.gles_version != 2 && self.gles_version != 3 {
+            return Err(GfxError::UnsupportedGlesVersion(self.gles_version));
+        }
+        let hwc = match self.hwc {
+            Some(hwc) => hwc,
+            None => Rc::new(HwcDevice::new()?),
+        };
+        let egl_display = DisplayRef::new()?;
+        let dpy = egl_display.get();
+
+        let display = self.display;
+        let mut partial = PartialWindow {
+            hwc: hwc.clone(),
+            display,
+            previous_config: None,
+            dpy,
+            native_window: ptr::null_mut(),
+            surf: egl::EGL_NO_SURFACE,
+            ctx: egl::EGL_NO_CONTEXT,
+        };
+        if let Some(config) = self.display_config {
+            let previous = hwc.active_config(display)?;
+            hwc.set_active_config(display, config)?;
+            partial.previous_config = Some(previous);
+        }
+        let info = hwc.display_info(display)?;
+        let (width, height) = (info.width, info.height);
 
+        let renderable_type = if self.gles_version == 3 {
+            EGL_OPENGL_ES3_BIT_KHR
+        } else {
+            egl::EGL_OPENGL_ES2_BIT
+        };
         let conf_attr = [
             egl::EGL_SURFACE_TYPE,
-            egl::EGL_WINDOW_BIT,
+            SURFACE_TYPE,
             egl::EGL_RENDERABLE_TYPE,
-            egl::EGL_OPENGL_ES2_BIT,
+            renderable_type,
             egl::EGL_RED_SIZE,
-            8,
+            color_sizes[0],
             egl::EGL_GREEN_SIZE,
-            8,
+            color_sizes[1],
             egl::EGL_BLUE_SIZE,
-            8,
+            color_sizes[2],
             egl::EGL_ALPHA_SIZE,
-            8,
+            color_sizes[3],
             egl::EGL_DEPTH_SIZE,
-            24,
+            self.depth_size,
+            egl::EGL_STENCIL_SIZE,
+            self.stencil_size,
+            egl::EGL_SAMPLE_BUFFERS,
+            (self.samples > 0) as EGLint,
+            egl::EGL_SAMPLES,
+            self.samples,
             egl::EGL_NONE,
         ];
 
-        let config = egl::choose_config(dpy, &conf_attr, 1);
-
-        assert!(config.is_some(), "Failed to choose a config");
-        let config = config.unwrap();
-
-        info!("Creating {}x{} native window", width, height);
+        // Configs with more color bits come first, prefer one rendering
+        // in the format we asked for.
+        let configs = choose_configs(dpy, &conf_attr);
+        let wanted = self.format.to_hal();
+        let config = *configs
+            .iter()
+            .find(|&&config| config_attrib(dpy, config, egl::EGL_NATIVE_VISUAL_ID) == wanted)
+            .or_else(|| configs.first())
+            .ok_or_else(|| GfxError::egl(EglStage::ChooseConfig))?;
 
-        let usage = GRALLOC_USAGE_HW_FB | GRALLOC_USAGE_HW_RENDER | GRALLOC_USAGE_HW_COMPOSER;
-        let native_window = GonkNativeWindow::new(hwc.native(), width, height, usage);
+        info!(
+            "Creating {}x{} native window on display {}",
+            width, height, display
+        );
 
-        let surf =
-            unsafe { egl::create_window_surface(dpy, config, transmute(native_window), &[]) };
+        // Allocate buffers in the format the EGL config renders to.
+        let visual_id = config_attrib(dpy, config, egl::EGL_NATIVE_VISUAL_ID);
+        let format = PixelFormat::from_hal(visual_id).unwrap_or(self.format);
 
-        assert!(surf.is_some());
-        let surf = surf.unwrap();
+        let usage = self.usage | GRALLOC_USAGE_HW_RENDER;
+        let native_window = GonkNativeWindow::new(
+            hwc.clone(),
+            display,
+            width,
+            height,
+            format,
+            usage,
+            self.buffer_count,
+        )?;
+        partial.native_window = native_window;
 
-        let ctx_attr = [egl::EGL_CONTEXT_CLIENT_VERSION, 2, egl::EGL_NONE];
+        let surf = create_surface(dpy, config, native_window)
+            .ok_or_else(|| GfxError::egl(EglStage::CreateWindowSurface))?;
+        partial.surf = surf;
 
-        let ctx = egl::create_context(dpy, config, egl::EGL_NO_CONTEXT, &ctx_attr);
+        let debug = self.debug && has_extension(dpy, "EGL_KHR_create_context");
+        if self.debug && !debug {
+            warn!("EGL_KHR_create_context is not supported, no debug context");
+        }
+        let mut ctx_attr = vec![egl::EGL_CONTEXT_CLIENT_VERSION, self.gles_version];
+        if debug {
+            ctx_attr.extend(&[EGL_CONTEXT_FLAGS_KHR, EGL_CONTEXT_OPENGL_DEBUG_BIT_KHR]);
+        }
+        ctx_attr.push(egl::EGL_NONE);
 
-        assert!(ctx.is_some(), "Failed to create a context!");
-        let ctx = ctx.unwrap();
+        let ctx = egl::create_context(dpy, config, egl::EGL_NO_CONTEXT, &ctx_attr)
+            .ok_or_else(|| GfxError::egl(EglStage::CreateContext))?;
+        partial.ctx = ctx;
 
         let ret = egl::make_current(dpy, surf, surf, ctx);
-        assert!(ret, "Failed to make current!");
+        if !ret {
+            error!("Failed to make current!");
+            return Err(GfxError::egl(EglStage::MakeCurrent));
+        }
 
-        egl::swap_interval(dpy, 1);
+        let swap_interval = self
+            .swap_interval
+            .max(config_attrib(dpy, config, egl::EGL_MIN_SWAP_INTERVAL))
+            .min(config_attrib(dpy, config, egl::EGL_MAX_SWAP_INTERVAL));
+        egl::swap_interval(dpy, swap_interval);
 
         unsafe {
-            (*native_window).alloc_buffers();
+            (*native_window).alloc_buffers()?;
         }
-        hwc.set_display(true);
+        // Nothing fails from here on, the window takes over.
+        partial.disarm();
+        hwc.set_display_on(display, true);
 
         let gl = unsafe { gl::GlesFns::load_with(|s| egl::get_proc_address(s) as *const _) };
 
         gl.viewport(0, 0, width, height);
 
+        let mut gles_version = self.gles_version;
+        egl::query_context(dpy, ctx, egl::EGL_CONTEXT_CLIENT_VERSION, &mut gles_version);
+        let config = WindowConfig {
+            format,
+            red_size: config_attrib(dpy, config, egl::EGL_RED_SIZE),
+            green_size: config_attrib(dpy, config, egl::EGL_GREEN_SIZE),
+            blue_size: config_attrib(dpy, config, egl::EGL_BLUE_SIZE),
+            alpha_size: config_attrib(dpy, config, egl::EGL_ALPHA_SIZE),
+            depth_size: config_attrib(dpy, config, egl::EGL_DEPTH_SIZE),
+            stencil_size: config_attrib(dpy, config, egl::EGL_STENCIL_SIZE),
+            samples: config_attrib(dpy, config, egl::EGL_SAMPLES),
+            gles_version,
+            debug,
+            swap_interval,
+            usage,
+            buffer_count: self.buffer_count,
+        };
+        info!("Window config: {:?}", config);
+
         // Create our window object.
         let window = Window {
-            width,
-            height,
-            dpi,
+            width: Cell::new(width),
+            height: Cell::new(height),
+            info,
+            transform: Cell::new(0),
+            config,
+            display,
             hwc,
             native_window,
             dpy,
+            egl_display,
             ctx,
             surf,
             gl,
+            compositor: RefCell::new(None),
         };
 
-        Rc::new(window)
+        Ok(Rc::new(window))
+    }
+}
+
+// What build() set up so far, undone if it fails before the window is
+// complete.
+struct PartialWindow {
+    hwc: Rc<HwcDevice>,
+    display: c_int,
+    // The display config to go back to.
+    previous_config: Option<usize>,
+    dpy: EGLDisplay,
+    native_window: *mut GonkNativeWindow,
+    surf: EGLSurface,
+    ctx: EGLContext,
+}
+
+impl PartialWindow {
+    // Leaves everything to the window, keeping only the device reference
+    // to drop.
+    fn disarm(mut self) {
+        self.previous_config = None;
+        self.native_window = ptr::null_mut();
+        self.surf = egl::EGL_NO_SURFACE;
+        self.ctx = egl::EGL_NO_CONTEXT;
+    }
+}
+
+impl Drop for PartialWindow {
+    fn drop(&mut self) {
+        if self.ctx != egl::EGL_NO_CONTEXT {
+            destroy_context(self.dpy, self.surf, self.ctx);
+        } else if self.surf != egl::EGL_NO_SURFACE && !egl::destroy_surface(self.dpy, self.surf) {
+            error!("Failed to destroy EGL surface: 0x{:x}", egl::get_error());
+        }
+        // Without the surface, ours is the last reference, and the
+        // buffers go along with the native window.
+        if !self.native_window.is_null() {
+            unsafe {
+                let common = &mut (*self.native_window).window.common;
+                (common.dec_ref)(common);
+            }
+        }
+        if let Some(config) = self.previous_config {
+            if let Err(err) = self.hwc.set_active_config(self.display, config) {
+                error!("Failed to restore the display config: {}", err);
+            }
+        }
+    }
+}
+
+impl Window {
+    /// Creates a new window on the primary display.
+    pub fn new() -> Result<Rc<Window>, GfxError> {
+        WindowBuilder::new().build()
+    }
+
+    /// Creates a new window covering a physical display. Windows on
+    /// different displays must share the same HwcDevice, see `Window::hwc()`.
+    pub fn new_on_display(hwc: Rc<HwcDevice>, display: c_int) -> Result<Rc<Window>, GfxError> {
+        WindowBuilder::new().hwc(hwc).display(display).build()
+    }
+
+    /// Creates a new window covering a physical display, cycling through
+    /// `buffer_count` buffers, see `WindowBuilder::buffer_count()`.
+    pub fn new_with_buffer_count(
+        hwc: Rc<HwcDevice>,
+        display: c_int,
+        buffer_count: usize,
+    ) -> Result<Rc<Window>, GfxError> {
+        WindowBuilder::new()
+            .hwc(hwc)
+            .display(display)
+            .buffer_count(buffer_count)
+            .build()
+    }
+
+    /// Creates a new window covering a physical display, after switching
+    /// the display to one of the configs listed by `HwcDevice::configs()`.
+    pub fn new_with_config(
+        hwc: Rc<HwcDevice>,
+        display: c_int,
+        config: usize,
+    ) -> Result<Rc<Window>, GfxError> {
+        WindowBuilder::new()
+            .hwc(hwc)
+            .display(display)
+            .display_config(config)
+            .build()
+    }
+
+    /// Makes the window's context current on the calling thread again,
+    /// e.g. after using an `OffscreenContext` on it.
+    pub fn make_current(&self) -> Result<(), GfxError> {
+        if !egl::make_current(self.dpy, self.surf, self.surf, self.ctx) {
+            return Err(GfxError::egl(EglStage::MakeCurrent));
+        }
+        Ok(())
+    }
+
+    /// What the window was actually set up with.
+    pub fn config(&self) -> WindowConfig {
+        self.config
+    }
+
+    /// The HWC device driving the window's display, e.g. to listen for
+    /// vsync events or to create a window on another display.
+    pub fn hwc(&self) -> &Rc<HwcDevice> {
+        &self.hwc
+    }
+
+    /// The size, density and refresh rate of the window's display.
+    pub fn display_info(&self) -> DisplayInfo {
+        self.info
+    }
+
+    /// The width of the surface, in pixels.
+    pub fn width(&self) -> i32 {
+        self.width.get()
+    }
+
+    /// The height of the surface, in pixels.
+    pub fn height(&self) -> i32 {
+        self.height.get()
+    }
+
+    /// Changes the size of the surface, from the next swap on. The HWC
+    /// scales it to cover the display, so a smaller surface trades
+    /// resolution for GPU time.
+    pub fn resize(&self, width: i32, height: i32) -> Result<(), GfxError> {
+        // 0x0 would go back to the size of the display, without updating
+        // ours.
+        let ret = if width > 0 && height > 0 {
+            unsafe {
+                let window = &mut (*self.native_window).window;
+                (window.perform)(window, NATIVE_WINDOW_SET_BUFFERS_DIMENSIONS, width, height)
+            }
+        } else {
+            -libc::EINVAL
+        };
+        if ret != 0 {
+            return Err(GfxError::NativeWindow {
+                operation: NATIVE_WINDOW_SET_BUFFERS_DIMENSIONS,
+                code: ret,
+            });
+        }
+        self.width.set(width);
+        self.height.set(height);
+        self.gl.viewport(0, 0, width, height);
+        Ok(())
+    }
+
+    /// Rotates and flips the surface on the display with a combination of
+    /// the HWC_TRANSFORM_* flags, e.g. HWC_TRANSFORM_ROT_90 to render in
+    /// landscape on a portrait panel. The width and height of the surface
+    /// are swapped when the rotation changes by 90 degrees.
+    pub fn set_transform(&self, transform: u32) -> Result<(), GfxError> {
+        let error = |code| GfxError::NativeWindow {
+            operation: NATIVE_WINDOW_SET_BUFFERS_TRANSFORM,
+            code,
+        };
+        if transform & !HWC_TRANSFORM_ROT_270 != 0 {
+            return Err(error(-libc::EINVAL));
+        }
+        let set_buffers_transform = |transform: u32| unsafe {
+            let window = &mut (*self.native_window).window;
+            (window.perform)(
+                window,
+                NATIVE_WINDOW_SET_BUFFERS_TRANSFORM,
+                transform as c_int,
+            )
+        };
+        let ret = set_buffers_transform(transform);
+        if ret != 0 {
+            return Err(error(ret));
+        }
+        if (self.transform() ^ transform) & HWC_TRANSFORM_ROT_90 != 0 {
+            if let Err(err) = self.resize(self.height(), self.width()) {
+                // Keep the transform in line with the size.
+                set_buffers_transform(self.transform());
+                return Err(err);
+            }
+        }
+        self.transform.set(transform);
+        unsafe {
+            (*self.native_window).set_transform_hint(transform);
+        }
+        Ok(())
+    }
+
+    pub fn transform(&self) -> u32 {
+        self.transform.get()
+    }
+
+    /// Maps a point of the surface to the display, e.g. to find where
+    /// something drawn at (x, y) shows up.
+    pub fn to_display(&self, x: f32, y: f32) -> (f32, f32) {
+        let (x, y) = transform_point(
+            self.transform(),
+            x / self.width() as f32,
+            y / self.height() as f32,
+        );
+        (x * self.info.width as f32, y * self.info.height as f32)
+    }
+
+    /// Maps a point of the display to the surface, e.g. for touch events.
+    pub fn from_display(&self, x: f32, y: f32) -> (f32, f32) {
+        let (x, y) = inverse_transform_point(
+            self.transform(),
+            x / self.info.width as f32,
+            y / self.info.height as f32,
+        );
+        (x * self.width() as f32, y * self.height() as f32)
     }
 
     pub fn fill_color(&self, r: f32, g: f32, b: f32, a: f32) {
@@ -119,14 +657,156 @@ impl Window {
         self.gl.clear(gl::COLOR_BUFFER_BIT);
         egl::swap_buffers(self.dpy, self.surf);
     }
+
+    /// Shows what was drawn since the last swap. Returns the id of the
+    /// new frame, see `frame_timings()`.
+    pub fn swap_buffers(&self) -> Result<u64, GfxError> {
+        self.swap_buffers_with_damage(&[])
+    }
+
+    /// Like eglSwapBuffersWithDamageKHR(), shows what was drawn since the
+    /// last swap and tells the display which parts of the window changed,
+    /// in window coordinates. HWC 1.5 panels updated on command can then
+    /// refresh only those parts.
+    pub fn swap_buffers_with_damage(&self, rects: &[hwc_rect]) -> Result<u64, GfxError> {
+        // Gonk's EGL may not implement EGL_KHR_swap_buffers_with_damage, so
+        // hand the damage to the native window ourselves, with EGL's bottom
+        // left origin.
+        let rects: Vec<android_native_rect> = rects
+            .iter()
+            .map(|rect| android_native_rect {
+                left: rect.left,
+                top: self.height() - rect.top,
+                right: rect.right,
+                bottom: self.height() - rect.bottom,
+            })
+            .collect();
+        let ret = unsafe {
+            let window = &mut (*self.native_window).window;
+            (window.perform)(
+                window,
+                NATIVE_WINDOW_SET_SURFACE_DAMAGE,
+                rects.as_ptr(),
+                rects.len(),
+            )
+        };
+        if ret != 0 {
+            return Err(GfxError::NativeWindow {
+                operation: NATIVE_WINDOW_SET_SURFACE_DAMAGE,
+                code: ret,
+            });
+        }
+        if !egl::swap_buffers(self.dpy, self.surf) {
+            return Err(GfxError::egl(EglStage::SwapBuffers));
+        }
+        Ok(self.last_frame())
+    }
+
+    /// The id of the last frame shown, by a swap or by `compose()`.
+    pub fn last_frame(&self) -> u64 {
+        unsafe { (*self.native_window).last_frame() }
+    }
+
+    /// The timings of the frames shown since the last call, oldest first,
+    /// stopping at the first one that is still on the display. The retire
+    /// time of a frame is when the next one was presented: the latency of
+    /// a frame is the retire time of the previous one minus its queue time,
+    /// and retire times more than a vsync period apart reveal dropped
+    /// frames.
+    pub fn frame_timings(&self) -> Vec<FrameTiming> {
+        unsafe { (*self.native_window).frame_timings() }
+    }
+
+    /// Shows the layers of `stack` on the window's display. The HWC picks
+    /// the layers it can put on overlay planes, and the others are drawn
+    /// with GLES into the window's surface, which is then shown below,
+    /// above or between them as the HWC sees fit. Returns the id of the
+    /// frame, and how each layer of the stack was composited.
+    pub fn compose(&self, stack: &LayerStack) -> Result<ComposedFrame, GfxError> {
+        let contents = stack.display_contents(hwc_rect {
+            left: 0,
+            top: 0,
+            right: self.info.width,
+            bottom: self.info.height,
+        });
+        let composition: Vec<Composition> = {
+            let contents = unsafe { (*self.native_window).prepare(contents)? };
+            contents.layers()[..stack.len()]
+                .iter()
+                .map(|layer| Composition::from_hwc(layer.composition_type))
+                .collect()
+        };
+
+        if let Err(err) = self.draw_gles_layers(stack, &composition) {
+            unsafe { (*self.native_window).discard_prepared() };
+            return Err(err);
+        }
+        if !egl::swap_buffers(self.dpy, self.surf) {
+            unsafe { (*self.native_window).discard_prepared() };
+            return Err(GfxError::egl(EglStage::SwapBuffers));
+        }
+        let mut layer_release = unsafe { (*self.native_window).take_layer_release_fences() };
+        layer_release.resize_with(stack.len(), || None);
+        Ok(ComposedFrame {
+            frame: self.last_frame(),
+            composition,
+            layer_release,
+        })
+    }
+
+    fn draw_gles_layers(
+        &self,
+        stack: &LayerStack,
+        composition: &[Composition],
+    ) -> Result<(), GfxError> {
+        // Let the overlays below show through.
+        self.gl.clear_color(0.0, 0.0, 0.0, 0.0);
+        self.gl.clear(gl::COLOR_BUFFER_BIT);
+
+        let mut compositor = self.compositor.borrow_mut();
+        let gles_layers: Vec<&Layer> = stack
+            .layers()
+            .iter()
+            .zip(composition)
+            .filter(|&(_, &composition)| composition == Composition::Gles)
+            .map(|(layer, _)| layer)
+            .collect();
+        for layer in &gles_layers {
+            if compositor.is_none() {
+                *compositor = Some(Compositor::new(&*self.gl)?);
+            }
+            compositor.as_mut().unwrap().draw(
+                &*self.gl,
+                self.dpy,
+                layer,
+                self.info.width,
+                self.info.height,
+                self.transform(),
+            )?;
+        }
+        // Keeps the images of the buffers likely to be drawn again.
+        if let Some(ref mut compositor) = *compositor {
+            compositor.retain_images(&gles_layers);
+        }
+        Ok(())
+    }
 }
 
 impl Drop for Window {
     fn drop(&mut self) {
         info!("Dropping Window");
+        // The compositor's GL objects go along with the context, but its
+        // images need the display.
+        self.compositor.borrow_mut().take();
+        // The display scans out the front buffer until it's off.
+        self.hwc.set_display_on(self.display, false);
+        destroy_context(self.egl_display.get(), self.surf, self.ctx);
+        // EGL let go of its references on the native window with the
+        // surface, so this frees the buffers.
         unsafe {
             ((*self.native_window).window.common.dec_ref)(&mut (*self.native_window).window.common);
         }
-        self.hwc.set_display(false);
+        // EGL is terminated and the HWC device closed along with the last
+        // window or offscreen context using them.
     }
 }
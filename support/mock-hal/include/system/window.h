@@ -0,0 +1,85 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/*
+ * Minimal stand-in for system/core/include/system/window.h, used to build
+ * the native window glue on hosts without a Gonk tree (mock-hal feature).
+ * Only the parts used by native_window_glue.cpp are declared here, and the
+ * layouts must match the ones in src/gonk_gfx.rs.
+ */
+
+#ifndef GONK_GFX_MOCK_SYSTEM_WINDOW_H
+#define GONK_GFX_MOCK_SYSTEM_WINDOW_H
+
+#include <stdint.h>
+
+typedef struct android_native_base_t
+{
+    int magic;
+    int version;
+    void *reserved[4];
+    void (*incRef)(struct android_native_base_t *base);
+    void (*decRef)(struct android_native_base_t *base);
+} android_native_base_t;
+
+typedef struct android_native_rect_t
+{
+    int32_t left;
+    int32_t top;
+    int32_t right;
+    int32_t bottom;
+} android_native_rect_t;
+
+struct ANativeWindowBuffer;
+
+struct ANativeWindow
+{
+    struct android_native_base_t common;
+    uint32_t flags;
+    int minSwapInterval;
+    int maxSwapInterval;
+    float xdpi;
+    float ydpi;
+    intptr_t oem[4];
+    int (*setSwapInterval)(struct ANativeWindow *window, int interval);
+    void *dequeueBuffer_DEPRECATED;
+    void *lockBuffer_DEPRECATED;
+    void *queueBuffer_DEPRECATED;
+    int (*query)(const struct ANativeWindow *window, int what, int *value);
+    int (*perform)(struct ANativeWindow *window, int operation, ...);
+    void *cancelBuffer_DEPRECATED;
+    int (*dequeueBuffer)(struct ANativeWindow *window,
+                         struct ANativeWindowBuffer **buffer, int *fenceFd);
+    int (*queueBuffer)(struct ANativeWindow *window,
+                       struct ANativeWindowBuffer *buffer, int fenceFd);
+    int (*cancelBuffer)(struct ANativeWindow *window,
+                        struct ANativeWindowBuffer *buffer, int fenceFd);
+};
+
+enum
+{
+    NATIVE_WINDOW_SET_USAGE = 0,
+    NATIVE_WINDOW_CONNECT = 1,
+    NATIVE_WINDOW_DISCONNECT = 2,
+    NATIVE_WINDOW_SET_CROP = 3,
+    NATIVE_WINDOW_SET_BUFFER_COUNT = 4,
+    NATIVE_WINDOW_SET_BUFFERS_GEOMETRY = 5,
+    NATIVE_WINDOW_SET_BUFFERS_TRANSFORM = 6,
+    NATIVE_WINDOW_SET_BUFFERS_TIMESTAMP = 7,
+    NATIVE_WINDOW_SET_BUFFERS_DIMENSIONS = 8,
+    NATIVE_WINDOW_SET_BUFFERS_FORMAT = 9,
+    NATIVE_WINDOW_SET_SCALING_MODE = 10,
+    NATIVE_WINDOW_LOCK = 11,
+    NATIVE_WINDOW_UNLOCK_AND_POST = 12,
+    NATIVE_WINDOW_API_CONNECT = 13,
+    NATIVE_WINDOW_API_DISCONNECT = 14,
+    NATIVE_WINDOW_SET_BUFFERS_USER_DIMENSIONS = 15,
+    NATIVE_WINDOW_SET_POST_TRANSFORM_CROP = 16,
+    NATIVE_WINDOW_SET_BUFFERS_STICKY_TRANSFORM = 17,
+    NATIVE_WINDOW_SET_SIDEBAND_STREAM = 18,
+    NATIVE_WINDOW_SET_BUFFERS_DATASPACE = 19,
+    NATIVE_WINDOW_SET_SURFACE_DAMAGE = 20,
+};
+
+#endif
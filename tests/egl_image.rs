@@ -0,0 +1,76 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Run with `cargo test --features mock-hal`. Mesa can't import Android
+// native buffers, which only leaves the failure paths to test on a host.
+#![cfg(feature = "mock-hal")]
+
+extern crate gleam;
+extern crate gonk_gfx;
+
+use gleam::gl;
+use gonk_gfx::egl_image::BufferTexture;
+use gonk_gfx::error::{EglStage, GfxError};
+use gonk_gfx::gonk_gfx::GRALLOC_USAGE_HW_TEXTURE;
+use gonk_gfx::gralloc::*;
+use gonk_gfx::mock_hal;
+use gonk_gfx::offscreen::{OffscreenContext, OffscreenSurface};
+use std::env;
+
+const USAGE: i32 = GRALLOC_USAGE_HW_TEXTURE;
+
+fn context() -> OffscreenContext {
+    env::set_var("EGL_PLATFORM", "surfaceless");
+    let ctx = OffscreenContext::new(
+        OffscreenSurface::Pbuffer {
+            width: 1,
+            height: 1,
+        },
+        2,
+    )
+    .unwrap();
+    ctx.make_current().unwrap();
+    ctx
+}
+
+#[test]
+fn yuv_buffers_need_external_textures() {
+    let ctx = context();
+    let gralloc = get_gralloc_module().unwrap();
+    let buffer = GrallocBuffer::new(gralloc, 16, 16, PixelFormat::NV21, USAGE).unwrap();
+
+    match BufferTexture::new(ctx.load_gl(), buffer, gl::TEXTURE_2D) {
+        Err(GfxError::UnsupportedFormat(format)) => assert_eq!(format, PixelFormat::NV21.to_hal()),
+        _ => panic!("NV21 buffers can't be bound to GL_TEXTURE_2D"),
+    }
+    assert_eq!(mock_hal::live_buffers(), 0);
+}
+
+#[test]
+fn other_texture_targets_are_refused() {
+    let ctx = context();
+    let gralloc = get_gralloc_module().unwrap();
+    let buffer = GrallocBuffer::new(gralloc, 16, 16, PixelFormat::RGBA_8888, USAGE).unwrap();
+
+    match BufferTexture::new(ctx.load_gl(), buffer, gl::TEXTURE_CUBE_MAP) {
+        Err(GfxError::UnsupportedTextureTarget(target)) => {
+            assert_eq!(target, gl::TEXTURE_CUBE_MAP)
+        }
+        _ => panic!("Buffers can't be bound to GL_TEXTURE_CUBE_MAP"),
+    }
+    assert_eq!(mock_hal::live_buffers(), 0);
+}
+
+#[test]
+fn buffer_is_freed_when_the_image_fails() {
+    let ctx = context();
+    let gralloc = get_gralloc_module().unwrap();
+    let buffer = GrallocBuffer::new(gralloc, 16, 16, PixelFormat::RGBA_8888, USAGE).unwrap();
+
+    match BufferTexture::new(ctx.load_gl(), buffer, gl::TEXTURE_EXTERNAL_OES) {
+        Err(GfxError::Egl { stage, .. }) => assert_eq!(stage, EglStage::CreateImage),
+        _ => panic!("Mesa doesn't support EGL_ANDROID_image_native_buffer"),
+    }
+    assert_eq!(mock_hal::live_buffers(), 0);
+}
@@ -0,0 +1,45 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Run with `cargo test --features mock-hal`. The EGL display is shared by
+// the whole process, so this gets a test binary of its own.
+#![cfg(feature = "mock-hal")]
+
+extern crate egl;
+extern crate gonk_gfx;
+
+use gonk_gfx::offscreen::{OffscreenContext, OffscreenSurface};
+use std::env;
+
+const PBUFFER: OffscreenSurface = OffscreenSurface::Pbuffer {
+    width: 1,
+    height: 1,
+};
+
+fn initialized(dpy: egl::EGLDisplay) -> bool {
+    egl::query_string(dpy, egl::EGL_VENDOR).is_some()
+}
+
+#[test]
+fn egl_is_released_with_the_last_context() {
+    env::set_var("EGL_PLATFORM", "surfaceless");
+    let first = OffscreenContext::new(PBUFFER, 2).unwrap();
+    let second = first.share(PBUFFER).unwrap();
+    let dpy = first.display();
+    first.make_current().unwrap();
+
+    drop(first);
+    assert_eq!(egl::get_current_context(), None);
+    assert!(initialized(dpy));
+
+    second.make_current().unwrap();
+    drop(second);
+    assert_eq!(egl::get_current_context(), None);
+    assert!(!initialized(dpy));
+
+    // And it comes back for the next one.
+    let third = OffscreenContext::new(PBUFFER, 2).unwrap();
+    third.make_current().unwrap();
+    assert!(initialized(dpy));
+}
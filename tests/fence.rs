@@ -0,0 +1,73 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Run with `cargo test --features mock-hal`.
+#![cfg(feature = "mock-hal")]
+
+extern crate gonk_gfx;
+
+use gonk_gfx::mock_hal::{self, SwSyncTimeline};
+use std::time::Duration;
+
+#[test]
+fn wait_times_out_until_signalled() {
+    let (fence, signal) = mock_hal::pipe_fence().unwrap();
+    assert!(!fence.wait(Some(Duration::from_millis(0))).unwrap());
+    assert!(!fence.wait(Some(Duration::from_millis(10))).unwrap());
+
+    signal.signal().unwrap();
+    assert!(fence.wait(Some(Duration::from_millis(0))).unwrap());
+    assert!(fence.wait(None).unwrap());
+}
+
+#[test]
+fn fence_is_closed_on_drop() {
+    let (fence, signal) = mock_hal::pipe_fence().unwrap();
+    drop(fence);
+    // Nobody is left to read the pipe.
+    assert!(signal.signal().is_err());
+}
+
+#[test]
+fn sync_ioctls_fail_on_other_fds() {
+    let (fence, _signal) = mock_hal::pipe_fence().unwrap();
+    let (other, _other_signal) = mock_hal::pipe_fence().unwrap();
+    assert!(fence.merge(&other, "merged").is_err());
+    assert!(fence.signal_time().is_err());
+}
+
+// The remaining tests need a kernel with CONFIG_SW_SYNC and access to
+// debugfs, run them with `cargo test --features mock-hal -- --ignored`.
+fn timeline() -> SwSyncTimeline {
+    SwSyncTimeline::new().expect("sw_sync is not available")
+}
+
+#[test]
+#[ignore = "needs sw_sync"]
+fn signal_time_is_reported_once_signalled() {
+    let timeline = timeline();
+    let fence = timeline.create_fence(1, "frame").unwrap();
+    assert_eq!(fence.signal_time().unwrap(), None);
+
+    timeline.inc(1).unwrap();
+    assert!(fence.wait(Some(Duration::from_millis(0))).unwrap());
+    assert!(fence.signal_time().unwrap().unwrap() > 0);
+}
+
+#[test]
+#[ignore = "needs sw_sync"]
+fn merged_fence_waits_for_both() {
+    let timeline = timeline();
+    let first = timeline.create_fence(1, "first").unwrap();
+    let second = timeline.create_fence(2, "second").unwrap();
+    let merged = first.merge(&second, "merged").unwrap();
+
+    timeline.inc(1).unwrap();
+    assert!(first.wait(Some(Duration::from_millis(0))).unwrap());
+    assert!(!merged.wait(Some(Duration::from_millis(0))).unwrap());
+
+    timeline.inc(1).unwrap();
+    assert!(merged.wait(Some(Duration::from_millis(0))).unwrap());
+    assert_eq!(merged.signal_time().unwrap(), second.signal_time().unwrap());
+}
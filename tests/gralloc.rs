@@ -0,0 +1,186 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Run with `cargo test --features mock-hal`.
+#![cfg(feature = "mock-hal")]
+
+extern crate gonk_gfx;
+
+use gonk_gfx::error::GfxError;
+use gonk_gfx::gonk_gfx::*;
+use gonk_gfx::gralloc::*;
+use gonk_gfx::hwc::hwc_rect;
+use gonk_gfx::mock_hal;
+
+const USAGE: i32 = GRALLOC_USAGE_SW_READ_OFTEN | GRALLOC_USAGE_SW_WRITE_OFTEN;
+
+fn rect(left: i32, top: i32, right: i32, bottom: i32) -> hwc_rect {
+    hwc_rect {
+        left,
+        top,
+        right,
+        bottom,
+    }
+}
+
+#[test]
+fn buffer_is_freed_on_drop() {
+    let gralloc = get_gralloc_module().unwrap();
+    let buffer = GrallocBuffer::new(gralloc, 16, 16, PixelFormat::RGB_565, USAGE).unwrap();
+    assert_eq!(mock_hal::live_buffers(), 1);
+    drop(buffer);
+    assert_eq!(mock_hal::live_buffers(), 0);
+}
+
+#[test]
+fn locked_rows_skip_the_stride_padding() {
+    let gralloc = get_gralloc_module().unwrap();
+    let mut buffer = GrallocBuffer::new(gralloc, 10, 4, PixelFormat::RGBA_8888, USAGE).unwrap();
+    let handle = buffer.handle();
+    // The mock allocator pads rows to 32 pixels.
+    assert_eq!(buffer.stride(), 32);
+
+    {
+        let mut mapped = buffer.lock(USAGE, rect(2, 1, 4, 3)).unwrap();
+        assert!(unsafe { mock_hal::is_locked(handle) });
+        assert_eq!(mapped.stride(), 32 * 4);
+        for (y, row) in mapped.rows_mut().enumerate() {
+            assert_eq!(row.len(), 2 * 4);
+            for byte in row.iter_mut() {
+                *byte = y as u8 + 1;
+            }
+        }
+    }
+    assert!(!unsafe { mock_hal::is_locked(handle) });
+
+    let mapped = buffer.lock(USAGE, rect(0, 0, 10, 4)).unwrap();
+    assert_eq!(mapped.rows().count(), 4);
+    for (y, row) in mapped.rows().enumerate() {
+        assert_eq!(row.len(), 10 * 4);
+        for (x, &byte) in row.iter().enumerate() {
+            let x = x / 4;
+            let expected = if (2..4).contains(&x) && (1..3).contains(&y) {
+                y as u8
+            } else {
+                0
+            };
+            assert_eq!(byte, expected, "at {},{}", x, y);
+        }
+    }
+}
+
+#[test]
+fn empty_rects_have_no_rows() {
+    let gralloc = get_gralloc_module().unwrap();
+    let mut buffer = GrallocBuffer::new(gralloc, 10, 4, PixelFormat::RGBA_8888, USAGE).unwrap();
+    let mut mapped = buffer.lock(USAGE, rect(2, 4, 6, 4)).unwrap();
+    assert_eq!(mapped.height(), 0);
+    assert_eq!(mapped.rows().count(), 0);
+    assert_eq!(mapped.rows_mut().count(), 0);
+}
+
+#[test]
+fn yuv_buffers_are_locked_by_plane() {
+    let gralloc = get_gralloc_module().unwrap();
+    let mut buffer = GrallocBuffer::new(gralloc, 8, 6, PixelFormat::NV21, USAGE).unwrap();
+    match buffer.lock(USAGE, rect(0, 0, 8, 6)) {
+        Err(GfxError::UnsupportedFormat(HAL_PIXEL_FORMAT_YCRCB_420_SP)) => (),
+        _ => panic!("Expected an unsupported format error"),
+    }
+
+    let mapped = buffer.lock_ycbcr(USAGE, rect(0, 0, 8, 6)).unwrap();
+    let (y, cb, cr) = (mapped.y(), mapped.cb(), mapped.cr());
+    assert_eq!((y.stride, y.step), (32, 1));
+    assert_eq!(y.data.len(), 5 * 32 + 8);
+    // NV21 interleaves the chroma samples, Cr first.
+    assert_eq!((cb.stride, cb.step), (32, 2));
+    assert_eq!(cb.data.as_ptr() as usize, cr.data.as_ptr() as usize + 1);
+    assert_eq!(cr.data.len(), 2 * 32 + 3 * 2 + 1);
+    assert_eq!(cr.sample(3, 2), 0);
+}
+
+#[test]
+fn yuv_planes_start_at_the_locked_rect() {
+    let gralloc = get_gralloc_module().unwrap();
+    let mut buffer = GrallocBuffer::new(gralloc, 8, 6, PixelFormat::NV21, USAGE).unwrap();
+    let (y_start, cr_start) = {
+        let mapped = buffer.lock_ycbcr(USAGE, rect(0, 0, 8, 6)).unwrap();
+        (
+            mapped.y().data.as_ptr() as usize,
+            mapped.cr().data.as_ptr() as usize,
+        )
+    };
+
+    // The odd left and top share their chroma samples with the pixels
+    // before them.
+    let mapped = buffer.lock_ycbcr(USAGE, rect(3, 1, 7, 5)).unwrap();
+    let (y, cb, cr) = (mapped.y(), mapped.cb(), mapped.cr());
+    assert_eq!(y.data.as_ptr() as usize, y_start + 32 + 3);
+    assert_eq!(y.data.len(), 3 * 32 + 3 + 1);
+    assert_eq!(cr.data.as_ptr() as usize, cr_start + 2);
+    assert_eq!(cr.data.len(), 2 * 32 + 2 * 2 + 1);
+    assert_eq!(cb.data.as_ptr() as usize, cr.data.as_ptr() as usize + 1);
+}
+
+#[test]
+fn rects_outside_the_buffer_are_not_locked() {
+    let gralloc = get_gralloc_module().unwrap();
+    let mut buffer = GrallocBuffer::new(gralloc, 8, 6, PixelFormat::RGBA_8888, USAGE).unwrap();
+    let handle = buffer.handle();
+    for &bad in &[rect(-1, 0, 8, 6), rect(0, 0, 9, 6), rect(4, 0, 2, 6)] {
+        match buffer.lock(USAGE, bad) {
+            Err(GfxError::RectOutOfBounds {
+                rect,
+                width: 8,
+                height: 6,
+            }) => assert_eq!(rect, bad),
+            _ => panic!("Expected an out of bounds error for {:?}", bad),
+        }
+        assert!(!unsafe { mock_hal::is_locked(handle) });
+    }
+}
+
+#[test]
+fn imported_buffers_are_unregistered_not_freed() {
+    let gralloc = get_gralloc_module().unwrap();
+    let buffer = GrallocBuffer::new(gralloc.clone(), 16, 16, PixelFormat::RGB_565, USAGE).unwrap();
+    let imported = unsafe {
+        GrallocBuffer::import(
+            gralloc,
+            buffer.handle(),
+            buffer.width(),
+            buffer.height(),
+            buffer.stride(),
+            buffer.format(),
+            USAGE,
+        )
+    }
+    .unwrap();
+    assert_eq!(mock_hal::registered_buffers(), 1);
+
+    drop(imported);
+    assert_eq!(mock_hal::registered_buffers(), 0);
+    assert_eq!(mock_hal::live_buffers(), 1);
+    drop(buffer);
+    assert_eq!(mock_hal::live_buffers(), 0);
+}
+
+#[test]
+fn allocator_is_closed_with_its_last_buffer() {
+    let gralloc = get_gralloc_module().unwrap();
+    let buffer = GrallocBuffer::new(gralloc.clone(), 16, 16, PixelFormat::RGB_565, USAGE).unwrap();
+    assert_eq!(mock_hal::open_alloc_devices(), 1);
+
+    drop(gralloc);
+    assert_eq!(mock_hal::open_alloc_devices(), 1);
+    drop(buffer);
+    assert_eq!(mock_hal::live_buffers(), 0);
+    assert_eq!(mock_hal::open_alloc_devices(), 0);
+
+    // Everything can be opened again from scratch.
+    let gralloc = get_gralloc_module().unwrap();
+    assert_eq!(mock_hal::open_alloc_devices(), 1);
+    drop(gralloc);
+    assert_eq!(mock_hal::open_alloc_devices(), 0);
+}
@@ -0,0 +1,89 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+extern crate gonk_gfx;
+extern crate libc;
+
+use gonk_gfx::input::*;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::FromRawFd;
+use std::time::Duration;
+
+fn raw(sec: i64, usec: i64, type_: u16, code: u16, value: i32) -> linux_input_event {
+    linux_input_event {
+        time: libc::timeval {
+            tv_sec: sec as _,
+            tv_usec: usec as _,
+        },
+        type_,
+        code,
+        value,
+    }
+}
+
+fn pipe() -> (File, File) {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+}
+
+#[test]
+fn replays_events_through_a_pipe() {
+    let (reader, mut writer) = pipe();
+    let mut manager = InputManager::new();
+    manager.add_device(InputDevice::from_reader("recording", reader));
+
+    let recording = [
+        raw(1, 500, EV_KEY, KEY_POWER, 1),
+        raw(1, 500, EV_SYN, SYN_REPORT, 0),
+        raw(2, 0, EV_REL, REL_X, -3),
+        raw(2, 0, EV_ABS, ABS_Y, 120),
+        raw(2, 0, 0x04, 0x04, 42), // EV_MSC is not delivered
+        raw(2, 0, EV_SYN, SYN_REPORT, 0),
+    ];
+    let bytes = linux_input_event::as_bytes(&recording);
+    // Split a record in two to check that partial reads are reassembled.
+    let split = bytes.len() - 5;
+    writer.write_all(&bytes[..split]).unwrap();
+
+    let mut events = vec![];
+    manager
+        .poll_events(1000, |idx, event| events.push((idx, *event)))
+        .unwrap();
+    assert_eq!(events.len(), 4);
+
+    writer.write_all(&bytes[split..]).unwrap();
+    manager
+        .poll_events(1000, |idx, event| events.push((idx, *event)))
+        .unwrap();
+
+    let kinds: Vec<_> = events.iter().map(|&(_, event)| event.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            EventKind::Key {
+                code: KEY_POWER,
+                state: KeyState::Pressed,
+            },
+            EventKind::Sync(SyncKind::Report),
+            EventKind::Relative {
+                axis: REL_X,
+                value: -3,
+            },
+            EventKind::Absolute {
+                axis: ABS_Y,
+                value: 120,
+            },
+            EventKind::Sync(SyncKind::Report),
+        ]
+    );
+    assert_eq!(events[0].1.time, Duration::new(1, 500_000));
+    assert!(events.iter().all(|&(idx, _)| idx == 0));
+
+    // Closing the writer ends the stream and drops the device.
+    drop(writer);
+    assert_eq!(manager.poll_events(1000, |_, _| ()).unwrap(), 0);
+    assert!(manager.devices().is_empty());
+}
@@ -0,0 +1,1269 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Run with `cargo test --features mock-hal`.
+#![cfg(feature = "mock-hal")]
+
+extern crate gonk_gfx;
+
+use gonk_gfx::error::GfxError;
+use gonk_gfx::fence::Fence;
+use gonk_gfx::gonk_gfx::*;
+use gonk_gfx::gralloc::*;
+use gonk_gfx::hwc::*;
+use gonk_gfx::layers::{Composition, Layer, LayerStack};
+use gonk_gfx::mock_hal::{self, HwcCall, MockHwcDevice};
+use gonk_gfx::window::WindowBuilder;
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::ptr;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FORMAT: PixelFormat = PixelFormat::RGBA_8888;
+const USAGE: i32 = GRALLOC_USAGE_HW_FB | GRALLOC_USAGE_HW_RENDER | GRALLOC_USAGE_HW_COMPOSER;
+
+fn new_window(hwc: &Rc<HwcDevice>) -> *mut GonkNativeWindow {
+    new_window_with_buffers(hwc, 2)
+}
+
+fn new_window_with_buffers(hwc: &Rc<HwcDevice>, count: usize) -> *mut GonkNativeWindow {
+    let window = GonkNativeWindow::new(
+        hwc.clone(),
+        HWC_DISPLAY_PRIMARY,
+        64,
+        32,
+        FORMAT,
+        USAGE,
+        count,
+    )
+    .unwrap();
+    unsafe {
+        (*window).alloc_buffers().unwrap();
+    }
+    window
+}
+
+fn query(window: *mut GonkNativeWindow, what: i32) -> i32 {
+    let mut value = -1;
+    unsafe {
+        let win = &(*window).window;
+        assert_eq!((win.query)(win, what, &mut value), 0);
+    }
+    value
+}
+
+fn release(window: *mut GonkNativeWindow) {
+    unsafe {
+        ((*window).window.common.dec_ref)(&mut (*window).window.common);
+    }
+}
+
+fn dequeue(window: *mut GonkNativeWindow) -> *mut ANativeWindowBuffer {
+    let mut buf = ptr::null_mut();
+    let mut fence = -1;
+    unsafe {
+        let win = &mut (*window).window;
+        assert_eq!((win.dequeue_buffer)(win, &mut buf, &mut fence), 0);
+    }
+    assert!(!buf.is_null());
+    assert_eq!(fence, -1);
+    buf
+}
+
+fn queue(window: *mut GonkNativeWindow, buf: *mut ANativeWindowBuffer) {
+    unsafe {
+        let win = &mut (*window).window;
+        assert_eq!((win.queue_buffer)(win, buf, -1), 0);
+    }
+}
+
+#[test]
+fn queued_buffer_is_submitted_as_framebuffer_target() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window(&hwc);
+    assert_eq!(mock_hal::live_buffers(), 2);
+
+    let buf = dequeue(window);
+    queue(window, buf);
+
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    let calls = mock.calls();
+    assert_eq!(calls.len(), 2);
+    for call in &calls {
+        let displays = match *call {
+            HwcCall::Prepare(ref displays) | HwcCall::Set(ref displays) => displays,
+            ref other => panic!("Unexpected call {:?}", other),
+        };
+        assert_eq!(displays.len(), HWC_NUM_DISPLAY_TYPES);
+        assert!(displays[1].is_none() && displays[2].is_none());
+        let layers = &displays[0].as_ref().unwrap().layers;
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].flags, HWC_SKIP_LAYER);
+        assert_eq!(layers[1].composition_type, HWC_FRAMEBUFFER_TARGET);
+        assert_eq!(layers[1].handle, unsafe { (*buf).handle });
+        assert_eq!(layers[1].display_frame.right, 64);
+        assert_eq!(layers[1].display_frame.bottom, 32);
+    }
+
+    release(window);
+}
+
+#[test]
+fn dequeue_alternates_between_buffers() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window(&hwc);
+
+    let first = dequeue(window);
+    queue(window, first);
+    let second = dequeue(window);
+    assert_ne!(first, second);
+    queue(window, second);
+    assert_eq!(dequeue(window), first);
+
+    release(window);
+}
+
+#[test]
+fn triple_buffering_dequeues_oldest_buffer() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window_with_buffers(&hwc, 3);
+    assert_eq!(mock_hal::live_buffers(), 3);
+
+    assert_eq!(query(window, NATIVE_WINDOW_MIN_UNDEQUEUED_BUFFERS), 1);
+
+    // Two buffers can be in flight, while the display holds the third.
+    let first = dequeue(window);
+    let second = dequeue(window);
+    queue(window, first);
+    queue(window, second);
+    let third = dequeue(window);
+    assert!(third != first && third != second);
+    assert_eq!(dequeue(window), first);
+    unsafe {
+        let win = &mut (*window).window;
+        let mut buf = ptr::null_mut();
+        let mut fence = -1;
+        assert_eq!((win.dequeue_buffer)(win, &mut buf, &mut fence), -1);
+    }
+    queue(window, third);
+    assert_eq!(dequeue(window), second);
+
+    release(window);
+}
+
+#[test]
+fn buffer_age_counts_frames_since_queued() {
+    for &count in &[2, 3] {
+        let hwc = Rc::new(HwcDevice::new().unwrap());
+        let window = new_window_with_buffers(&hwc, count);
+
+        // Fresh buffers have undefined contents.
+        for _ in 0..count {
+            let buf = dequeue(window);
+            assert_eq!(query(window, NATIVE_WINDOW_BUFFER_AGE), 0);
+            queue(window, buf);
+        }
+        for _ in 0..3 {
+            let buf = dequeue(window);
+            assert_eq!(query(window, NATIVE_WINDOW_BUFFER_AGE), count as i32);
+            queue(window, buf);
+        }
+
+        // Reallocating the buffers drops their contents.
+        unsafe {
+            (*window).alloc_buffers().unwrap();
+        }
+        dequeue(window);
+        assert_eq!(query(window, NATIVE_WINDOW_BUFFER_AGE), 0);
+
+        release(window);
+    }
+}
+
+#[test]
+fn crop_and_damage_reach_the_framebuffer_target() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    let window = new_window(&hwc);
+
+    // Damage uses EGL's bottom left origin, on the 64x32 window.
+    let crop = android_native_rect {
+        left: 0,
+        top: 0,
+        right: 32,
+        bottom: 16,
+    };
+    let damage = [android_native_rect {
+        left: 8,
+        top: 30,
+        right: 16,
+        bottom: 20,
+    }];
+    unsafe {
+        let win = &mut (*window).window;
+        assert_eq!((win.perform)(win, NATIVE_WINDOW_SET_CROP, &crop), 0);
+        assert_eq!(
+            (win.perform)(
+                win,
+                NATIVE_WINDOW_SET_SURFACE_DAMAGE,
+                damage.as_ptr(),
+                damage.len()
+            ),
+            0
+        );
+    }
+
+    let target = |call: &HwcCall| match *call {
+        HwcCall::Set(ref displays) => displays[0].as_ref().unwrap().layers[1].clone(),
+        ref other => panic!("Unexpected call {:?}", other),
+    };
+    let buf = dequeue(window);
+    queue(window, buf);
+    let layer = target(&mock.calls()[1]);
+    assert_eq!(
+        layer.source_crop,
+        hwc_frect {
+            left: 0.0,
+            top: 0.0,
+            right: 32.0,
+            bottom: 16.0,
+        }
+    );
+    assert_eq!(layer.display_frame.right, 64);
+    assert_eq!(
+        layer.surface_damage,
+        vec![hwc_rect {
+            left: 8,
+            top: 2,
+            right: 16,
+            bottom: 12,
+        }]
+    );
+
+    // The damage only applies to one frame, the crop sticks.
+    let buf = dequeue(window);
+    queue(window, buf);
+    let layer = target(&mock.calls()[3]);
+    assert_eq!(layer.source_crop.right, 32.0);
+    assert!(layer.surface_damage.is_empty());
+
+    release(window);
+}
+
+#[test]
+fn crop_is_clamped_to_the_buffer() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    let window = new_window(&hwc);
+
+    let set_crop = |crop: android_native_rect| unsafe {
+        let win = &mut (*window).window;
+        assert_eq!((win.perform)(win, NATIVE_WINDOW_SET_CROP, &crop), 0);
+    };
+    let source_crop = |call: &HwcCall| match *call {
+        HwcCall::Set(ref displays) => displays[0].as_ref().unwrap().layers[1].source_crop,
+        ref other => panic!("Unexpected call {:?}", other),
+    };
+
+    set_crop(android_native_rect {
+        left: -8,
+        top: -4,
+        right: 100,
+        bottom: 16,
+    });
+    let buf = dequeue(window);
+    queue(window, buf);
+    assert_eq!(
+        source_crop(&mock.calls()[1]),
+        hwc_frect {
+            left: 0.0,
+            top: 0.0,
+            right: 64.0,
+            bottom: 16.0,
+        }
+    );
+
+    // Nothing of the crop is inside the buffer, show all of it.
+    set_crop(android_native_rect {
+        left: 70,
+        top: 0,
+        right: 80,
+        bottom: 16,
+    });
+    let buf = dequeue(window);
+    queue(window, buf);
+    assert_eq!(
+        source_crop(&mock.calls()[3]),
+        hwc_frect {
+            left: 0.0,
+            top: 0.0,
+            right: 64.0,
+            bottom: 32.0,
+        }
+    );
+
+    release(window);
+}
+
+#[test]
+fn unsupported_buffer_count_is_reported() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    for &count in &[1, 5] {
+        match GonkNativeWindow::new(
+            hwc.clone(),
+            HWC_DISPLAY_PRIMARY,
+            64,
+            32,
+            FORMAT,
+            USAGE,
+            count,
+        ) {
+            Err(gonk_gfx::error::GfxError::BufferCount(c)) => assert_eq!(c, count),
+            _ => panic!("Expected a buffer count error"),
+        }
+    }
+}
+
+#[test]
+fn buffers_are_freed_with_their_last_reference() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window(&hwc);
+    assert_eq!(mock_hal::live_buffers(), 2);
+
+    // Reallocating frees the previous buffers as they get dequeued.
+    unsafe { (*window).alloc_buffers().unwrap() };
+    let buf = dequeue(window);
+    queue(window, buf);
+    assert_eq!(mock_hal::live_buffers(), 2);
+
+    // A reference held on a buffer, as EGL does while rendering to it,
+    // keeps it alive past the window.
+    let buf = dequeue(window);
+    unsafe { ((*buf).common.inc_ref)(&mut (*buf).common) };
+    release(window);
+    assert_eq!(mock_hal::live_buffers(), 1);
+    unsafe { ((*buf).common.dec_ref)(&mut (*buf).common) };
+    assert_eq!(mock_hal::live_buffers(), 0);
+}
+
+#[test]
+fn buffers_are_reallocated_when_the_format_changes() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window(&hwc);
+    assert_eq!(
+        query(window, NATIVE_WINDOW_FORMAT),
+        HAL_PIXEL_FORMAT_RGBA_8888
+    );
+    let buf = dequeue(window);
+    queue(window, buf);
+
+    let perform = |op: i32, value: i32| unsafe {
+        let win = &mut (*window).window;
+        (win.perform)(win, op, value)
+    };
+    assert_eq!(
+        perform(NATIVE_WINDOW_SET_BUFFERS_FORMAT, HAL_PIXEL_FORMAT_RGB_565),
+        0
+    );
+    assert_eq!(
+        query(window, NATIVE_WINDOW_FORMAT),
+        HAL_PIXEL_FORMAT_RGB_565
+    );
+    assert_eq!(mock_hal::live_buffers(), 2);
+    let buf = dequeue(window);
+    assert_eq!(unsafe { (*buf).format }, HAL_PIXEL_FORMAT_RGB_565);
+    // The new buffers haven't been drawn to yet.
+    assert_eq!(query(window, NATIVE_WINDOW_BUFFER_AGE), 0);
+    queue(window, buf);
+
+    let buf = dequeue(window);
+    queue(window, buf);
+
+    // Setting the same format and usage again keeps the buffers.
+    assert_eq!(
+        perform(NATIVE_WINDOW_SET_BUFFERS_FORMAT, HAL_PIXEL_FORMAT_RGB_565),
+        0
+    );
+    assert_eq!(perform(NATIVE_WINDOW_SET_USAGE, USAGE), 0);
+    let buf = dequeue(window);
+    assert_eq!(query(window, NATIVE_WINDOW_BUFFER_AGE), 2);
+    queue(window, buf);
+
+    // 0 goes back to the window's format, unknown formats are refused.
+    assert_eq!(perform(NATIVE_WINDOW_SET_BUFFERS_FORMAT, 0), 0);
+    assert_eq!(
+        query(window, NATIVE_WINDOW_FORMAT),
+        HAL_PIXEL_FORMAT_RGBA_8888
+    );
+    assert_eq!(perform(NATIVE_WINDOW_SET_BUFFERS_FORMAT, 0x7f), -1);
+    assert_eq!(
+        query(window, NATIVE_WINDOW_FORMAT),
+        HAL_PIXEL_FORMAT_RGBA_8888
+    );
+
+    release(window);
+}
+
+#[test]
+fn format_changes_keep_the_buffers_in_use() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window(&hwc);
+    let front = dequeue(window);
+    queue(window, front);
+    let buf = dequeue(window);
+
+    unsafe {
+        let win = &mut (*window).window;
+        assert_eq!(
+            (win.perform)(
+                win,
+                NATIVE_WINDOW_SET_BUFFERS_FORMAT,
+                HAL_PIXEL_FORMAT_RGB_565
+            ),
+            0
+        );
+    }
+    // The buffer on the display and the one being drawn to are kept, the
+    // latter can still be queued.
+    assert_eq!(mock_hal::live_buffers(), 2);
+    queue(window, buf);
+    assert_eq!(unsafe { (*buf).format }, HAL_PIXEL_FORMAT_RGBA_8888);
+
+    // The previous front buffer is replaced once it's off the display.
+    let buf = dequeue(window);
+    assert_eq!(unsafe { (*buf).format }, HAL_PIXEL_FORMAT_RGB_565);
+    assert_eq!(mock_hal::live_buffers(), 2);
+    queue(window, buf);
+
+    release(window);
+    assert_eq!(mock_hal::live_buffers(), 0);
+}
+
+#[test]
+fn smaller_buffers_are_scaled_to_the_display() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window(&hwc);
+    let buf = dequeue(window);
+    queue(window, buf);
+
+    let set_dimensions = |width: i32, height: i32| unsafe {
+        let win = &mut (*window).window;
+        (win.perform)(win, NATIVE_WINDOW_SET_BUFFERS_DIMENSIONS, width, height)
+    };
+    assert_eq!(set_dimensions(32, 16), 0);
+    assert_eq!(set_dimensions(-1, 16), -1);
+    assert_eq!(query(window, NATIVE_WINDOW_WIDTH), 32);
+    assert_eq!(query(window, NATIVE_WINDOW_HEIGHT), 16);
+    assert_eq!(query(window, NATIVE_WINDOW_DEFAULT_WIDTH), 64);
+    assert_eq!(query(window, NATIVE_WINDOW_DEFAULT_HEIGHT), 32);
+
+    // Buffers are reallocated one at a time, as they get dequeued.
+    let buf = dequeue(window);
+    assert_eq!(unsafe { ((*buf).width, (*buf).height) }, (32, 16));
+    assert_eq!(query(window, NATIVE_WINDOW_BUFFER_AGE), 0);
+    assert_eq!(mock_hal::live_buffers(), 2);
+    queue(window, buf);
+
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    let target = match mock.calls().last() {
+        Some(HwcCall::Set(displays)) => {
+            displays[0].as_ref().unwrap().layers.last().unwrap().clone()
+        }
+        other => panic!("Unexpected call {:?}", other),
+    };
+    assert_eq!(target.handle, unsafe { (*buf).handle });
+    assert_eq!(
+        target.source_crop,
+        hwc_frect {
+            left: 0.0,
+            top: 0.0,
+            right: 32.0,
+            bottom: 16.0,
+        }
+    );
+    assert_eq!(
+        target.display_frame,
+        hwc_rect {
+            left: 0,
+            top: 0,
+            right: 64,
+            bottom: 32,
+        }
+    );
+
+    // 0x0 goes back to the size of the display.
+    assert_eq!(set_dimensions(0, 0), 0);
+    let buf = dequeue(window);
+    assert_eq!(unsafe { ((*buf).width, (*buf).height) }, (64, 32));
+
+    release(window);
+    assert_eq!(mock_hal::live_buffers(), 0);
+}
+
+#[test]
+fn resized_buffers_are_freed_once_released() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window(&hwc);
+
+    let buf = dequeue(window);
+    let (fence, signal) = mock_hal::pipe_fence().unwrap();
+    unsafe {
+        let win = &mut (*window).window;
+        assert_eq!((win.cancel_buffer)(win, buf, fence.into_raw_fd()), 0);
+        assert_eq!(
+            (win.perform)(win, NATIVE_WINDOW_SET_BUFFERS_DIMENSIONS, 32, 16),
+            0
+        );
+    }
+
+    // The old buffer is only replaced once its release fence signals.
+    let start = Instant::now();
+    let signaller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        signal.signal().unwrap();
+    });
+    let buf = dequeue(window);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(unsafe { ((*buf).width, (*buf).height) }, (32, 16));
+    assert_eq!(mock_hal::live_buffers(), 2);
+    signaller.join().unwrap();
+
+    release(window);
+}
+
+#[test]
+fn buffer_transform_is_applied_to_the_framebuffer_target() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window(&hwc);
+    assert_eq!(query(window, NATIVE_WINDOW_TRANSFORM_HINT), 0);
+
+    // Landscape buffers rotated onto the 64x32 display.
+    unsafe { (*window).set_transform_hint(HWC_TRANSFORM_ROT_90) };
+    assert_eq!(
+        query(window, NATIVE_WINDOW_TRANSFORM_HINT),
+        HWC_TRANSFORM_ROT_90 as i32
+    );
+    let perform = |op: i32, a: i32, b: i32| unsafe {
+        let win = &mut (*window).window;
+        (win.perform)(win, op, a, b)
+    };
+    assert_eq!(perform(NATIVE_WINDOW_SET_BUFFERS_TRANSFORM, 8, 0), -1);
+    assert_eq!(
+        perform(
+            NATIVE_WINDOW_SET_BUFFERS_TRANSFORM,
+            HWC_TRANSFORM_ROT_90 as i32,
+            0
+        ),
+        0
+    );
+    assert_eq!(perform(NATIVE_WINDOW_SET_BUFFERS_DIMENSIONS, 32, 64), 0);
+    let buf = dequeue(window);
+    queue(window, buf);
+
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    let target = match mock.calls().last() {
+        Some(HwcCall::Set(displays)) => {
+            displays[0].as_ref().unwrap().layers.last().unwrap().clone()
+        }
+        other => panic!("Unexpected call {:?}", other),
+    };
+    assert_eq!(target.transform, HWC_TRANSFORM_ROT_90);
+    assert_eq!(
+        (target.source_crop.right, target.source_crop.bottom),
+        (32.0, 64.0)
+    );
+    assert_eq!(
+        (target.display_frame.right, target.display_frame.bottom),
+        (64, 32)
+    );
+
+    release(window);
+}
+
+#[test]
+fn transformed_points_map_back() {
+    // The top left corner of a buffer rotated by 90 degrees clockwise
+    // ends up at the top right of the screen.
+    assert_eq!(transform_point(HWC_TRANSFORM_ROT_90, 0.0, 0.0), (1.0, 0.0));
+    assert_eq!(transform_point(HWC_TRANSFORM_ROT_90, 1.0, 0.0), (1.0, 1.0));
+    assert_eq!(
+        transform_point(HWC_TRANSFORM_ROT_180, 0.25, 0.0),
+        (0.75, 1.0)
+    );
+    assert_eq!(transform_point(HWC_TRANSFORM_ROT_270, 0.0, 0.0), (0.0, 1.0));
+    assert_eq!(
+        transform_point(HWC_TRANSFORM_FLIP_H, 0.25, 0.5),
+        (0.75, 0.5)
+    );
+    for transform in 0..8 {
+        let (x, y) = transform_point(transform, 0.25, 0.5);
+        assert_eq!(inverse_transform_point(transform, x, y), (0.25, 0.5));
+    }
+}
+
+#[test]
+fn cancelled_buffer_is_not_composited() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window(&hwc);
+
+    let buf = dequeue(window);
+    unsafe {
+        let win = &mut (*window).window;
+        assert_eq!((win.cancel_buffer)(win, buf, -1), 0);
+    }
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    assert!(mock.calls().is_empty());
+
+    release(window);
+}
+
+#[test]
+fn acquire_fences_are_handed_to_the_hwc() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window(&hwc);
+
+    let buf = dequeue(window);
+    let (fence, signal) = mock_hal::pipe_fence().unwrap();
+    unsafe {
+        let win = &mut (*window).window;
+        assert_eq!((win.queue_buffer)(win, buf, fence.into_raw_fd()), 0);
+    }
+    // The mock HWC closed the fence once done with it.
+    assert!(signal.signal().is_err());
+
+    release(window);
+}
+
+#[test]
+fn frames_are_timed_by_their_retire_fence() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    mock.enable_retire_fences();
+    let window = new_window(&hwc);
+
+    for _ in 0..2 {
+        let buf = dequeue(window);
+        queue(window, buf);
+    }
+    let window_ref = unsafe { &mut *window };
+    assert_eq!(window_ref.last_frame(), 2);
+    assert!(window_ref.frame_timings().is_empty());
+
+    mock.retire_frames();
+    let timings = window_ref.frame_timings();
+    assert_eq!(
+        timings
+            .iter()
+            .map(|timing| timing.frame)
+            .collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    for timing in &timings {
+        assert!(timing.retire_time.unwrap() >= timing.queue_time);
+    }
+    assert!(timings[0].queue_time <= timings[1].queue_time);
+    assert!(window_ref.frame_timings().is_empty());
+
+    release(window);
+}
+
+#[test]
+fn only_recent_frames_are_timed() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window(&hwc);
+
+    for _ in 0..70 {
+        let buf = dequeue(window);
+        queue(window, buf);
+    }
+    // Without retire fences, the timings are complete right away.
+    let timings = unsafe { (*window).frame_timings() };
+    assert_eq!(timings.len(), 64);
+    assert_eq!(timings[0].frame, 7);
+    assert!(timings.iter().all(|timing| timing.retire_time.is_none()));
+
+    release(window);
+}
+
+#[test]
+fn cancelled_buffers_keep_their_fence() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = new_window(&hwc);
+
+    let buf = dequeue(window);
+    let (fence, signal) = mock_hal::pipe_fence().unwrap();
+    unsafe {
+        let win = &mut (*window).window;
+        assert_eq!((win.cancel_buffer)(win, buf, fence.into_raw_fd()), 0);
+    }
+    let fence = unsafe {
+        let win = &mut (*window).window;
+        let mut buf2 = ptr::null_mut();
+        let mut fence = -1;
+        assert_eq!((win.dequeue_buffer)(win, &mut buf2, &mut fence), 0);
+        assert_eq!(buf2, buf);
+        Fence::from_raw(fence).unwrap()
+    };
+    assert!(!fence.wait(Some(Duration::from_millis(0))).unwrap());
+    signal.signal().unwrap();
+    assert!(fence.wait(None).unwrap());
+
+    release(window);
+}
+
+#[test]
+fn set_display_uses_power_modes() {
+    mock_hal::set_hwc_version(HwcApiVersion::hwc_api_version(1, 4));
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+
+    hwc.set_display(true);
+    assert!(!mock_hal::autosuspend_enabled());
+    hwc.set_display(false);
+    assert!(mock_hal::autosuspend_enabled());
+
+    let modes: Vec<_> = mock
+        .calls()
+        .into_iter()
+        .map(|call| match call {
+            HwcCall::SetPowerMode { display, mode } => (display, mode),
+            other => panic!("Unexpected call {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        modes,
+        vec![
+            (HWC_DISPLAY_PRIMARY, HWC_POWER_MODE_NORMAL),
+            (HWC_DISPLAY_PRIMARY, HWC_POWER_MODE_OFF),
+        ]
+    );
+}
+
+#[test]
+fn set_display_blanks_on_hwc_1_3() {
+    mock_hal::set_hwc_version(HwcApiVersion::hwc_api_version(1, 3));
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+
+    hwc.set_display(true);
+    hwc.set_display(false);
+
+    match &mock.calls()[..] {
+        [HwcCall::SetPowerMode { mode: 0, .. }, HwcCall::SetPowerMode { mode: 1, .. }] => (),
+        other => panic!("Unexpected calls {:?}", other),
+    }
+}
+
+#[test]
+fn doze_suspend_lets_the_system_suspend() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+
+    let set_mode = |display, mode| {
+        hwc.set_power_mode(display, mode).unwrap();
+        assert_eq!(hwc.power_mode(display), mode);
+        mock_hal::autosuspend_enabled()
+    };
+    assert!(!set_mode(HWC_DISPLAY_PRIMARY, PowerMode::Normal));
+    assert!(!set_mode(HWC_DISPLAY_PRIMARY, PowerMode::Doze));
+    assert!(set_mode(HWC_DISPLAY_PRIMARY, PowerMode::DozeSuspend));
+    // Another display in use keeps the system up.
+    assert!(!set_mode(HWC_DISPLAY_EXTERNAL, PowerMode::Normal));
+    assert!(!set_mode(HWC_DISPLAY_PRIMARY, PowerMode::Off));
+    assert!(set_mode(HWC_DISPLAY_EXTERNAL, PowerMode::Off));
+
+    let modes: Vec<_> = mock
+        .calls()
+        .into_iter()
+        .map(|call| match call {
+            HwcCall::SetPowerMode { display, mode } => (display, mode),
+            other => panic!("Unexpected call {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        modes,
+        vec![
+            (HWC_DISPLAY_PRIMARY, HWC_POWER_MODE_NORMAL),
+            (HWC_DISPLAY_PRIMARY, HWC_POWER_MODE_DOZE),
+            (HWC_DISPLAY_PRIMARY, HWC_POWER_MODE_DOZE_SUSPEND),
+            (HWC_DISPLAY_EXTERNAL, HWC_POWER_MODE_NORMAL),
+            (HWC_DISPLAY_PRIMARY, HWC_POWER_MODE_OFF),
+            (HWC_DISPLAY_EXTERNAL, HWC_POWER_MODE_OFF),
+        ]
+    );
+    assert!(hwc
+        .set_power_mode(HWC_DISPLAY_VIRTUAL, PowerMode::Normal)
+        .is_err());
+}
+
+#[test]
+fn doze_modes_unblank_on_hwc_1_3() {
+    mock_hal::set_hwc_version(HwcApiVersion::hwc_api_version(1, 3));
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+
+    hwc.set_power_mode(HWC_DISPLAY_PRIMARY, PowerMode::DozeSuspend)
+        .unwrap();
+    assert_eq!(hwc.power_mode(HWC_DISPLAY_PRIMARY), PowerMode::Normal);
+    assert!(!mock_hal::autosuspend_enabled());
+    hwc.set_power_mode(HWC_DISPLAY_PRIMARY, PowerMode::Off)
+        .unwrap();
+    assert!(mock_hal::autosuspend_enabled());
+
+    match &mock.calls()[..] {
+        [HwcCall::SetPowerMode { mode: 0, .. }, HwcCall::SetPowerMode { mode: 1, .. }] => (),
+        other => panic!("Unexpected calls {:?}", other),
+    }
+}
+
+#[test]
+fn unknown_hwc_version_is_reported() {
+    mock_hal::set_hwc_version(HwcApiVersion::hwc_api_version(1, 1));
+    match HwcDevice::new() {
+        Err(gonk_gfx::error::GfxError::UnsupportedHwcVersion(version)) => {
+            assert_eq!(version, HwcApiVersion::hwc_api_version(1, 1))
+        }
+        _ => panic!("Expected an unsupported version error"),
+    }
+    assert_eq!(mock_hal::open_hwc_devices(), 0);
+}
+
+#[test]
+fn hwc_device_is_closed_with_its_last_user() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    assert_eq!(mock_hal::open_hwc_devices(), 1);
+    let window = new_window(&hwc);
+    let buf = dequeue(window);
+    queue(window, buf);
+    release(window);
+    assert_eq!(mock_hal::live_buffers(), 0);
+
+    drop(hwc);
+    assert_eq!(mock_hal::open_hwc_devices(), 0);
+    assert_eq!(mock_hal::open_alloc_devices(), 0);
+}
+
+#[test]
+fn vsync_events_are_delivered_while_enabled() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    let events = hwc.event_channel();
+
+    mock.vsync(HWC_DISPLAY_PRIMARY, 1000);
+    hwc.set_vsync_enabled(HWC_DISPLAY_PRIMARY, true).unwrap();
+    mock.vsync(HWC_DISPLAY_PRIMARY, 2000);
+    mock.invalidate();
+    hwc.set_vsync_enabled(HWC_DISPLAY_PRIMARY, false).unwrap();
+    mock.vsync(HWC_DISPLAY_PRIMARY, 3000);
+
+    let received: Vec<_> = events.try_iter().collect();
+    assert_eq!(
+        received,
+        vec![
+            HwcEvent::Vsync {
+                display: HWC_DISPLAY_PRIMARY,
+                timestamp: 2000,
+            },
+            HwcEvent::Invalidate,
+        ]
+    );
+}
+
+#[test]
+fn event_handler_can_replace_itself() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    let (sender, receiver) = std::sync::mpsc::channel();
+    // The mock calls back on this thread, while hwc is alive.
+    let hwc_ptr = &*hwc as *const HwcDevice as usize;
+    hwc.set_event_handler(move |_| {
+        let hwc = unsafe { &*(hwc_ptr as *const HwcDevice) };
+        let _ = sender.send(hwc.event_channel());
+    });
+
+    mock.invalidate();
+    let events = receiver.try_recv().unwrap();
+    mock.invalidate();
+    assert_eq!(events.try_recv(), Ok(HwcEvent::Invalidate));
+}
+
+#[test]
+fn display_configs_can_be_listed_and_switched() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    mock.add_config(
+        HWC_DISPLAY_PRIMARY,
+        mock_hal::MockDisplayConfig {
+            vsync_period: 33_333_333,
+            ..Default::default()
+        },
+    );
+    mock.add_config(
+        HWC_DISPLAY_PRIMARY,
+        mock_hal::MockDisplayConfig {
+            width: 360,
+            height: 640,
+            dpi_x: 160_000,
+            dpi_y: 160_000,
+            ..Default::default()
+        },
+    );
+
+    let configs = hwc.configs(HWC_DISPLAY_PRIMARY).unwrap();
+    assert_eq!(configs.len(), 3);
+    assert_eq!(
+        configs[0],
+        DisplayInfo {
+            width: 720,
+            height: 1280,
+            dpi_x: 320.0,
+            dpi_y: 320.0,
+            vsync_period: 16_666_666,
+        }
+    );
+    assert_eq!(configs[1].vsync_period, 33_333_333);
+    assert_eq!((configs[2].width, configs[2].dpi_y), (360, 160.0));
+    assert_eq!(hwc.active_config(HWC_DISPLAY_PRIMARY).unwrap(), 0);
+
+    hwc.set_active_config(HWC_DISPLAY_PRIMARY, 2).unwrap();
+    assert_eq!(hwc.active_config(HWC_DISPLAY_PRIMARY).unwrap(), 2);
+    assert_eq!(
+        hwc.get_display_dimensions_and_dpi(HWC_DISPLAY_PRIMARY)
+            .unwrap(),
+        (360, 640, 160)
+    );
+    assert!(hwc.set_active_config(HWC_DISPLAY_PRIMARY, 3).is_err());
+    assert!(hwc.configs(HWC_DISPLAY_EXTERNAL).is_err());
+}
+
+#[test]
+fn display_info_works_around_hal_quirks() {
+    mock_hal::set_primary_display(mock_hal::MockDisplayConfig {
+        width: 1080,
+        height: 1920,
+        // Dots per inch rather than per thousand inches.
+        dpi_x: 403,
+        dpi_y: 0,
+        vsync_period: 0,
+    });
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let info = hwc.display_info(HWC_DISPLAY_PRIMARY).unwrap();
+    assert_eq!((info.width, info.height), (1080, 1920));
+    assert_eq!((info.dpi_x, info.dpi_y), (403.0, 403.0));
+    assert_eq!(info.vsync_period, 16_666_667);
+    assert!((info.width_mm() - 68.07).abs() < 0.01);
+
+    // HALs failing on attributes they don't know still get asked for the
+    // others.
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    mock.set_unknown_attribute(HWC_DISPLAY_DPI_Y);
+    let info = hwc.display_info(HWC_DISPLAY_PRIMARY).unwrap();
+    assert_eq!((info.width, info.height, info.dpi_y), (1080, 1920, 403.0));
+    mock.set_unknown_attribute(HWC_DISPLAY_WIDTH);
+    assert!(hwc.display_info(HWC_DISPLAY_PRIMARY).is_err());
+}
+
+#[test]
+fn display_config_is_fixed_on_hwc_1_3() {
+    mock_hal::set_hwc_version(HwcApiVersion::hwc_api_version(1, 3));
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    mock.add_config(HWC_DISPLAY_PRIMARY, Default::default());
+
+    assert_eq!(hwc.configs(HWC_DISPLAY_PRIMARY).unwrap().len(), 2);
+    assert_eq!(hwc.active_config(HWC_DISPLAY_PRIMARY).unwrap(), 0);
+    assert!(hwc.set_active_config(HWC_DISPLAY_PRIMARY, 0).is_ok());
+    match hwc.set_active_config(HWC_DISPLAY_PRIMARY, 1) {
+        Err(gonk_gfx::error::GfxError::Hwc {
+            call: "set_active_config",
+            ..
+        }) => (),
+        _ => panic!("Expected set_active_config to fail"),
+    }
+    assert!(mock.calls().is_empty());
+}
+
+#[test]
+fn external_display_hotplug() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    let events = hwc.event_channel();
+
+    assert!(hwc.is_connected(HWC_DISPLAY_PRIMARY));
+    assert!(!hwc.is_connected(HWC_DISPLAY_EXTERNAL));
+    assert!(hwc
+        .get_display_dimensions_and_dpi(HWC_DISPLAY_EXTERNAL)
+        .is_err());
+
+    mock.connect_external(mock_hal::MockDisplayConfig {
+        width: 1920,
+        height: 1080,
+        dpi_x: 96_000,
+        ..Default::default()
+    });
+    assert!(hwc.is_connected(HWC_DISPLAY_EXTERNAL));
+    assert_eq!(
+        hwc.get_display_dimensions_and_dpi(HWC_DISPLAY_EXTERNAL)
+            .unwrap(),
+        (1920, 1080, 96)
+    );
+
+    // A window on the external display only submits contents for it.
+    let window = GonkNativeWindow::new(
+        hwc.clone(),
+        HWC_DISPLAY_EXTERNAL,
+        1920,
+        1080,
+        FORMAT,
+        USAGE,
+        2,
+    )
+    .unwrap();
+    unsafe {
+        (*window).alloc_buffers().unwrap();
+    }
+    let buf = dequeue(window);
+    queue(window, buf);
+    match mock.calls().last() {
+        Some(HwcCall::Set(displays)) => {
+            assert!(displays[0].is_none());
+            assert!(displays[1].is_some());
+        }
+        other => panic!("Unexpected call {:?}", other),
+    }
+    release(window);
+
+    mock.disconnect_external();
+    assert!(!hwc.is_connected(HWC_DISPLAY_EXTERNAL));
+
+    let received: Vec<_> = events.try_iter().collect();
+    assert_eq!(
+        received,
+        vec![
+            HwcEvent::Hotplug {
+                display: HWC_DISPLAY_EXTERNAL,
+                connected: true,
+            },
+            HwcEvent::Hotplug {
+                display: HWC_DISPLAY_EXTERNAL,
+                connected: false,
+            },
+        ]
+    );
+}
+
+fn last_set(mock: &MockHwcDevice) -> Vec<Option<mock_hal::RecordedDisplay>> {
+    match mock.calls().last() {
+        Some(HwcCall::Set(displays)) => displays.clone(),
+        other => panic!("Unexpected call {:?}", other),
+    }
+}
+
+#[test]
+fn windows_on_both_displays_are_set_together() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    mock.connect_external(mock_hal::MockDisplayConfig::default());
+    let primary = new_window(&hwc);
+    let external =
+        GonkNativeWindow::new(hwc.clone(), HWC_DISPLAY_EXTERNAL, 64, 32, FORMAT, USAGE, 2).unwrap();
+    unsafe {
+        (*external).alloc_buffers().unwrap();
+    }
+
+    let buf = dequeue(external);
+    queue(external, buf);
+    let buf = dequeue(primary);
+    queue(primary, buf);
+    // Leaving the external display out would blank it.
+    let displays = last_set(mock);
+    assert!(displays[0].is_some());
+    let external_frame = displays[1].as_ref().unwrap();
+    assert_eq!(external_frame.flags & HWC_GEOMETRY_CHANGED, 0);
+
+    release(external);
+    let buf = dequeue(primary);
+    queue(primary, buf);
+    let displays = last_set(mock);
+    assert!(displays[0].is_some());
+    assert!(displays[1].is_none());
+    release(primary);
+}
+
+#[test]
+fn windows_on_unknown_displays_are_rejected() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    match GonkNativeWindow::new(
+        hwc.clone(),
+        HWC_NUM_DISPLAY_TYPES as i32,
+        64,
+        32,
+        FORMAT,
+        USAGE,
+        2,
+    ) {
+        Err(GfxError::DisplayNotConnected(display)) => {
+            assert_eq!(display, HWC_NUM_DISPLAY_TYPES as i32)
+        }
+        _ => panic!("Expected a display error"),
+    }
+}
+
+#[test]
+fn layer_stack_is_split_between_overlays_and_gles() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    mock.set_overlay_planes(2);
+    let window = new_window(&hwc);
+
+    let gralloc = get_gralloc_module().unwrap();
+    let mut stack = LayerStack::new();
+    for i in 0..3 {
+        let buffer =
+            GonkNativeWindowBuffer::new(gralloc.clone(), 16, 16, FORMAT, GRALLOC_USAGE_HW_COMPOSER);
+        let mut layer = unsafe { Layer::new(buffer.unwrap() as *mut ANativeWindowBuffer) };
+        layer.display_frame.left = i * 16;
+        layer.display_frame.right = (i + 1) * 16;
+        layer.blending = HWC_BLENDING_PREMULT;
+        stack.push(layer);
+    }
+    let target_frame = hwc_rect {
+        left: 0,
+        top: 0,
+        right: 64,
+        bottom: 32,
+    };
+    let composition: Vec<_> = unsafe {
+        (*window)
+            .prepare(stack.display_contents(target_frame))
+            .unwrap()
+            .layers()
+            .iter()
+            .map(|layer| Composition::from_hwc(layer.composition_type))
+            .collect()
+    };
+    assert_eq!(
+        composition,
+        vec![
+            Composition::Overlay,
+            Composition::Overlay,
+            Composition::Gles,
+            Composition::Gles,
+        ]
+    );
+
+    // The next queued buffer completes the prepared contents.
+    let buf = dequeue(window);
+    queue(window, buf);
+    let calls = mock.calls();
+    assert_eq!(calls.len(), 2);
+    let layers = match calls[1] {
+        HwcCall::Set(ref displays) => &displays[0].as_ref().unwrap().layers,
+        ref other => panic!("Unexpected call {:?}", other),
+    };
+    assert_eq!(layers.len(), 4);
+    for (i, layer) in layers[..3].iter().enumerate() {
+        let expected = stack.layers()[i];
+        assert_eq!(layer.handle, unsafe { (*expected.buffer()).handle });
+        assert_eq!(layer.display_frame, expected.display_frame);
+        assert_eq!(layer.visible_region, vec![expected.display_frame]);
+        assert_eq!(layer.blending, HWC_BLENDING_PREMULT);
+    }
+    assert_eq!(layers[3].composition_type, HWC_FRAMEBUFFER_TARGET);
+    assert_eq!(layers[3].handle, unsafe { (*buf).handle });
+    assert_eq!(layers[3].visible_region, vec![target_frame]);
+
+    release(window);
+    for layer in stack.layers() {
+        unsafe { ((*layer.buffer()).common.dec_ref)(&mut (*layer.buffer()).common) };
+    }
+    assert_eq!(mock_hal::live_buffers(), 0);
+}
+
+#[test]
+fn overlays_get_their_own_release_fence() {
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    mock.set_overlay_planes(2);
+    mock.enable_release_fences();
+    let window = new_window(&hwc);
+
+    let gralloc = get_gralloc_module().unwrap();
+    let mut stack = LayerStack::new();
+    for _ in 0..3 {
+        let buffer =
+            GonkNativeWindowBuffer::new(gralloc.clone(), 16, 16, FORMAT, GRALLOC_USAGE_HW_COMPOSER);
+        stack.push(unsafe { Layer::new(buffer.unwrap() as *mut ANativeWindowBuffer) });
+    }
+    let frame = hwc_rect {
+        left: 0,
+        top: 0,
+        right: 64,
+        bottom: 32,
+    };
+    unsafe { (*window).prepare(stack.display_contents(frame)).unwrap() };
+    let buf = dequeue(window);
+    queue(window, buf);
+
+    let fences = unsafe { (*window).take_layer_release_fences() };
+    assert_eq!(fences.len(), 3);
+    let (first, second) = match (&fences[0], &fences[1]) {
+        (Some(first), Some(second)) => (first, second),
+        _ => panic!("The overlays should have release fences"),
+    };
+    assert_ne!(first.as_raw_fd(), second.as_raw_fd());
+    // The GLES layer isn't read by the display.
+    assert!(fences[2].is_none());
+    assert!(!first.wait(Some(Duration::from_millis(0))).unwrap());
+    mock.release_layers();
+    assert!(first.wait(Some(Duration::from_millis(0))).unwrap());
+    assert!(second.wait(Some(Duration::from_millis(0))).unwrap());
+
+    // They are only handed out once, and not for plain buffers.
+    assert!(unsafe { (*window).take_layer_release_fences() }.is_empty());
+    let buf = dequeue(window);
+    queue(window, buf);
+    assert!(unsafe { (*window).take_layer_release_fences() }.is_empty());
+
+    drop(fences);
+    release(window);
+    for layer in stack.layers() {
+        unsafe { ((*layer.buffer()).common.dec_ref)(&mut (*layer.buffer()).common) };
+    }
+    assert_eq!(mock_hal::live_buffers(), 0);
+}
+
+#[test]
+fn layers_are_stacked_by_z_order() {
+    let gralloc = get_gralloc_module().unwrap();
+    let mut stack = LayerStack::new();
+    let mut buffers = vec![];
+    for &z_order in &[1, 0, 1] {
+        let buffer =
+            GonkNativeWindowBuffer::new(gralloc.clone(), 16, 16, FORMAT, GRALLOC_USAGE_HW_COMPOSER)
+                .unwrap() as *mut ANativeWindowBuffer;
+        let mut layer = unsafe { Layer::new(buffer) };
+        layer.z_order = z_order;
+        stack.push(layer);
+        buffers.push(buffer);
+    }
+
+    let stacked: Vec<_> = stack.layers().iter().map(|layer| layer.buffer()).collect();
+    assert_eq!(stacked, vec![buffers[1], buffers[0], buffers[2]]);
+
+    for buffer in buffers {
+        unsafe { ((*buffer).common.dec_ref)(&mut (*buffer).common) };
+    }
+}
+
+#[test]
+fn window_builder_rejects_formats_egl_cannot_render() {
+    match WindowBuilder::new().format(PixelFormat::NV21).build() {
+        Err(GfxError::UnsupportedFormat(format)) => {
+            assert_eq!(format, PixelFormat::NV21.to_hal())
+        }
+        _ => panic!("NV21 windows shouldn't be created"),
+    }
+}
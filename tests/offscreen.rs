@@ -0,0 +1,112 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Run with `cargo test --features mock-hal`. The contexts come from the
+// host's EGL, Mesa's surfaceless platform works without a display server.
+#![cfg(feature = "mock-hal")]
+
+extern crate gleam;
+extern crate gonk_gfx;
+
+use gleam::gl::{self, GLuint, Gl};
+use gonk_gfx::error::GfxError;
+use gonk_gfx::offscreen::{OffscreenContext, OffscreenSurface};
+use std::env;
+
+const PBUFFER: OffscreenSurface = OffscreenSurface::Pbuffer {
+    width: 4,
+    height: 4,
+};
+
+fn context(surface: OffscreenSurface) -> OffscreenContext {
+    env::set_var("EGL_PLATFORM", "surfaceless");
+    OffscreenContext::new(surface, 2).unwrap()
+}
+
+// A 1x1 texture of the given color, attached to a new framebuffer.
+fn texture_framebuffer(gl: &dyn Gl, texture: Option<GLuint>, color: Option<[u8; 4]>) -> GLuint {
+    let texture = texture.unwrap_or_else(|| gl.gen_textures(1)[0]);
+    gl.bind_texture(gl::TEXTURE_2D, texture);
+    if let Some(color) = color {
+        gl.tex_image_2d(
+            gl::TEXTURE_2D,
+            0,
+            gl::RGBA as i32,
+            1,
+            1,
+            0,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            Some(&color),
+        );
+    }
+    let framebuffer = gl.gen_framebuffers(1)[0];
+    gl.bind_framebuffer(gl::FRAMEBUFFER, framebuffer);
+    gl.framebuffer_texture_2d(
+        gl::FRAMEBUFFER,
+        gl::COLOR_ATTACHMENT0,
+        gl::TEXTURE_2D,
+        texture,
+        0,
+    );
+    assert_eq!(
+        gl.check_frame_buffer_status(gl::FRAMEBUFFER),
+        gl::FRAMEBUFFER_COMPLETE
+    );
+    texture
+}
+
+#[test]
+fn pbuffer_context_renders() {
+    let ctx = context(PBUFFER);
+    ctx.make_current().unwrap();
+    let gl = ctx.load_gl();
+
+    gl.clear_color(1.0, 0.0, 0.0, 1.0);
+    gl.clear(gl::COLOR_BUFFER_BIT);
+    let pixels = gl.read_pixels(0, 0, 4, 4, gl::RGBA, gl::UNSIGNED_BYTE);
+    assert!(pixels.chunks(4).all(|pixel| pixel == [255, 0, 0, 255]));
+}
+
+#[test]
+fn surfaceless_context_renders_to_framebuffers() {
+    let ctx = context(OffscreenSurface::Surfaceless);
+    ctx.make_current().unwrap();
+    let gl = ctx.load_gl();
+
+    texture_framebuffer(&*gl, None, Some([0, 0, 0, 0]));
+    gl.clear_color(0.0, 1.0, 0.0, 1.0);
+    gl.clear(gl::COLOR_BUFFER_BIT);
+    assert_eq!(
+        gl.read_pixels(0, 0, 1, 1, gl::RGBA, gl::UNSIGNED_BYTE),
+        vec![0, 255, 0, 255]
+    );
+}
+
+#[test]
+fn shared_contexts_see_the_same_textures() {
+    let ctx = context(PBUFFER);
+    ctx.make_current().unwrap();
+    let gl = ctx.load_gl();
+    let texture = texture_framebuffer(&*gl, None, Some([0, 0, 255, 255]));
+    gl.finish();
+
+    let shared = ctx.share(PBUFFER).unwrap();
+    shared.make_current().unwrap();
+    let gl = shared.load_gl();
+    texture_framebuffer(&*gl, Some(texture), None);
+    assert_eq!(
+        gl.read_pixels(0, 0, 1, 1, gl::RGBA, gl::UNSIGNED_BYTE),
+        vec![0, 0, 255, 255]
+    );
+}
+
+#[test]
+fn unsupported_gles_versions_are_reported() {
+    env::set_var("EGL_PLATFORM", "surfaceless");
+    match OffscreenContext::new(PBUFFER, 1) {
+        Err(GfxError::UnsupportedGlesVersion(1)) => (),
+        _ => panic!("GLES 1 contexts shouldn't be created"),
+    }
+}
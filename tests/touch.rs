@@ -0,0 +1,154 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+extern crate gonk_gfx;
+
+use gonk_gfx::input::*;
+use gonk_gfx::touch::*;
+use std::time::Duration;
+
+fn range(minimum: i32, maximum: i32) -> input_absinfo {
+    input_absinfo {
+        minimum,
+        maximum,
+        ..Default::default()
+    }
+}
+
+// A 0..1023 x 0..2047 panel mapped onto a 512x1024 window.
+fn tracker() -> TouchTracker {
+    let axes = TouchAxes {
+        x: range(0, 1023),
+        y: range(0, 2047),
+        pressure: Some(range(0, 255)),
+        touch_major: None,
+        slots: 10,
+    };
+    TouchTracker::new(axes, 512, 1024)
+}
+
+fn feed(tracker: &mut TouchTracker, events: &[(u16, i32)]) -> Vec<TouchEvent> {
+    let mut out = vec![];
+    for &(axis, value) in events {
+        let kind = EventKind::Absolute { axis, value };
+        out.extend(tracker.process(&InputEvent {
+            time: Duration::from_millis(1),
+            kind,
+        }));
+    }
+    out.extend(tracker.process(&InputEvent {
+        time: Duration::from_millis(2),
+        kind: EventKind::Sync(SyncKind::Report),
+    }));
+    out
+}
+
+fn phases(events: &[TouchEvent]) -> Vec<(u32, TouchPhase)> {
+    events.iter().map(|e| (e.id, e.phase)).collect()
+}
+
+#[test]
+fn tracks_two_fingers() {
+    let mut tracker = tracker();
+
+    let down = feed(
+        &mut tracker,
+        &[
+            (ABS_MT_SLOT, 0),
+            (ABS_MT_TRACKING_ID, 45),
+            (ABS_MT_POSITION_X, 512),
+            (ABS_MT_POSITION_Y, 1024),
+            (ABS_MT_PRESSURE, 255),
+        ],
+    );
+    assert_eq!(phases(&down), vec![(0, TouchPhase::Down)]);
+    assert_eq!((down[0].x, down[0].y), (256.0, 512.0));
+    assert_eq!(down[0].pressure, 1.0);
+    assert_eq!(down[0].time, Duration::from_millis(2));
+
+    let second = feed(
+        &mut tracker,
+        &[
+            (ABS_MT_POSITION_X, 514),
+            (ABS_MT_SLOT, 1),
+            (ABS_MT_TRACKING_ID, 46),
+            (ABS_MT_POSITION_X, 0),
+            (ABS_MT_POSITION_Y, 0),
+        ],
+    );
+    assert_eq!(
+        phases(&second),
+        vec![(0, TouchPhase::Move), (1, TouchPhase::Down)]
+    );
+    assert_eq!(second[0].x, 257.0);
+
+    let up = feed(&mut tracker, &[(ABS_MT_SLOT, 0), (ABS_MT_TRACKING_ID, -1)]);
+    assert_eq!(phases(&up), vec![(0, TouchPhase::Up)]);
+    assert_eq!((up[0].x, up[0].y), (257.0, 512.0));
+
+    // Nothing changed for the second finger.
+    assert!(feed(&mut tracker, &[]).is_empty());
+}
+
+#[test]
+fn new_contact_in_same_slot_gets_a_new_id() {
+    let mut tracker = tracker();
+    feed(
+        &mut tracker,
+        &[(ABS_MT_TRACKING_ID, 7), (ABS_MT_POSITION_X, 10)],
+    );
+
+    // Lifted and touched again between two reports.
+    let events = feed(
+        &mut tracker,
+        &[(ABS_MT_TRACKING_ID, 8), (ABS_MT_POSITION_X, 20)],
+    );
+    assert_eq!(
+        phases(&events),
+        vec![(0, TouchPhase::Up), (1, TouchPhase::Down)]
+    );
+    assert_eq!(events[0].x, 5.0);
+    assert_eq!(events[1].x, 10.0);
+}
+
+#[test]
+fn dropped_events_cancel_touches() {
+    let mut tracker = tracker();
+    feed(&mut tracker, &[(ABS_MT_TRACKING_ID, 1)]);
+    feed(&mut tracker, &[(ABS_MT_SLOT, 1), (ABS_MT_TRACKING_ID, 2)]);
+
+    let cancelled = tracker.process(&InputEvent {
+        time: Duration::from_millis(3),
+        kind: EventKind::Sync(SyncKind::Dropped),
+    });
+    assert_eq!(
+        phases(&cancelled),
+        vec![(0, TouchPhase::Cancel), (1, TouchPhase::Cancel)]
+    );
+
+    // Events up to the next report are stale.
+    assert!(feed(&mut tracker, &[(ABS_MT_TRACKING_ID, 3)]).is_empty());
+    let events = feed(&mut tracker, &[(ABS_MT_TRACKING_ID, 4)]);
+    assert_eq!(phases(&events), vec![(2, TouchPhase::Down)]);
+}
+
+#[test]
+fn slots_past_the_device_maximum_are_ignored() {
+    let mut tracker = tracker();
+    let events = feed(
+        &mut tracker,
+        &[
+            (ABS_MT_SLOT, i32::MAX),
+            (ABS_MT_TRACKING_ID, 1),
+            (ABS_MT_POSITION_X, 10),
+            (ABS_MT_SLOT, 10),
+            (ABS_MT_TRACKING_ID, 2),
+        ],
+    );
+    assert!(events.is_empty());
+
+    // The slots the device has still work.
+    let events = feed(&mut tracker, &[(ABS_MT_SLOT, 9), (ABS_MT_TRACKING_ID, 3)]);
+    assert_eq!(phases(&events), vec![(0, TouchPhase::Down)]);
+}
@@ -0,0 +1,103 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Run with `cargo test --features mock-hal`. With the mock HAL, windows
+// render to a pbuffer of the host's EGL.
+#![cfg(feature = "mock-hal")]
+
+extern crate egl;
+extern crate gonk_gfx;
+
+use gonk_gfx::error::GfxError;
+use gonk_gfx::hwc::*;
+use gonk_gfx::mock_hal::{self, HwcCall, MockHwcDevice};
+use gonk_gfx::window::WindowBuilder;
+use std::env;
+use std::rc::Rc;
+
+#[test]
+fn failed_builds_release_what_they_set_up() {
+    env::set_var("EGL_PLATFORM", "surfaceless");
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    mock.add_config(
+        HWC_DISPLAY_PRIMARY,
+        mock_hal::MockDisplayConfig {
+            width: 360,
+            height: 640,
+            ..Default::default()
+        },
+    );
+
+    // Allocating the buffers is the last step that can fail.
+    mock_hal::set_fail_allocations(true);
+    match WindowBuilder::new()
+        .hwc(hwc.clone())
+        .display_config(1)
+        .build()
+    {
+        Err(GfxError::GrallocAlloc { .. }) => (),
+        Err(err) => panic!("Unexpected error {}", err),
+        Ok(_) => panic!("The buffers shouldn't be allocated"),
+    }
+    mock_hal::set_fail_allocations(false);
+    // The display is back to its config, the context is gone, and nothing
+    // holds on to the device.
+    assert_eq!(hwc.active_config(HWC_DISPLAY_PRIMARY).unwrap(), 0);
+    assert_eq!(egl::get_current_context(), None);
+    assert_eq!(Rc::strong_count(&hwc), 1);
+    assert_eq!(mock_hal::live_buffers(), 0);
+
+    drop(hwc);
+    assert_eq!(mock_hal::open_hwc_devices(), 0);
+}
+
+#[test]
+fn windows_let_go_of_their_device() {
+    env::set_var("EGL_PLATFORM", "surfaceless");
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let window = WindowBuilder::new().hwc(hwc.clone()).build().unwrap();
+    drop(window);
+    assert_eq!(Rc::strong_count(&hwc), 1);
+    drop(hwc);
+    assert_eq!(mock_hal::open_hwc_devices(), 0);
+
+    // Nor do they keep the one they opened.
+    drop(WindowBuilder::new().build().unwrap());
+    assert_eq!(mock_hal::open_hwc_devices(), 0);
+}
+
+#[test]
+fn dropped_windows_turn_the_display_off_and_free_everything() {
+    env::set_var("EGL_PLATFORM", "surfaceless");
+    let hwc = Rc::new(HwcDevice::new().unwrap());
+    let mock = unsafe { MockHwcDevice::from_native(hwc.native()) };
+    let window = WindowBuilder::new().hwc(hwc.clone()).build().unwrap();
+    assert_eq!(mock_hal::live_buffers(), 2);
+    assert!(egl::get_current_context().is_some());
+
+    mock.clear_calls();
+    drop(window);
+    match mock.calls()[..] {
+        [HwcCall::SetPowerMode { display, mode }] => {
+            assert_eq!((display, mode), (HWC_DISPLAY_PRIMARY, HWC_POWER_MODE_OFF))
+        }
+        ref other => panic!("Unexpected calls {:?}", other),
+    }
+    assert_eq!(egl::get_current_context(), None);
+    assert_eq!(mock_hal::live_buffers(), 0);
+
+    drop(hwc);
+    assert_eq!(mock_hal::open_hwc_devices(), 0);
+    assert_eq!(mock_hal::open_alloc_devices(), 0);
+}
+
+#[test]
+fn unsupported_gles_versions_are_reported() {
+    match WindowBuilder::new().gles_version(1).build() {
+        Err(GfxError::UnsupportedGlesVersion(1)) => (),
+        _ => panic!("GLES 1 windows shouldn't be created"),
+    }
+    assert_eq!(mock_hal::open_hwc_devices(), 0);
+}